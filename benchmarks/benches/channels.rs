@@ -0,0 +1,50 @@
+#![feature(test)]
+
+//! Comparison of passing items between two coroutines through a futures channel and through the
+//! rendezvous channel.
+
+extern crate corona;
+extern crate futures_await as futures;
+extern crate test;
+extern crate tokio_core;
+
+use corona::Coroutine;
+use corona::prelude::*;
+use corona::rendezvous::coro_rendezvous;
+use futures::unsync::mpsc;
+use test::Bencher;
+use tokio_core::reactor::Core;
+
+const ITEMS: usize = 1000;
+
+#[bench]
+fn mpsc_channel(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let (mut sender, receiver) = mpsc::channel(1);
+        let consumer = Coroutine::with_defaults(core.handle(), move || {
+            receiver.iter_ok().sum::<usize>()
+        });
+        Coroutine::with_defaults(core.handle(), move || {
+            for i in 0..ITEMS {
+                sender.coro_send(i).unwrap();
+            }
+        });
+        core.run(consumer).unwrap()
+    });
+}
+
+#[bench]
+fn rendezvous(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let (producer, consumer) = coro_rendezvous();
+        let consumer = Coroutine::with_defaults(core.handle(), move || consumer.sum::<usize>());
+        Coroutine::with_defaults(core.handle(), move || {
+            for i in 0..ITEMS {
+                producer.produce(i).unwrap();
+            }
+        });
+        core.run(consumer).unwrap()
+    });
+}
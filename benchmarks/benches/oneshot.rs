@@ -0,0 +1,69 @@
+#![feature(test)]
+
+//! Waiting for oneshots that already have their values, with and without the shortcut.
+
+extern crate corona;
+extern crate futures_await as futures;
+extern crate test;
+extern crate tokio_core;
+
+use corona::Coroutine;
+use futures::unsync::oneshot;
+use test::Bencher;
+use tokio_core::reactor::Core;
+
+const ITEMS: usize = 1000;
+
+/// One coroutine fills a batch of oneshots, the other picks the values up.
+fn batch(b: &mut Bencher, wait: fn(oneshot::Receiver<usize>) -> usize) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..ITEMS).map(|_| oneshot::channel()).unzip();
+        Coroutine::with_defaults(core.handle(), move || {
+            for (i, sender) in senders.into_iter().enumerate() {
+                sender.send(i).unwrap();
+            }
+        });
+        let consumer = Coroutine::with_defaults(core.handle(), move || {
+            receivers.into_iter().map(wait).sum::<usize>()
+        });
+        core.run(consumer).unwrap()
+    });
+}
+
+#[bench]
+fn sent_wait_oneshot(b: &mut Bencher) {
+    batch(b, |receiver| Coroutine::wait_oneshot(receiver).unwrap());
+}
+
+/// Going through the reactor for each value.
+#[bench]
+fn sent_wait(b: &mut Bencher) {
+    batch(b, |receiver| Coroutine::wait(receiver).unwrap().unwrap());
+}
+
+/// A ping-pong where each value needs to be waited for, the shortcut doesn't apply.
+#[bench]
+fn ping_pong(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let (pings, ping_receivers): (Vec<_>, Vec<_>) =
+            (0..ITEMS).map(|_| oneshot::channel()).unzip();
+        let (pongs, pong_receivers): (Vec<_>, Vec<_>) =
+            (0..ITEMS).map(|_| oneshot::channel()).unzip();
+        let ponger = Coroutine::with_defaults(core.handle(), move || {
+            for (ping, pong) in ping_receivers.into_iter().zip(pongs) {
+                let value = Coroutine::wait_oneshot(ping).unwrap();
+                pong.send(value).unwrap();
+            }
+        });
+        let pinger = Coroutine::with_defaults(core.handle(), move || {
+            for (i, (ping, pong)) in pings.into_iter().zip(pong_receivers).enumerate() {
+                ping.send(i).unwrap();
+                assert_eq!(i, Coroutine::wait_oneshot(pong).unwrap());
+            }
+        });
+        core.run(pinger).unwrap();
+        core.run(ponger).unwrap();
+    });
+}
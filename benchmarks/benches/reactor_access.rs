@@ -0,0 +1,39 @@
+#![feature(test)]
+
+//! Comparison of getting a clone of the reactor handle inside a coroutine and borrowing it.
+
+extern crate corona;
+extern crate test;
+extern crate tokio_core;
+
+use corona::Coroutine;
+use test::Bencher;
+use tokio_core::reactor::Core;
+
+const ITERATIONS: usize = 1000;
+
+#[bench]
+fn reactor_clone(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            (0..ITERATIONS)
+                .map(|_| Coroutine::reactor().id())
+                .last()
+        });
+        core.run(coroutine).unwrap()
+    });
+}
+
+#[bench]
+fn reactor_borrow(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            (0..ITERATIONS)
+                .map(|_| Coroutine::with_reactor(|handle| handle.id()))
+                .last()
+        });
+        core.run(coroutine).unwrap()
+    });
+}
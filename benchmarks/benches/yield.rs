@@ -0,0 +1,42 @@
+#![feature(test)]
+
+//! Comparison of the dedicated yield path with yielding through a waited-for future.
+
+extern crate corona;
+extern crate futures_await as futures;
+extern crate test;
+extern crate tokio_core;
+
+use corona::Coroutine;
+use futures::future;
+use test::Bencher;
+use tokio_core::reactor::Core;
+
+const ITERATIONS: usize = 1000;
+
+#[bench]
+fn yield_now(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            for _ in 0..ITERATIONS {
+                Coroutine::yield_now().unwrap();
+            }
+        });
+        core.run(coroutine).unwrap()
+    });
+}
+
+/// The way yielding worked before, spawning a future onto the reactor each time.
+#[bench]
+fn yield_through_future(b: &mut Bencher) {
+    let mut core = Core::new().unwrap();
+    b.iter(|| {
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            for _ in 0..ITERATIONS {
+                Coroutine::wait_fair(future::ok::<(), ()>(())).unwrap().unwrap();
+            }
+        });
+        core.run(coroutine).unwrap()
+    });
+}
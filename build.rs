@@ -0,0 +1,14 @@
+//! Detects the panic strategy for the crate.
+//!
+//! The `cfg(panic = "...")` can't be used directly. Older compilers don't know it and the ones
+//! between 1.49 and 1.59 reject it as unstable. Cargo passes the strategy to the build script on
+//! all the versions, so it is turned into a cfg of our own here.
+
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(corona_panic_abort)");
+    if env::var("CARGO_CFG_PANIC").map(|panic| panic == "abort").unwrap_or(false) {
+        println!("cargo:rustc-cfg=corona_panic_abort");
+    }
+}
@@ -1,18 +1,43 @@
 //! The [`Coroutine`](struct.Coroutine.html) and related things.
 
-use std::any::Any;
-use std::cell::RefCell;
-use std::panic::{self, AssertUnwindSafe, UnwindSafe};
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+#[cfg(all(unix, feature = "signal"))]
+use std::io::{Error as IoError, ErrorKind};
+use std::iter;
+use std::panic::{self, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
 
 use context::Context;
 use context::stack::{Stack, ProtectedFixedSizeStack};
-use futures::{Async, Future, Poll};
-use futures::unsync::oneshot::{self, Receiver};
-use tokio_core::reactor::Handle;
+use futures::{task, Async, AsyncSink, Future, IntoFuture, Poll, Sink, Stream};
+use futures::executor::{self, Notify};
+use futures::task::Task;
+use futures::future::{self, Either, Shared, SharedItem};
+use futures::sync::mpsc as sync_mpsc;
+use futures::sync::oneshot as sync_oneshot;
+use futures::unsync::oneshot::{self, Receiver, Sender};
+#[cfg(all(unix, feature = "signal"))]
+use libc::c_int;
+use tokio_core::reactor::{Core, CoreId, Handle, Interval, Remote, Timeout};
+#[cfg(all(unix, feature = "signal"))]
+use tokio_signal::unix::Signal;
 
-use errors::{Dropped, StackError, TaskFailed};
-use switch::{Switch, WaitTask};
+use errors::{Dropped, InvalidStackSize, LabeledPanic, PumpError, SharedFailed, SpawnError,
+             TaskFailed, WaitAborted, WaitTimeout};
+use reactor::{self, Reactor, ReactorLike};
+use stack_cache;
+use switch::{BoxedTask, ReadyCoroutine, Switch, WaitTask};
+use trace::CoroutineSpan;
+use wrappers::{BufferedIterator, Fused, SinkFlusher, SinkSender, StreamExtractor};
 
+#[cfg_attr(corona_panic_abort, allow(dead_code))]
 enum TaskResult<R> {
     Panicked(Box<Any + Send + 'static>),
     PanicPropagated,
@@ -26,12 +51,159 @@ enum TaskResult<R> {
 /// during shutdown or panicked).
 pub struct CoroutineResult<R> {
     receiver: Receiver<TaskResult<R>>,
+    cancel: Rc<CancelState>,
+    id: usize,
+    /// Notification about the start of a coroutine queued by `max_concurrent`.
+    started: Option<Receiver<()>>,
+}
+
+impl<R> CoroutineResult<R> {
+    /// A number identifying the coroutine.
+    ///
+    /// The ids are unique within the process. This is the id passed to the
+    /// [`on_complete`](struct.Coroutine.html#method.on_complete) callbacks.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Cancels the coroutine.
+    ///
+    /// The coroutine is woken up and its current wait (and any further waits) fail with
+    /// [`Dropped`](struct.Dropped.html), the same as if the reactor got dropped. With the waiting
+    /// methods that panic in such case, the coroutine's stack unwinds and the result resolves to
+    /// [`TaskFailed::Lost`](enum.TaskFailed.html#variant.Lost). The coroutine that doesn't wait
+    /// for anything at the moment notices on its next wait.
+    ///
+    /// Waking the coroutine up respects its
+    /// [`cleanup_strategy`](struct.Coroutine.html#method.cleanup_strategy), the same as dropping
+    /// the reactor. With the leaking strategies, the waiting coroutine is leaked instead of woken
+    /// up and its result never resolves.
+    ///
+    /// All the coroutines spawned from within the cancelled one are cancelled as well, unless
+    /// they were spawned as [`independent`](struct.Coroutine.html#method.independent).
+    ///
+    /// Cancelling an already terminated coroutine does nothing.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Returns a future resolving once the coroutine starts.
+    ///
+    /// Coroutines spawned with [`max_concurrent`](struct.Coroutine.html#method.max_concurrent)
+    /// may be queued instead of started right away. This allows the spawning side to slow down
+    /// until they actually start. The future resolves to `Err(Dropped)` if the coroutine never
+    /// starts (because the reactor got dropped in the meantime).
+    ///
+    /// Only the first call tracks the start ‒ the futures returned by the later calls (and the
+    /// ones for coroutines that were never queued) resolve right away.
+    pub fn started(&mut self) -> Started {
+        Started(self.started.take())
+    }
+
+    /// Runs the reactor until the coroutine finishes and returns its result.
+    ///
+    /// This is a convenience for the top-level code and tests, a shorthand for `core.run(result)`.
+    ///
+    /// The whole reactor runs during the call, not only this coroutine. Therefore, anything else
+    /// the coroutine depends on (other coroutines, futures spawned onto the same core) is driven as
+    /// well. If the coroutine waits for something that never happens, this blocks forever.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate tokio_core;
+    /// use corona::Coroutine;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let result = Coroutine::with_defaults(core.handle(), || 42);
+    /// assert_eq!(42, result.block(&mut core).unwrap());
+    /// # }
+    /// ```
+    pub fn block(self, core: &mut Core) -> Result<R, TaskFailed> {
+        core.run(self)
+    }
+
+    /// Gives up waiting for the result after the given time.
+    ///
+    /// The returned future resolves to `Some(result)` if the coroutine finishes in time or to
+    /// `None` once the time runs out. Unlike cancelling, this only stops the waiting ‒ the
+    /// coroutine itself keeps running (its result is thrown away when it finishes).
+    ///
+    /// The `CoroutineResult` doesn't know what reactor the coroutine lives on, so the handle to
+    /// create the timeout with needs to be passed. If the timeout can't be created (because the
+    /// reactor is gone), this waits without a time limit.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate tokio_core;
+    /// use std::time::Duration;
+    ///
+    /// use corona::Coroutine;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let handle = core.handle();
+    /// let slow = Coroutine::with_defaults(handle.clone(), || {
+    ///     // Interrupted once the core goes away at the end
+    ///     let _ = Coroutine::sleep(Duration::from_secs(10));
+    /// });
+    /// let result = core.run(slow.with_timeout(Duration::from_millis(10), &handle));
+    /// assert_eq!(None, result.unwrap());
+    /// # }
+    /// ```
+    pub fn with_timeout(self, duration: Duration, handle: &Handle) -> WithTimeout<R> {
+        WithTimeout {
+            result: self,
+            timeout: Timeout::new(duration, handle).ok(),
+        }
+    }
+
+    /// Waits for the result from within another coroutine, giving up after the given time.
+    ///
+    /// This suspends the current coroutine until the one behind the result finishes or the time
+    /// runs out. On timeout, `Ok(None)` is returned and the other coroutine keeps running, the
+    /// same as with [`with_timeout`](#method.with_timeout). The timeout is created on the reactor
+    /// of the current coroutine.
+    ///
+    /// If the reactor is dropped during the wait, the result is `Err(TaskFailed::Lost)`.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn join_timeout(self, duration: Duration) -> Result<Option<R>, TaskFailed> {
+        match Coroutine::wait_timeout(self, duration) {
+            Ok(Ok(result)) => Ok(Some(result)),
+            Ok(Err(err)) => Err(err),
+            Err(WaitTimeout::TimedOut) => Ok(None),
+            Err(WaitTimeout::Dropped) => Err(TaskFailed::Lost),
+        }
+    }
+}
+
+/// Checks if the innermost coroutine whose future is being polled is the one with this cancel
+/// state.
+fn is_polled_for(cancel: &Rc<CancelState>) -> bool {
+    let depth = CONTEXTS.with(|c| c.borrow().len());
+    POLLING.with(|p| {
+        p.borrow()
+            .last()
+            .map_or(false, |&(_, at, ref waiting)| at >= depth && Rc::ptr_eq(waiting, cancel))
+    })
 }
 
 impl<R> Future for CoroutineResult<R> {
     type Item = R;
     type Error = TaskFailed;
     fn poll(&mut self) -> Poll<R, TaskFailed> {
+        // It would never resolve, so make the mistake obvious. The panic is propagated into the
+        // waiting coroutine.
+        assert!(!is_polled_for(&self.cancel), "A coroutine cannot await its own completion");
         match self.receiver.poll() {
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Ok(Async::Ready(TaskResult::Finished(result))) => Ok(Async::Ready(result)),
@@ -42,6 +214,594 @@ impl<R> Future for CoroutineResult<R> {
     }
 }
 
+/// A future waiting for a coroutine result with a time limit.
+///
+/// See [`CoroutineResult::with_timeout`](struct.CoroutineResult.html#method.with_timeout).
+pub struct WithTimeout<R> {
+    result: CoroutineResult<R>,
+    timeout: Option<Timeout>,
+}
+
+impl<R> Future for WithTimeout<R> {
+    type Item = Option<R>;
+    type Error = TaskFailed;
+    fn poll(&mut self) -> Poll<Option<R>, TaskFailed> {
+        if let Async::Ready(result) = self.result.poll()? {
+            return Ok(Async::Ready(Some(result)));
+        }
+        match self.timeout.as_mut().map(Future::poll) {
+            // A failed timeout counts as an elapsed one, we wouldn't get woken up by it anyway.
+            Some(Ok(Async::Ready(()))) | Some(Err(_)) => Ok(Async::Ready(None)),
+            Some(Ok(Async::NotReady)) | None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A time limit reusable for many waits.
+///
+/// Each [`wait_timeout`](struct.Coroutine.html#method.wait_timeout) registers a new timer with
+/// the reactor and throws it away afterwards. When a coroutine waits with the same time limit over
+/// and over (like reading requests in a loop), the deadline keeps a single timer and only moves it
+/// for each wait.
+///
+/// Created by [`Coroutine::deadline`](struct.Coroutine.html#method.deadline).
+pub struct Deadline {
+    timeout: Timeout,
+    duration: Duration,
+}
+
+impl Deadline {
+    /// The time limit of each wait.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Waits for a future, but only up to the duration of the deadline.
+    ///
+    /// The timer is reset at the start of each call to the full duration (from now), no matter if
+    /// the previous wait resolved in time, timed out or got interrupted. Otherwise, this works
+    /// like [`Coroutine::wait_timeout`](struct.Coroutine.html#method.wait_timeout) ‒ the future is
+    /// dropped if it doesn't resolve in time.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait<I, E, Fut>(&mut self, fut: Fut) -> Result<Result<I, E>, WaitTimeout>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        self.timeout.reset(Instant::now() + self.duration);
+        let timer = &mut self.timeout;
+        let timeout = future::poll_fn(|| timer.poll());
+        match Coroutine::wait(fut.select2(timeout))? {
+            Ok(Either::A((item, _timeout))) => Ok(Ok(item)),
+            Err(Either::A((err, _timeout))) => Ok(Err(err)),
+            Ok(Either::B((_, _fut))) => Err(WaitTimeout::TimedOut),
+            // A timeout errors only when the reactor is gone
+            Err(Either::B((_, _fut))) => Err(WaitTimeout::Dropped),
+        }
+    }
+}
+
+/// A future resolving once a coroutine starts.
+///
+/// See [`CoroutineResult::started`](struct.CoroutineResult.html#method.started).
+pub struct Started(Option<Receiver<()>>);
+
+impl Future for Started {
+    type Item = ();
+    type Error = Dropped;
+    fn poll(&mut self) -> Poll<(), Dropped> {
+        match self.0 {
+            Some(ref mut receiver) => receiver.poll().map_err(|_| Dropped),
+            None => Ok(Async::Ready(())),
+        }
+    }
+}
+
+/// Limits the number of coroutines running at once, see `Coroutine::max_concurrent`.
+struct Limiter {
+    max: usize,
+    running: Cell<usize>,
+    /// Starts of the queued coroutines.
+    pending: RefCell<VecDeque<Box<FnMut()>>>,
+}
+
+impl Limiter {
+    /// Takes a free slot for a coroutine, if there's one.
+    fn acquire(&self) -> bool {
+        let running = self.running.get();
+        if running < self.max {
+            self.running.set(running + 1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Releases the slot of a terminated coroutine, passing it on to the next queued one.
+    ///
+    /// The queued coroutines hold only a weak reference to the limiter (they are stored inside
+    /// it), so the started one is passed a strong one to keep the limiter alive until it runs.
+    fn release(limiter: &Rc<Limiter>, handle: &Reactor) {
+        let next = limiter.pending.borrow_mut().pop_front();
+        match next {
+            Some(mut start) => {
+                let limiter = limiter.clone();
+                // Start it from the reactor, not from the stack of the one that just terminated.
+                handle.spawn_task(Box::new(future::lazy(move || {
+                    start();
+                    drop(limiter);
+                    Ok::<(), ()>(())
+                })));
+            },
+            None => limiter.running.set(limiter.running.get() - 1),
+        }
+    }
+}
+
+/// How a coroutine terminated.
+///
+/// See [`Coroutine::on_complete`](struct.Coroutine.html#method.on_complete).
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum CompletionStatus {
+    /// The task returned.
+    Succeeded,
+    /// The task panicked.
+    Panicked,
+    /// The coroutine was cancelled or its reactor was dropped.
+    Lost,
+}
+
+/// Information about a terminated coroutine, passed to the
+/// [`on_complete`](struct.Coroutine.html#method.on_complete) callbacks.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CompletionInfo {
+    /// The [`id`](struct.CoroutineResult.html#method.id) of the coroutine.
+    pub id: usize,
+    /// How it terminated.
+    pub status: CompletionStatus,
+}
+
+/// A spawned coroutine together with where it was placed.
+///
+/// See [`Coroutine::spawn_with_ids`](struct.Coroutine.html#method.spawn_with_ids).
+pub struct SpawnHandle<R> {
+    /// The result of the coroutine.
+    pub result: CoroutineResult<R>,
+    /// The reactor the coroutine runs on.
+    ///
+    /// The ids are assigned to the `tokio_core` reactors on the current thread in the order they
+    /// are first seen and stay the same for the life of the reactor. They are not reused after
+    /// the reactor is gone. It is `None` if the coroutine doesn't run on a `tokio_core` reactor.
+    pub reactor_id: Option<usize>,
+    /// The [`id`](struct.CoroutineResult.html#method.id) of the coroutine.
+    pub coroutine_id: usize,
+}
+
+/// The source of the coroutine ids.
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The overridden sizes of the stack profiles, 0 for the built-in ones.
+static PROFILE_SIZES: [AtomicUsize; 4] =
+    [ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT];
+
+/// Checks the stack size is a non-zero multiple of the page size.
+fn check_stack_size(size: usize) -> Result<(), InvalidStackSize> {
+    let page_size = stack_cache::page_size();
+    if size == 0 || size % page_size != 0 {
+        Err(InvalidStackSize { size, page_size })
+    } else {
+        Ok(())
+    }
+}
+
+/// Named stack sizes for typical kinds of coroutines.
+///
+/// Instead of scattering stack sizes through the code, the coroutines can be spawned with a
+/// profile (see [`Coroutine::profile`](struct.Coroutine.html#method.profile)). The sizes are
+/// multiples of the page size and can be tuned for the whole application at one place with
+/// [`Coroutine::set_profile_size`](struct.Coroutine.html#method.set_profile_size).
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum StackProfile {
+    /// Small handlers with little nesting, 4 pages by default (16 kB with the usual page size).
+    Tiny,
+    /// Ordinary tasks, 8 pages by default (32 kB with the usual page size).
+    Small,
+    /// Tasks calling into deeper libraries, 32 pages by default (128 kB with the usual page size).
+    Medium,
+    /// Heavy processing or recursion, 256 pages by default (1 MB with the usual page size).
+    Large,
+}
+
+impl StackProfile {
+    fn index(self) -> usize {
+        match self {
+            StackProfile::Tiny => 0,
+            StackProfile::Small => 1,
+            StackProfile::Medium => 2,
+            StackProfile::Large => 3,
+        }
+    }
+
+    /// Returns to the built-in size, dropping any override.
+    #[cfg(test)]
+    fn reset_size(self) {
+        PROFILE_SIZES[self.index()].store(0, Ordering::Relaxed);
+    }
+
+    /// The stack size of the profile, taking the overrides into account.
+    pub fn size(self) -> usize {
+        let overridden = PROFILE_SIZES[self.index()].load(Ordering::Relaxed);
+        if overridden != 0 {
+            return overridden;
+        }
+        let pages = match self {
+            StackProfile::Tiny => 4,
+            StackProfile::Small => 8,
+            StackProfile::Medium => 32,
+            StackProfile::Large => 256,
+        };
+        pages * stack_cache::page_size()
+    }
+}
+
+/// The ids handed out to the reactors, see `SpawnHandle::reactor_id`.
+///
+/// The reactors are kept together with their sentinels, so the ones that are gone can be
+/// forgotten. Their ids are not reused.
+#[derive(Default)]
+struct ReactorIds {
+    ids: HashMap<CoreId, (usize, Weak<()>)>,
+    next: usize,
+}
+
+/// Returns the id of the reactor, assigning a new one if it is seen for the first time.
+fn assign_reactor_id(handle: &Handle) -> usize {
+    REACTOR_IDS.with(|r| {
+        let mut reactors = r.borrow_mut();
+        if let Some(&(id, _)) = reactors.ids.get(&handle.id()) {
+            return id;
+        }
+        reactors.ids.retain(|_, &mut (_, ref sentinel)| sentinel.upgrade().is_some());
+        let id = reactors.next;
+        reactors.next += 1;
+        reactors.ids.insert(handle.id(), (id, reactor::sentinel(handle)));
+        id
+    })
+}
+
+/// The pause after the first failed attempt of [`Coroutine::retry`], doubled after each next one.
+const RETRY_BACKOFF_MS: u64 = 10;
+
+/// The longest pause between attempts of [`Coroutine::retry`], the doubling stops there.
+const RETRY_MAX_BACKOFF_MS: u64 = 60_000;
+
+/// A coroutine that starts only once polled.
+///
+/// This is returned from [`Coroutine::lazy`](struct.Coroutine.html#method.lazy). Unlike the
+/// coroutines started by `spawn`, the task doesn't run until this future is polled for the first
+/// time. After that, it acts just like [`CoroutineResult`](struct.CoroutineResult.html).
+///
+/// If it is dropped without being polled, the task never runs.
+pub struct LazyCoroutine<R> {
+    start: Option<(ProtectedFixedSizeStack, BoxedTask)>,
+    result: CoroutineResult<R>,
+}
+
+impl<R> Future for LazyCoroutine<R> {
+    type Item = R;
+    type Error = TaskFailed;
+    fn poll(&mut self) -> Poll<R, TaskFailed> {
+        if let Some((stack, task)) = self.start.take() {
+            Switch::run_new_coroutine(stack, task);
+        }
+        self.result.poll()
+    }
+}
+
+impl<R> Drop for LazyCoroutine<R> {
+    fn drop(&mut self) {
+        // Never started, so the stack is still clean and can be reused.
+        if let Some((stack, _task)) = self.start.take() {
+            stack_cache::put(stack);
+        }
+    }
+}
+
+/// A suspended coroutine.
+///
+/// This is obtained through [`Coroutine::suspend`](struct.Coroutine.html#method.suspend) and
+/// allows waking the coroutine up again. That can be done either with
+/// [`resume`](#method.resume) from anywhere on the same thread, or by handing the control to it
+/// directly from another coroutine with
+/// [`Coroutine::yield_to`](struct.Coroutine.html#method.yield_to).
+///
+/// If the handle is dropped without resuming the coroutine, the coroutine is cleaned up the same
+/// way as if its reactor got dropped (according to its
+/// [`CleanupStrategy`](enum.CleanupStrategy.html)) ‒ the `suspend` returns `Err(Dropped)`.
+pub struct CoroutineHandle {
+    coroutine: ReadyCoroutine,
+    /// Where the coroutine picks up the handle of whoever yielded to it.
+    inbox: Rc<RefCell<Option<CoroutineHandle>>>,
+}
+
+impl CoroutineHandle {
+    /// Resumes the coroutine.
+    ///
+    /// The coroutine runs until it waits for something again (or terminates), then this returns.
+    /// Its `suspend` returns `Ok(None)`, as it wasn't yielded to by another coroutine.
+    pub fn resume(self) {
+        self.coroutine.resume();
+    }
+}
+
+/// The state shared between a [`WaitInProgress`](struct.WaitInProgress.html) and the task driving
+/// its future.
+struct InProgress<Fut: Future> {
+    /// The future, until it resolves or is abandoned.
+    fut: Option<Fut>,
+    result: Option<Result<Fut::Item, Fut::Error>>,
+    /// The coroutine waiting for the result.
+    waiting: Option<Task>,
+    /// The task driving the future, to let it go when the wait is abandoned.
+    driver: Option<Task>,
+}
+
+type InProgressRef<Fut> = Rc<RefCell<InProgress<Fut>>>;
+
+/// Drives the future of a `WaitInProgress` on the reactor.
+struct InProgressDriver<Fut: Future>(InProgressRef<Fut>);
+
+impl<Fut: Future> Future for InProgressDriver<Fut> {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        // Don't hold the borrow while polling, the future may do anything.
+        let fut = self.0.borrow_mut().fut.take();
+        let mut fut = match fut {
+            Some(fut) => fut,
+            // Abandoned
+            None => return Ok(Async::Ready(())),
+        };
+        let result = match fut.poll() {
+            Ok(Async::NotReady) => {
+                let mut state = self.0.borrow_mut();
+                state.fut = Some(fut);
+                state.driver = Some(task::current());
+                return Ok(Async::NotReady);
+            },
+            Ok(Async::Ready(item)) => Ok(item),
+            Err(err) => Err(err),
+        };
+        let waiting = {
+            let mut state = self.0.borrow_mut();
+            state.result = Some(result);
+            state.waiting.take()
+        };
+        if let Some(waiting) = waiting {
+            waiting.notify();
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A wait that was started, but the coroutine didn't suspend on it yet.
+///
+/// This is returned by [`Coroutine::wait_guarded`](struct.Coroutine.html#method.wait_guarded).
+/// The future runs on the reactor in the meantime. Calling [`complete`](#method.complete) waits
+/// for its result, dropping this abandons the wait ‒ the future is dropped right away, without
+/// the coroutine being disturbed.
+pub struct WaitInProgress<Fut: Future>(InProgressRef<Fut>);
+
+impl<Fut: Future> WaitInProgress<Fut> {
+    /// Waits for the result of the future.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result the future resolved to.
+    /// * `Err(Dropped)` when the reactor was dropped before the future had a chance to resolve.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn complete(self) -> Result<Result<Fut::Item, Fut::Error>, Dropped> {
+        let state = &self.0;
+        let result = future::poll_fn(|| {
+            let mut state = state.borrow_mut();
+            match state.result.take() {
+                Some(Ok(item)) => Ok(Async::Ready(item)),
+                Some(Err(err)) => Err(err),
+                None => {
+                    state.waiting = Some(task::current());
+                    Ok(Async::NotReady)
+                },
+            }
+        });
+        Coroutine::wait(result)
+    }
+}
+
+impl<Fut: Future> Drop for WaitInProgress<Fut> {
+    fn drop(&mut self) {
+        // Take them out first, so nothing is borrowed while running the destructor.
+        let (fut, driver) = {
+            let mut state = self.0.borrow_mut();
+            (state.fut.take(), state.driver.take())
+        };
+        drop(fut);
+        // Let the driver notice there's nothing left to drive.
+        if let Some(driver) = driver {
+            driver.notify();
+        }
+    }
+}
+
+/// Restarts a panicking coroutine, as part of `spawn_supervised`.
+///
+/// It watches the current incarnation of the coroutine and once it finishes, either passes the
+/// result on or starts a new one.
+struct Restarter<R, Task> {
+    builder: Coroutine,
+    task: Rc<RefCell<Task>>,
+    restarts_left: usize,
+    current: CoroutineResult<R>,
+    sender: Option<Sender<TaskResult<R>>>,
+    /// Cancellation of the whole supervised coroutine, passed on to the current incarnation.
+    cancel: Rc<CancelState>,
+}
+
+impl<R, Task> Restarter<R, Task>
+where
+    R: 'static,
+    Task: FnMut() -> R + 'static,
+{
+    fn start(builder: &Coroutine, task: &Rc<RefCell<Task>>, cancel: &Rc<CancelState>)
+        -> Result<CoroutineResult<R>, SpawnError>
+    {
+        let task = task.clone();
+        // The task is restarted after a panic, that's the whole point. It's up to the task to keep
+        // its state consistent.
+        let current = builder.spawn_catch_panic(AssertUnwindSafe(move || {
+            let mut task = task.borrow_mut();
+            (*task)()
+        }))?;
+        cancel.adopt(&current.cancel);
+        Ok(current)
+    }
+}
+
+impl<R, Task> Future for Restarter<R, Task>
+where
+    R: 'static,
+    Task: FnMut() -> R + 'static,
+{
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let result = match self.current.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(result)) => TaskResult::Finished(result),
+                Err(TaskFailed::Panicked(panic)) => {
+                    if self.restarts_left == 0 {
+                        TaskResult::Panicked(panic)
+                    } else {
+                        self.restarts_left -= 1;
+                        match Self::start(&self.builder, &self.task, &self.cancel) {
+                            Ok(current) => {
+                                self.current = current;
+                                continue;
+                            },
+                            // The stack was fine the first time and we are run by the reactor,
+                            // so this is unlikely
+                            Err(_) => TaskResult::Panicked(panic),
+                        }
+                    }
+                },
+                Err(TaskFailed::PanicPropagated) => TaskResult::PanicPropagated,
+                Err(TaskFailed::Lost) => TaskResult::Lost,
+            };
+            drop(self.sender.take().unwrap().send(result));
+            return Ok(Async::Ready(()));
+        }
+    }
+}
+
+/// Drives a bunch of futures until all of them resolve, as part of `wait_all_settled`.
+///
+/// Unlike `join_all`, failure of one future doesn't stop the others.
+struct AllSettled<Fut: Future> {
+    futures: Vec<Option<Fut>>,
+    results: Vec<Option<Result<Fut::Item, Fut::Error>>>,
+}
+
+impl<Fut: Future> Future for AllSettled<Fut> {
+    type Item = Vec<Result<Fut::Item, Fut::Error>>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, ()> {
+        let mut pending = false;
+        for (slot, result) in self.futures.iter_mut().zip(self.results.iter_mut()) {
+            let settled = match *slot {
+                Some(ref mut fut) => match fut.poll() {
+                    Ok(Async::NotReady) => None,
+                    Ok(Async::Ready(item)) => Some(Ok(item)),
+                    Err(err) => Some(Err(err)),
+                },
+                None => continue,
+            };
+            match settled {
+                Some(settled) => {
+                    *slot = None;
+                    *result = Some(settled);
+                },
+                None => pending = true,
+            }
+        }
+        if pending {
+            Ok(Async::NotReady)
+        } else {
+            let results = self.results
+                .drain(..)
+                .map(|result| result.expect("Unsettled future"))
+                .collect();
+            Ok(Async::Ready(results))
+        }
+    }
+}
+
+/// Waits for the first of the futures, preferring the earlier ones, as part of `select_biased`.
+struct SelectBiased<Fut>(Vec<Fut>);
+
+impl<Fut: Future> Future for SelectBiased<Fut> {
+    type Item = (Result<Fut::Item, Fut::Error>, usize, Vec<Fut>);
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, ()> {
+        let mut resolved = None;
+        for (idx, fut) in self.0.iter_mut().enumerate() {
+            match fut.poll() {
+                Ok(Async::NotReady) => (),
+                Ok(Async::Ready(item)) => {
+                    resolved = Some((idx, Ok(item)));
+                    break;
+                },
+                Err(err) => {
+                    resolved = Some((idx, Err(err)));
+                    break;
+                },
+            }
+        }
+        match resolved {
+            Some((idx, result)) => {
+                let mut rest = mem::replace(&mut self.0, Vec::new());
+                // Unlike swap_remove, this keeps the order for the next selection.
+                rest.remove(idx);
+                Ok(Async::Ready((result, idx, rest)))
+            },
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Gives the others a chance to run, as part of `yield_now` and `wait_fair`.
+///
+/// It is not ready on the first poll, but it schedules itself to be polled again right away.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.0 {
+            Ok(Async::Ready(()))
+        } else {
+            self.0 = true;
+            task::current().notify();
+            Ok(Async::NotReady)
+        }
+    }
+}
+
 /// Controls how a cleanup happens if the driving `core` is dropped while a coroutine lives.
 ///
 /// If a core is dropped and there is a coroutine that haven't finished yet, there's no chance for
@@ -92,32 +852,625 @@ pub enum CleanupStrategy {
     AbortAlways,
 }
 
-struct CoroutineContext {
-    /// Use this to spawn waiting coroutines
-    handle: Handle,
-    /// The context that called us and we'll switch back to it when we wait for something.
-    parent_context: Context,
-    /// Our own stack. We keep ourselvel alive.
-    stack: ProtectedFixedSizeStack,
-    /// How do we clean up the coroutine if it doesn't end before dropping the core?
-    cleanup_strategy: CleanupStrategy,
+/// Shared state for cancelling a coroutine.
+#[derive(Default)]
+pub(crate) struct CancelState {
+    cancelled: Cell<bool>,
+    /// The task waiting on behalf of the coroutine, to wake it up on cancellation.
+    waiting: RefCell<Option<Task>>,
+    /// The coroutines spawned from within this one, to be cancelled together with it.
+    children: RefCell<Vec<Weak<CancelState>>>,
+    /// The coroutine is suspended, waiting for something (for the reactor statistics).
+    parked: Cell<bool>,
+    /// The coroutine terminated or was leaked, so it no longer counts in the registry.
+    finished: Cell<bool>,
 }
 
-thread_local! {
-    static CONTEXTS: RefCell<Vec<CoroutineContext>> = RefCell::new(Vec::new());
-}
+impl CancelState {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
 
-/// A builder of coroutines.
-///
-/// This struct is the main entry point and a way to start coroutines of various kinds. It allows
-/// both starting them with default parameters and configuring them with the builder pattern.
+    pub(crate) fn set_parked(&self, parked: bool) {
+        self.parked.set(parked);
+    }
+
+    /// Remembers the current task, to be woken up on cancellation.
+    ///
+    /// The task is replaced only if the remembered one wouldn't wake up the current one, so the
+    /// repeated polls of the same wait don't clone it each time.
+    pub(crate) fn park(&self) {
+        let mut waiting = self.waiting.borrow_mut();
+        let current = match *waiting {
+            Some(ref task) => task.will_notify_current(),
+            None => false,
+        };
+        if !current {
+            *waiting = Some(task::current());
+        }
+    }
+
+    /// Cancels the coroutine and all its children.
+    pub(crate) fn cancel(&self) {
+        if self.cancelled.replace(true) {
+            return;
+        }
+        // Don't hold the borrows while notifying, anything could happen in there.
+        let waiting = self.waiting.borrow_mut().take();
+        if let Some(task) = waiting {
+            task.notify();
+        }
+        let children = mem::replace(&mut *self.children.borrow_mut(), Vec::new());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+
+    /// Links a child to be cancelled together with this coroutine.
+    fn adopt(&self, child: &Rc<CancelState>) {
+        if self.is_cancelled() {
+            child.cancel();
+        } else {
+            let mut children = self.children.borrow_mut();
+            // Forget the children that are gone already
+            children.retain(|child| child.upgrade().is_some());
+            children.push(Rc::downgrade(child));
+        }
+    }
+}
+
+struct CoroutineContext {
+    /// Use this to spawn waiting coroutines
+    handle: Reactor,
+    /// The context that called us and we'll switch back to it when we wait for something.
+    parent_context: Context,
+    /// Our own stack. We keep ourselvel alive.
+    stack: ProtectedFixedSizeStack,
+    /// How do we clean up the coroutine if it doesn't end before dropping the core?
+    cleanup_strategy: CleanupStrategy,
+    /// The priority of resuming, if any.
+    priority: Option<u8>,
+    /// Resume through the round-robin queue.
+    fair: bool,
+    /// The values attached by the builder, see `Coroutine::context_value`.
+    values: ContextValues,
+    /// Cancellation of this coroutine.
+    cancel: Rc<CancelState>,
+    /// The tracing span, entered while the coroutine runs.
+    span: CoroutineSpan,
+}
+
+thread_local! {
+    static CONTEXTS: RefCell<Vec<CoroutineContext>> = RefCell::new(Vec::new());
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+    static THREAD_REACTOR: RefCell<ThreadReactor> = RefCell::new(ThreadReactor::default());
+    static REACTOR_IDS: RefCell<ReactorIds> = RefCell::new(ReactorIds::default());
+    /// The reactors of the coroutines whose futures are being polled, with the depth of `CONTEXTS`
+    /// at that time and the cancel state identifying the coroutine.
+    static POLLING: RefCell<Vec<(Reactor, usize, Rc<CancelState>)>> = RefCell::new(Vec::new());
+    /// Polls taking longer than this get a warning, see `Coroutine::enable_poll_watchdog`.
+    static POLL_WATCHDOG: Cell<Option<Duration>> = Cell::new(None);
+    #[cfg(feature = "leak-check")]
+    static LEAKS: RefCell<HashMap<usize, Instant>> = RefCell::new(HashMap::new());
+}
+
+/// The reactor managed by the library for the current thread.
+#[derive(Default)]
+struct ThreadReactor {
+    /// The core, unless it is running at the moment.
+    core: Option<Core>,
+    handle: Option<Handle>,
+}
+
+impl Drop for ThreadReactor {
+    fn drop(&mut self) {
+        // The other thread locals may be gone already, so the coroutines can't be cleaned up
+        // properly at this point.
+        if let Some(core) = self.core.take() {
+            mem::forget(core);
+        }
+    }
+}
+
+/// The coroutines running on this thread, for the graceful shutdown.
+///
+/// To keep the start and termination of coroutines cheap, the terminated ones are only marked as
+/// such and removed from the list lazily, once there's enough of them.
+#[derive(Default)]
+struct Registry {
+    /// The coroutines that started, each with the `tokio_core` reactor it runs on (if any).
+    ///
+    /// May contain ones that terminated already.
+    live: Vec<(Weak<CancelState>, Option<CoreId>)>,
+    /// How many coroutines are alive.
+    alive: usize,
+    /// How many coroutines terminated so far.
+    terminated: usize,
+    /// No new coroutines are accepted.
+    shutting_down: bool,
+    /// The shutdown waiting for the coroutines to terminate.
+    waiter: Option<Task>,
+}
+
+impl Registry {
+    /// The coroutines that didn't terminate yet.
+    fn live(&self) -> Vec<(Rc<CancelState>, Option<CoreId>)> {
+        self.live
+            .iter()
+            .filter_map(|&(ref cancel, core)| cancel.upgrade().map(|cancel| (cancel, core)))
+            .filter(|&(ref cancel, _)| !cancel.finished.get())
+            .collect()
+    }
+}
+
+/// Notes a coroutine as started.
+fn register(cancel: &Rc<CancelState>, core: Option<CoreId>) {
+    REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        if registry.live.len() >= 2 * registry.alive + 16 {
+            registry.live.retain(|&(ref cancel, _)| {
+                cancel.upgrade().map_or(false, |cancel| !cancel.finished.get())
+            });
+        }
+        registry.live.push((Rc::downgrade(cancel), core));
+        registry.alive += 1;
+    });
+}
+
+/// Counts the coroutines in the registry running on the given reactor.
+fn stats(core: Option<CoreId>) -> ReactorStats {
+    REGISTRY.with(|r| {
+        let registry = r.borrow();
+        let mut stats = ReactorStats::default();
+        for (cancel, _) in registry.live().into_iter().filter(|&(_, live)| live == core) {
+            stats.alive += 1;
+            if cancel.parked.get() {
+                stats.parked += 1;
+            } else {
+                stats.runnable += 1;
+            }
+        }
+        stats
+    })
+}
+
+/// Notes a coroutine as terminated (or leaked), waking up the shutdown waiting for it.
+pub(crate) fn unregister(cancel: &Rc<CancelState>) {
+    if cancel.finished.replace(true) {
+        return;
+    }
+    let waiter = REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        registry.alive -= 1;
+        registry.terminated += 1;
+        registry.waiter.take()
+    });
+    if let Some(waiter) = waiter {
+        waiter.notify();
+    }
+}
+
+/// How many coroutines terminated on this thread so far.
+fn terminated_coroutines() -> usize {
+    REGISTRY.with(|r| r.borrow().terminated)
+}
+
+/// Resolves once all the coroutines on this thread terminated.
+fn all_terminated() -> Poll<(), ()> {
+    REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        if registry.alive == 0 {
+            Ok(Async::Ready(()))
+        } else {
+            registry.waiter = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    })
+}
+
+/// Drives the core until all coroutines terminate or the time runs out.
+fn drive_until_terminated(core: &mut Core, timeout: Duration) {
+    let deadline = Timeout::new(timeout, &core.handle()).expect("Failed to create a timeout");
+    // Whichever comes first, we don't care about the errors.
+    let _ = core.run(future::poll_fn(all_terminated).select2(deadline));
+}
+
+/// Keeps a coroutine registered in the leak check for as long as it exists.
+///
+/// Without the `leak-check` feature, this does nothing.
+struct LeakGuard {
+    #[cfg(feature = "leak-check")]
+    id: usize,
+}
+
+impl LeakGuard {
+    #[cfg(feature = "leak-check")]
+    fn new(id: usize) -> Self {
+        LEAKS.with(|l| l.borrow_mut().insert(id, Instant::now()));
+        LeakGuard { id }
+    }
+
+    #[cfg(not(feature = "leak-check"))]
+    fn new(_id: usize) -> Self {
+        LeakGuard {}
+    }
+
+    /// The coroutine is gone, stop tracking it.
+    fn release(self) {}
+}
+
+#[cfg(feature = "leak-check")]
+impl Drop for LeakGuard {
+    fn drop(&mut self) {
+        LEAKS.with(|l| l.borrow_mut().remove(&self.id));
+    }
+}
+
+/// The outcome of [`Coroutine::shutdown_graceful`](struct.Coroutine.html#method.shutdown_graceful).
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ShutdownReport {
+    /// The coroutines that terminated on their own within the timeout.
+    pub completed: usize,
+    /// The coroutines that had to be cancelled.
+    pub cancelled: usize,
+}
+
+/// The outcome of
+/// [`Coroutine::wait_timeout_graceful`](struct.Coroutine.html#method.wait_timeout_graceful).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GracefulTimeout<T> {
+    /// The future resolved before the deadline.
+    InTime(T),
+    /// The future missed the deadline, but resolved within the grace period.
+    InGrace(T),
+    /// The future didn't resolve even within the grace period and was dropped.
+    Abandoned,
+}
+
+/// How a wait observed by
+/// [`Coroutine::wait_observed`](struct.Coroutine.html#method.wait_observed) ended.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WaitOutcome {
+    /// The future resolved (successfully or with an error).
+    Completed,
+    /// The future was dropped unresolved because the reactor went away (or the future panicked).
+    Dropped,
+    /// The future was dropped unresolved because the waiting coroutine got cancelled.
+    Cancelled,
+}
+
+/// Reports the outcome of the wait for the wrapped future once it resolves or gets dropped.
+struct Observed<Fut, F: FnOnce(WaitOutcome)> {
+    fut: Fut,
+    on_done: Option<F>,
+    /// Cancellation of the waiting coroutine, to tell the reasons of dropping apart.
+    cancel: Rc<CancelState>,
+}
+
+impl<Fut: Future, F: FnOnce(WaitOutcome)> Future for Observed<Fut, F> {
+    type Item = Fut::Item;
+    type Error = Fut::Error;
+    fn poll(&mut self) -> Poll<Fut::Item, Fut::Error> {
+        let result = self.fut.poll();
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        if let Some(on_done) = self.on_done.take() {
+            on_done(WaitOutcome::Completed);
+        }
+        result
+    }
+}
+
+impl<Fut, F: FnOnce(WaitOutcome)> Drop for Observed<Fut, F> {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            if self.cancel.is_cancelled() {
+                on_done(WaitOutcome::Cancelled);
+            } else {
+                on_done(WaitOutcome::Dropped);
+            }
+        }
+    }
+}
+
+/// A notification that goes nowhere, for peeking at futures outside of any task.
+struct NoNotify;
+
+impl Notify for NoNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+/// A future able to describe how far it got.
+///
+/// Used by [`Coroutine::wait_resumable`](struct.Coroutine.html#method.wait_resumable) to capture
+/// the progress of a wait that gets interrupted, so the operation can be picked up from there
+/// later on (possibly on a different reactor, after a restart). How the checkpoint is stored or
+/// serialized is up to the caller.
+pub trait Stash {
+    /// The description of the progress.
+    type Checkpoint;
+
+    /// Describes how far the future got.
+    ///
+    /// This is called at most once, just before an unfinished future is dropped.
+    fn stash(&self) -> Self::Checkpoint;
+}
+
+/// Stashes the checkpoint of the wrapped future if dropped before it resolves.
+struct Stashing<'a, Fut: Stash + 'a> {
+    fut: Fut,
+    done: bool,
+    checkpoint: &'a mut Option<Fut::Checkpoint>,
+}
+
+impl<'a, Fut: Future + Stash + 'a> Future for Stashing<'a, Fut> {
+    type Item = Fut::Item;
+    type Error = Fut::Error;
+    fn poll(&mut self) -> Poll<Fut::Item, Fut::Error> {
+        let result = self.fut.poll();
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        self.done = true;
+        result
+    }
+}
+
+impl<'a, Fut: Stash + 'a> Drop for Stashing<'a, Fut> {
+    fn drop(&mut self) {
+        if !self.done {
+            *self.checkpoint = Some(self.fut.stash());
+        }
+    }
+}
+
+/// A flag to ask a loop (or anything else) to stop.
+///
+/// The clones of the token share the flag, so one part of the program can hold a clone to cancel
+/// with and other parts can watch theirs. Once cancelled, the token stays cancelled.
+///
+/// It is used by [`Coroutine::interval_until`](struct.Coroutine.html#method.interval_until).
+#[derive(Clone)]
+pub struct CancelToken {
+    sender: Rc<RefCell<Option<Sender<()>>>>,
+    fired: Shared<Receiver<()>>,
+}
+
+impl CancelToken {
+    /// Creates a new token, not cancelled yet.
+    pub fn new() -> Self {
+        let (sender, receiver) = oneshot::channel();
+        CancelToken {
+            sender: Rc::new(RefCell::new(Some(sender))),
+            fired: receiver.shared(),
+        }
+    }
+
+    /// Cancels the token, waking up everyone waiting for it.
+    pub fn cancel(&self) {
+        if let Some(sender) = self.sender.borrow_mut().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Checks if the token got cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.sender.borrow().is_none()
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken::new()
+    }
+}
+
+#[derive(Default)]
+struct NotifyState {
+    pending: Cell<bool>,
+    waiting: RefCell<Option<Task>>,
+}
+
+/// A handle to wake up a coroutine from a callback.
+///
+/// This bridges callback based code (like a C library reporting events through a callback) with
+/// the coroutines. The coroutine waits with
+/// [`Coroutine::wait_notify`](struct.Coroutine.html#method.wait_notify) and the callback calls
+/// [`notify`](#method.notify) on a clone of the handle. Unlike sending through a channel, no
+/// allocation happens per event.
+///
+/// The notifications don't queue ‒ several of them before the coroutine gets to waiting are
+/// merged into one. A notification coming before the coroutine waits is not lost, though, the
+/// next wait returns right away.
+///
+/// The handle is bound to its thread, the callback must run on the thread of the reactor.
+#[derive(Clone, Default)]
+pub struct CoNotify(Rc<NotifyState>);
+
+impl CoNotify {
+    /// Creates a new handle, with no notification pending.
+    pub fn new() -> Self {
+        CoNotify::default()
+    }
+
+    /// Wakes up the coroutine waiting on the handle (or the next one to wait).
+    pub fn notify(&self) {
+        self.0.pending.set(true);
+        if let Some(task) = self.0.waiting.borrow_mut().take() {
+            task.notify();
+        }
+    }
+
+    /// Takes the pending notification, if any.
+    fn take_pending(&self) -> bool {
+        self.0.pending.replace(false)
+    }
+}
+
+impl Future for CoNotify {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.take_pending() {
+            Ok(Async::Ready(()))
+        } else {
+            *self.0.waiting.borrow_mut() = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Counts of the coroutines on a reactor, as returned by
+/// [`Coroutine::reactor_stats`](struct.Coroutine.html#method.reactor_stats).
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReactorStats {
+    /// The coroutines that started and didn't terminate yet.
+    pub alive: usize,
+    /// The alive coroutines suspended, waiting for something.
+    pub parked: usize,
+    /// The alive coroutines that are running or ready to be resumed.
+    ///
+    /// This includes the coroutine asking for the statistics (and its parents, if it was spawned
+    /// from within another coroutine and didn't wait for anything yet).
+    pub runnable: usize,
+}
+
+/// Runs the poll of a future some coroutine waits for, noting the reactor of the coroutine.
+///
+/// This makes [`Coroutine::current_handle`] work from within the poll.
+pub(crate) fn polling_for<R, F>(handle: &Reactor, cancel: &Rc<CancelState>, poll: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let depth = CONTEXTS.with(|c| c.borrow().len());
+    POLLING.with(|p| p.borrow_mut().push((handle.clone(), depth, cancel.clone())));
+    let watchdog = POLL_WATCHDOG.with(Cell::get).map(|threshold| (threshold, Instant::now()));
+    // Unlike the poll, this doesn't panic.
+    let result = poll();
+    POLLING.with(|p| p.borrow_mut().pop());
+    if let Some((threshold, start)) = watchdog {
+        let elapsed = start.elapsed();
+        if elapsed > threshold {
+            warn!("Polling a future a coroutine waits for took {:?} (over {:?}), blocking the \
+                   reactor", elapsed, threshold);
+        }
+    }
+    result
+}
+
+/// Makes the context the current one, until it is taken out by `pop_context`.
+fn push_context(context: CoroutineContext) {
+    context.span.enter();
+    CONTEXTS.with(|c| c.borrow_mut().push(context));
+}
+
+/// Takes the context of the currently running coroutine out.
+///
+/// The contexts form a stack, because the coroutines are strictly nested ‒ a coroutine that
+/// switches into another one (by spawning or resuming it) gets control back only after the other
+/// one switched out again. Each switch out pops the context pushed by the corresponding switch in.
+/// If that ever didn't hold, we would pick up a stranger's stack and parent, which would end badly
+/// much later, so we check the popped context really belongs to the running coroutine.
+///
+/// Returns `None` if there's no coroutine running.
+fn pop_context() -> Option<CoroutineContext> {
+    let context = CONTEXTS.with(|c| c.borrow_mut().pop());
+    if let Some(ref context) = context {
+        context.span.exit();
+        let marker = 0u8;
+        let here = &marker as *const u8 as usize;
+        let bottom = context.stack.bottom() as usize;
+        let top = context.stack.top() as usize;
+        debug_assert!(bottom <= here && here < top, "Coroutine context stack imbalance");
+    }
+    context
+}
+
+/// Attaches the label of a wait to a panic unwinding through it.
+///
+/// The labels of the outer waits are added in front of the inner ones as the panic propagates.
+fn label_panic(panic: Box<Any + Send + 'static>, label: &'static str)
+    -> Box<Any + Send + 'static>
+{
+    match panic.downcast::<LabeledPanic>() {
+        Ok(mut labeled) => {
+            labeled.trail.insert(0, label);
+            labeled
+        },
+        Err(panic) => Box::new(LabeledPanic {
+            trail: vec![label],
+            payload: panic,
+        }),
+    }
+}
+
+/// Runs the task of a coroutine, catching any panics coming out of it.
+///
+/// Returns the result to report and possibly a panic to propagate further up.
+#[cfg(not(corona_panic_abort))]
+fn run_task<R, Task>(task: Task, propagate_panic: bool)
+    -> (TaskResult<R>, Option<Box<Any + Send + 'static>>)
+where
+    Task: FnOnce() -> R + UnwindSafe,
+{
+    match panic::catch_unwind(task) {
+        Ok(res) => (TaskResult::Finished(res), None),
+        Err(panic) => {
+            if panic.is::<Dropped>() {
+                (TaskResult::Lost, None)
+            } else if propagate_panic {
+                (TaskResult::PanicPropagated, Some(panic))
+            } else {
+                (TaskResult::Panicked(panic), None)
+            }
+        },
+    }
+}
+
+/// Runs the task of a coroutine directly.
+///
+/// With `panic = "abort"`, there's never anything to catch and the `catch_unwind` would be just an
+/// overhead.
+#[cfg(corona_panic_abort)]
+fn run_task<R, Task>(task: Task, _propagate_panic: bool)
+    -> (TaskResult<R>, Option<Box<Any + Send + 'static>>)
+where
+    Task: FnOnce() -> R + UnwindSafe,
+{
+    (TaskResult::Finished(task()), None)
+}
+
+/// The values attached to coroutines, keyed by their types.
+///
+/// Each value is stored as `Rc<T>` inside the `Any`, so it can be handed out as `Rc<T>`.
+type ContextValues = Rc<HashMap<TypeId, Rc<Any>>>;
+
+/// A builder of coroutines.
+///
+/// This struct is the main entry point and a way to start coroutines of various kinds. It allows
+/// both starting them with default parameters and configuring them with the builder pattern.
 #[derive(Clone)]
 pub struct Coroutine {
-    handle: Handle,
+    handle: Reactor,
     stack_size: usize,
     cleanup_strategy: CleanupStrategy,
+    priority: Option<u8>,
+    fair: bool,
+    values: ContextValues,
+    independent: bool,
+    prefault_stack: bool,
+    defer_start: bool,
+    on_complete: Option<Rc<Fn(CompletionInfo)>>,
+    limiter: Option<Rc<Limiter>>,
 }
 
+// The builder holds only the configuration and handles to the reactor and the shared limits,
+// which stay consistent when a panic passes through (the same as the bare `tokio_core` handle it
+// held originally). So it can be captured by the closures of `spawn_catch_panic`.
+impl UnwindSafe for Coroutine {}
+impl RefUnwindSafe for Coroutine {}
+
 impl Coroutine {
     /// Starts building a coroutine.
     ///
@@ -129,7 +1482,9 @@ impl Coroutine {
     /// # Parameters
     ///
     /// * `handle`: The coroutines need a reactor core to run on and schedule their control
-    ///   switches. This is the handle to the reactor core to be used.
+    ///   switches. This is the handle to the reactor core to be used. Usually, this is a
+    ///   `tokio_core` `Handle`, but anything implementing
+    ///   [`ReactorLike`](trait.ReactorLike.html) can be used.
     ///
     /// # Examples
     ///
@@ -147,11 +1502,19 @@ impl Coroutine {
     /// # }
     ///
     /// ```
-    pub fn new(handle: Handle) -> Self {
+    pub fn new<H: ReactorLike + 'static>(handle: H) -> Self {
         Coroutine {
-            handle,
+            handle: Rc::new(handle),
             stack_size: Stack::default_size(),
             cleanup_strategy: CleanupStrategy::CleanupAlways,
+            priority: None,
+            fair: false,
+            values: ContextValues::default(),
+            independent: false,
+            prefault_stack: false,
+            defer_start: false,
+            on_complete: None,
+            limiter: None,
         }
     }
 
@@ -163,7 +1526,9 @@ impl Coroutine {
     /// Note that the size must be a valid stack size. This is platform dependente, but usually
     /// must be multiple of a page size. That usually means a multiple of 4096.
     ///
-    /// If an invalid size is set, attemts to spawn coroutines will fail with an error.
+    /// If an invalid size is set, attemts to spawn coroutines will fail with an error. Use
+    /// [`try_stack_size`](#method.try_stack_size) to catch such mistakes when configuring the
+    /// builder instead.
     ///
     /// # Parameters
     ///
@@ -173,6 +1538,47 @@ impl Coroutine {
         self
     }
 
+    /// Configures the stack size, checking it first.
+    ///
+    /// This is like [`stack_size`](#method.stack_size), but a size that is zero or not a multiple
+    /// of the page size is rejected right away (and the builder is left unchanged), instead of
+    /// making the later spawns fail. The system may still refuse a stack too large, though.
+    ///
+    /// [`stack_size_rounded`](#method.stack_size_rounded) can be used to make a size acceptable.
+    pub fn try_stack_size(&mut self, size: usize) -> Result<&mut Self, InvalidStackSize> {
+        check_stack_size(size)?;
+        Ok(self.stack_size(size))
+    }
+
+    /// Configures the stack size according to a profile.
+    ///
+    /// This is [`stack_size`](#method.stack_size) with the size of the
+    /// [`StackProfile`](enum.StackProfile.html). The size is looked up at the time of this call,
+    /// later overrides of the profile don't affect the already configured builders.
+    pub fn profile(&mut self, profile: StackProfile) -> &mut Self {
+        self.stack_size(profile.size())
+    }
+
+    /// Overrides the stack size of a profile.
+    ///
+    /// The override is global for the whole process. It applies to the builders configured with
+    /// the profile from now on. The size is validated the same way as with
+    /// [`try_stack_size`](#method.try_stack_size).
+    pub fn set_profile_size(profile: StackProfile, size: usize) -> Result<(), InvalidStackSize> {
+        check_stack_size(size)?;
+        PROFILE_SIZES[profile.index()].store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Rounds a stack size up to the nearest valid one.
+    ///
+    /// The result is the smallest non-zero multiple of the page size that is at least `size`.
+    pub fn stack_size_rounded(size: usize) -> usize {
+        let page_size = stack_cache::page_size();
+        let pages = (size + page_size - 1) / page_size;
+        cmp::max(pages, 1) * page_size
+    }
+
     /// Configures how the coroutines should be cleaned up if the core is dropped before the
     /// coroutine resolves.
     ///
@@ -182,13 +1588,146 @@ impl Coroutine {
         self
     }
 
+    /// Configures the priority of resuming the coroutines.
+    ///
+    /// Normally, when the future a coroutine waits for resolves, the coroutine is resumed right
+    /// away, in whatever order the reactor happens to notice. Coroutines with a priority set are
+    /// instead resumed in order of their priorities (higher first) if several of them get ready
+    /// in the same reactor turn. The same goes for coroutines resumed after
+    /// [`yield_now`](#method.yield_now).
+    ///
+    /// This is a best-effort ordering. The coroutines are cooperative, so a running one is never
+    /// interrupted by a higher-priority one getting ready. Also, the priorities order only the
+    /// coroutines that have one set, the others are resumed directly without going through the
+    /// ordering. Resuming through the ordering is slightly slower.
+    ///
+    /// # Parameters
+    ///
+    /// * `priority`: The priority of the spawned coroutines. Higher values get resumed first.
+    pub fn priority(&mut self, priority: u8) -> &mut Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Configures round-robin resuming of the coroutines.
+    ///
+    /// Normally, the coroutines whose futures resolved are resumed in whatever order the reactor
+    /// polls the futures. With fairness turned on, they are put into a queue instead and resumed
+    /// in the order they got ready. The ones getting ready again while the queue is being worked
+    /// through wait for the next round, so no coroutine can keep getting ahead of the others (or
+    /// keep being the last one).
+    ///
+    /// This is meant for oversubscribed situations, with many coroutines ready most of the time.
+    /// The queue has a small cost, so it is off by default. It works only on the `tokio_core`
+    /// reactor and a [`priority`](#method.priority), if set, takes precedence.
+    pub fn fairness(&mut self, fair: bool) -> &mut Self {
+        self.fair = fair;
+        self
+    }
+
+    /// Attaches a value to the spawned coroutines.
+    ///
+    /// The value can be retrieved from within the coroutines with
+    /// [`get_context_value`](#method.get_context_value). This allows passing things like
+    /// request-scoped configuration down to deeply nested code without threading it through the
+    /// closure and all the function calls.
+    ///
+    /// One value of each type can be attached, setting another one of the same type replaces it.
+    /// All the coroutines spawned by the builder share the same value.
+    pub fn context_value<T: 'static>(&mut self, value: T) -> &mut Self {
+        let value: Rc<Any> = Rc::new(Rc::new(value));
+        Rc::make_mut(&mut self.values).insert(TypeId::of::<T>(), value);
+        self
+    }
+
+    /// Configures if the coroutines survive cancellation of the coroutine that spawned them.
+    ///
+    /// By default, a coroutine spawned from within another coroutine gets cancelled together with
+    /// it (see [`CoroutineResult::cancel`](struct.CoroutineResult.html#method.cancel)). The
+    /// independent ones are not.
+    pub fn independent(&mut self, independent: bool) -> &mut Self {
+        self.independent = independent;
+        self
+    }
+
+    /// Configures if the stacks should be backed by memory upfront.
+    ///
+    /// A freshly allocated stack is only reserved address space. The first time the coroutine
+    /// reaches deeper into it, each new page causes a page fault while the kernel finds memory for
+    /// it. This is usually fine, but it adds latency at unpredictable places.
+    ///
+    /// With this turned on, every page of a newly allocated stack is touched before the coroutine
+    /// starts. This makes spawning slower (and uses the whole stack size of memory right away), but
+    /// the coroutine doesn't get interrupted by these faults later on. Stacks reused from the
+    /// internal cache are not touched again.
+    pub fn prefault_stack(&mut self, yes: bool) -> &mut Self {
+        self.prefault_stack = yes;
+        self
+    }
+
+    /// Configures if the coroutines start running right away.
+    ///
+    /// By default, spawning a coroutine runs it until it first waits for something, before the
+    /// spawn returns. With this turned on, the start is scheduled onto the reactor instead and
+    /// spawning returns without running any of the task. This is useful when spawning from a
+    /// place that must not be re-entered by whatever the task does.
+    ///
+    /// This applies to [`spawn`](#method.spawn) and
+    /// [`spawn_catch_panic`](#method.spawn_catch_panic).
+    pub fn defer_start(&mut self, defer: bool) -> &mut Self {
+        self.defer_start = defer;
+        self
+    }
+
+    /// Sets a callback to be called whenever a coroutine spawned from this builder terminates.
+    ///
+    /// This allows observing the lifecycle of the coroutines in one place, without holding and
+    /// waiting for each [`CoroutineResult`](struct.CoroutineResult.html). The callback gets the
+    /// [`id`](struct.CoroutineResult.html#method.id) of the coroutine and how it ended.
+    ///
+    /// The callback is called right as the coroutine terminates, as the last thing on its own
+    /// stack (but outside of its context, so it can't wait for anything), after its result was
+    /// delivered. If it panics, the panic is propagated out of whatever ran the coroutine last
+    /// (usually the reactor's `run`), the same way as a panic inside [`spawn`](#method.spawn).
+    pub fn on_complete(&mut self, callback: Rc<Fn(CompletionInfo)>) -> &mut Self {
+        self.on_complete = Some(callback);
+        self
+    }
+
+    /// Limits how many coroutines spawned from this builder run at once.
+    ///
+    /// Once `max` of them are alive, further [`spawn`](#method.spawn) (and
+    /// [`spawn_catch_panic`](#method.spawn_catch_panic)) calls don't start the coroutine. It is
+    /// queued instead and started (from the reactor) as soon as one of the running ones
+    /// terminates, in the order they were spawned. The queued coroutines don't hold a stack yet.
+    /// Use [`CoroutineResult::started`](struct.CoroutineResult.html#method.started) to wait until
+    /// a coroutine actually starts, to apply back-pressure to whatever spawns them.
+    ///
+    /// The limit is shared by the clones of the builder made after this call. Calling this again
+    /// sets up a new, separate limit.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is 0.
+    pub fn max_concurrent(&mut self, max: usize) -> &mut Self {
+        assert!(max > 0, "At least one coroutine must be allowed to run");
+        self.limiter = Some(Rc::new(Limiter {
+            max,
+            running: Cell::new(0),
+            pending: RefCell::new(VecDeque::new()),
+        }));
+        self
+    }
+
     /// Spawns a coroutine directly.
     ///
     /// This constructor spawns a coroutine with default parameters without the inconvenience of
     /// handling a builder. It is equivalent to spawning it with an unconfigured builder.
     ///
     /// Unlike the [`spawn`](#method.spawn.html), this one can't fail, since the default parameters
-    /// of the builder are expected to always work (if they don't, file a bug).
+    /// of the builder are expected to always work (if they don't, file a bug). Spawning onto a
+    /// reactor that is already gone is not reported either ‒ the coroutine is destroyed on its
+    /// first wait, the same as if the reactor was dropped a moment later.
     ///
     /// # Parameters
     ///
@@ -218,57 +1757,475 @@ impl Coroutine {
     /// # }
     ///
     /// ```
-    pub fn with_defaults<R, Task>(handle: Handle, task: Task) -> CoroutineResult<R>
+    pub fn with_defaults<R, Task, H>(handle: H, task: Task) -> CoroutineResult<R>
     where
         R: 'static,
         Task: FnOnce() -> R + 'static,
+        H: ReactorLike + 'static,
     {
-        Coroutine::new(handle).spawn(task).unwrap()
+        let builder = Coroutine::new(handle);
+        builder
+            .check_shutdown()
+            .and_then(|()| builder.spawn_unchecked(AssertUnwindSafe(task), true))
+            .unwrap()
     }
 
-    /// The inner workings of `spawn` and `spawn_catch_panic`.
-    fn spawn_inner<R, Task>(&self, task: Task, propagate_panic: bool)
-        -> Result<CoroutineResult<R>, StackError>
-    where
-        R: 'static,
-        Task: FnOnce() -> R + UnwindSafe + 'static,
-    {
-        let (sender, receiver) = oneshot::channel();
-
-        let handle = self.handle.clone();
-        let cleanup_strategy = self.cleanup_strategy;
-
-        let perform = move |context, stack| {
-            let my_context = CoroutineContext {
-                handle,
+    /// Runs a coroutine to completion on a fresh reactor.
+    ///
+    /// This creates a new `Core`, spawns the `task` onto it with default parameters and runs the
+    /// core until the coroutine terminates. The core is owned by this call and dropped before it
+    /// returns, therefore any other coroutines spawned onto it that didn't finish by then are
+    /// cleaned up.
+    ///
+    /// This is mostly a convenience for small programs and tests, which would otherwise need to
+    /// create the core, get its handle and run it by hand.
+    ///
+    /// # Parameters
+    ///
+    /// * `task`: The closure to run inside the coroutine.
+    ///
+    /// # Returns
+    ///
+    /// The result of the `task`, or an error explaining why the coroutine failed.
+    ///
+    /// # Panics
+    ///
+    /// If the reactor core can't be created.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// use corona::prelude::*;
+    /// use futures::future;
+    ///
+    /// # fn main() {
+    /// let result = Coroutine::run(|| {
+    ///     let half = future::ok::<_, ()>(21).coro_wait().unwrap();
+    ///     half * 2
+    /// });
+    /// assert_eq!(42, result.unwrap());
+    /// # }
+    /// ```
+    pub fn run<R, Task>(task: Task) -> Result<R, TaskFailed>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + 'static,
+    {
+        let mut core = Core::new().expect("Failed to create a reactor core");
+        let coroutine = Coroutine::with_defaults(core.handle(), task);
+        core.run(coroutine)
+    }
+
+    /// Sets up a reactor for the current thread.
+    ///
+    /// The library then owns a `Core` for the thread. Coroutines can be spawned onto it with
+    /// [`per_thread_spawn`](#method.per_thread_spawn) without passing handles around and it is
+    /// driven by [`run_thread_reactor`](#method.run_thread_reactor). This suits servers with one
+    /// reactor per worker thread.
+    ///
+    /// The reactor lives until [`drop_thread_reactor`](#method.drop_thread_reactor) is called.
+    /// If the thread terminates before that, the reactor and the coroutines on it are leaked (they
+    /// can't be cleaned up properly during the thread's destruction).
+    ///
+    /// Calling it again on a thread that already has the reactor does nothing.
+    ///
+    /// # Panics
+    ///
+    /// If the reactor core can't be created.
+    pub fn init_thread_reactor() {
+        THREAD_REACTOR.with(|r| {
+            let mut reactor = r.borrow_mut();
+            if reactor.handle.is_none() {
+                let core = Core::new().expect("Failed to create a reactor core");
+                reactor.handle = Some(core.handle());
+                reactor.core = Some(core);
+            }
+        });
+    }
+
+    /// Spawns a coroutine onto the reactor of the current thread.
+    ///
+    /// This is like [`with_defaults`](#method.with_defaults), using the reactor set up by
+    /// [`init_thread_reactor`](#method.init_thread_reactor). It can be called from anywhere on
+    /// the thread, including from within other coroutines.
+    ///
+    /// # Panics
+    ///
+    /// If the thread has no reactor set up.
+    pub fn per_thread_spawn<R, Task>(task: Task) -> CoroutineResult<R>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + 'static,
+    {
+        let handle = THREAD_REACTOR.with(|r| r.borrow().handle.clone())
+            .expect("No reactor set up for this thread");
+        Coroutine::with_defaults(handle, task)
+    }
+
+    /// Drives the reactor of the current thread until the future resolves.
+    ///
+    /// The future is usually the result of a coroutine spawned with
+    /// [`per_thread_spawn`](#method.per_thread_spawn).
+    ///
+    /// # Panics
+    ///
+    /// If the thread has no reactor set up or if the reactor is already running (eg. this is
+    /// called from within a coroutine on it).
+    pub fn run_thread_reactor<F: Future>(future: F) -> Result<F::Item, F::Error> {
+        let core = THREAD_REACTOR.with(|r| r.borrow_mut().core.take())
+            .expect("No reactor set up for this thread or it is already running");
+        // Put it back even if something panics.
+        struct Restore(Option<Core>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let core = self.0.take();
+                THREAD_REACTOR.with(|r| r.borrow_mut().core = core);
+            }
+        }
+        let mut restore = Restore(Some(core));
+        let result = restore.0.as_mut().unwrap().run(future);
+        result
+    }
+
+    /// Destroys the reactor of the current thread.
+    ///
+    /// The coroutines still alive on it are cleaned up, the same way as when any other core is
+    /// dropped. Afterwards, a new reactor may be set up by
+    /// [`init_thread_reactor`](#method.init_thread_reactor).
+    ///
+    /// # Panics
+    ///
+    /// If the reactor is running at the moment.
+    pub fn drop_thread_reactor() {
+        let core = THREAD_REACTOR.with(|r| {
+            let mut reactor = r.borrow_mut();
+            assert!(reactor.core.is_some() || reactor.handle.is_none(),
+                    "Can't drop the thread reactor while it is running");
+            reactor.handle = None;
+            reactor.core.take()
+        });
+        // Drop it outside of the borrow, the coroutines may want to spawn more things.
+        drop(core);
+    }
+
+    /// Counts the coroutines on the reactor of the current coroutine.
+    ///
+    /// This is a read-only view for monitoring ‒ how many coroutines are alive on the reactor and
+    /// how many of them are parked (waiting for something) or runnable. Only the coroutines that
+    /// already started are counted (lazy or deferred ones that didn't run yet are not).
+    ///
+    /// The coroutines are tracked per thread, so only the ones on the current thread are seen.
+    /// Coroutines running on other implementations of [`ReactorLike`](trait.ReactorLike.html)
+    /// than the `tokio_core` reactor are counted all together.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. See
+    /// [`reactor_stats_of`](#method.reactor_stats_of) for the outside.
+    pub fn reactor_stats() -> ReactorStats {
+        let core = CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't get the reactor stats outside of a coroutine")
+                .handle
+                .tokio_handle_ref()
+                .map(Handle::id)
+        });
+        stats(core)
+    }
+
+    /// Counts the coroutines on the given reactor.
+    ///
+    /// This is like [`reactor_stats`](#method.reactor_stats), but the reactor is specified
+    /// explicitly, so it can be called from anywhere on the reactor's thread.
+    pub fn reactor_stats_of(handle: &Handle) -> ReactorStats {
+        stats(Some(handle.id()))
+    }
+
+    /// Checks that no coroutine on the current thread is still alive.
+    ///
+    /// With the `leak-check` feature, every coroutine is tracked from the moment it is spawned
+    /// until it terminates (or is dropped without ever running). This is meant for test suites, to
+    /// catch coroutines that were forgotten parked on something that never happens.
+    ///
+    /// The tracking is per thread, as are the coroutines. Coroutines suspended on a still existing
+    /// reactor count as alive, so the reactor should usually be dropped first if it is expected to
+    /// clean them up.
+    ///
+    /// # Panics
+    ///
+    /// If any coroutine spawned on this thread is still alive. The message lists their ids (see
+    /// [`CoroutineResult::id`](struct.CoroutineResult.html#method.id)).
+    #[cfg(feature = "leak-check")]
+    pub fn assert_no_leaks() {
+        let mut leaked = LEAKS.with(|l| {
+            l.borrow()
+                .iter()
+                .map(|(id, since)| (*id, since.elapsed()))
+                .collect::<Vec<_>>()
+        });
+        if !leaked.is_empty() {
+            leaked.sort();
+            let list = leaked
+                .iter()
+                .map(|&(id, age)| format!("#{} (alive for {:?})", id, age))
+                .collect::<Vec<_>>()
+                .join(", ");
+            panic!("{} coroutine(s) leaked: {}", leaked.len(), list);
+        }
+    }
+
+    /// Frees the pooled stacks that are not in use.
+    ///
+    /// The stacks of terminated coroutines are kept in a pool for reuse by future coroutines.
+    /// After a spike, the pool may hold a lot of memory that is no longer needed. This returns the
+    /// pooled stacks that weren't used within the idle window (see
+    /// [`stack_pool_idle`](#method.stack_pool_idle)) to the OS, or all of them if no window is set.
+    ///
+    /// The pool is per thread, so this affects only the current thread.
+    ///
+    /// Returns the number of freed stacks.
+    pub fn trim_stack_pool() -> usize {
+        stack_cache::trim()
+    }
+
+    /// Sets the idle window of the stack pool of the current thread.
+    ///
+    /// If set, the pooled stacks that aren't used for at least this long are freed automatically
+    /// (the check happens whenever a stack is taken from or returned to the pool, but at most once
+    /// per the window). It also limits what [`trim_stack_pool`](#method.trim_stack_pool) frees.
+    /// `None` (the default) turns the automatic trimming off.
+    pub fn stack_pool_idle(idle: Option<Duration>) {
+        stack_cache::set_idle(idle);
+    }
+
+    /// Shuts down the coroutines on the current thread.
+    ///
+    /// First, spawning new coroutines is refused (with
+    /// [`SpawnError::ShuttingDown`](enum.SpawnError.html#variant.ShuttingDown)) and the core is
+    /// driven until all the running coroutines terminate on their own, but at most for the
+    /// `timeout`. The ones still running after that are cancelled (see
+    /// [`CoroutineResult::cancel`](struct.CoroutineResult.html#method.cancel)) and the core is
+    /// driven for up to another `timeout` to let them unwind. Then spawning is allowed again.
+    ///
+    /// This covers all the coroutines on the current thread, so it is meant for the usual setup
+    /// with a single core per thread. Coroutines that are cancelled but never wait for anything
+    /// again (or are suspended by [`suspend`](#method.suspend) and never resumed) are left alone.
+    /// The ones with a leaking [cleanup strategy](#method.cleanup_strategy) are leaked by the
+    /// cancellation.
+    ///
+    /// # Returns
+    ///
+    /// How many coroutines terminated on their own and how many had to be cancelled.
+    ///
+    /// # Panics
+    ///
+    /// If called from within a coroutine.
+    pub fn shutdown_graceful(core: &mut Core, timeout: Duration) -> ShutdownReport {
+        assert!(CONTEXTS.with(|c| c.borrow().is_empty()),
+                "Can't shut down from within a coroutine");
+        REGISTRY.with(|r| r.borrow_mut().shutting_down = true);
+        let terminated = terminated_coroutines();
+        drive_until_terminated(core, timeout);
+        // Count them explicitly, lazy and deferred coroutines may have started in the meantime.
+        let completed = terminated_coroutines() - terminated;
+        let stragglers = REGISTRY.with(|r| {
+            r.borrow()
+                .live()
+                .into_iter()
+                .map(|(cancel, _)| cancel)
+                .collect::<Vec<_>>()
+        });
+        for straggler in &stragglers {
+            straggler.cancel();
+        }
+        if !stragglers.is_empty() {
+            drive_until_terminated(core, timeout);
+        }
+        REGISTRY.with(|r| r.borrow_mut().shutting_down = false);
+        ShutdownReport {
+            completed,
+            cancelled: stragglers.len(),
+        }
+    }
+
+    /// Checks the coroutines on this thread are not being shut down.
+    fn check_shutdown(&self) -> Result<(), SpawnError> {
+        if REGISTRY.with(|r| r.borrow().shutting_down) {
+            Err(SpawnError::ShuttingDown)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks if new coroutines can be spawned at all.
+    fn check_spawn(&self) -> Result<(), SpawnError> {
+        self.check_shutdown()?;
+        if self.handle.is_running() {
+            Ok(())
+        } else {
+            Err(SpawnError::ReactorGone)
+        }
+    }
+
+    /// The inner workings of `spawn` and `spawn_catch_panic`.
+    fn spawn_inner<R, Task>(&self, task: Task, propagate_panic: bool)
+        -> Result<CoroutineResult<R>, SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + UnwindSafe + 'static,
+    {
+        self.check_spawn()?;
+        self.spawn_unchecked(task, propagate_panic)
+    }
+
+    /// The rest of `spawn_inner`, without checking the reactor still runs.
+    fn spawn_unchecked<R, Task>(&self, task: Task, propagate_panic: bool)
+        -> Result<CoroutineResult<R>, SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + UnwindSafe + 'static,
+    {
+        let stack = stack_cache::get(self.stack_size, self.prefault_stack)?;
+        let (perform, result) = self.prepare(task, propagate_panic);
+        if let Some(ref limiter) = self.limiter {
+            if !limiter.acquire() {
+                // Don't hold the stack while queued, we'll get one once it's our turn.
+                stack_cache::put(stack);
+                let (stack_size, prefault) = (self.stack_size, self.prefault_stack);
+                let handle = self.handle.clone();
+                let weak_limiter = Rc::downgrade(limiter);
+                let mut perform = Some(perform);
+                limiter.pending.borrow_mut().push_back(Box::new(move || {
+                    let perform = perform.take().expect("Coroutine started twice");
+                    match stack_cache::get(stack_size, prefault) {
+                        Ok(stack) => Switch::run_new_coroutine(stack, perform),
+                        // No stack for us now, the coroutine is lost. Let the next one try.
+                        Err(_) => if let Some(limiter) = weak_limiter.upgrade() {
+                            Limiter::release(&limiter, &handle);
+                        },
+                    }
+                }));
+                return Ok(result);
+            }
+        }
+        if self.defer_start {
+            // If the reactor never gets to it, the task is dropped and the result reports it lost.
+            let start = move || {
+                Switch::run_new_coroutine(stack, perform);
+                Ok::<(), ()>(())
+            };
+            match self.handle.tokio_handle_ref() {
+                Some(handle) => handle.spawn_fn(start),
+                None => self.handle.spawn_task(Box::new(future::lazy(start))),
+            }
+        } else {
+            Switch::run_new_coroutine(stack, perform);
+        }
+        Ok(result)
+    }
+
+    /// Prepares the body of a coroutine, without starting it.
+    ///
+    /// Returns the thing to run inside the new coroutine and the future for its result.
+    fn prepare<R, Task>(&self, task: Task, propagate_panic: bool)
+        -> (BoxedTask, CoroutineResult<R>)
+    where
+        R: 'static,
+        Task: FnOnce() -> R + UnwindSafe + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+
+        let handle = self.handle.clone();
+        let cleanup_strategy = self.cleanup_strategy;
+        let priority = self.priority;
+        let fair = self.fair;
+        let values = self.values.clone();
+        let cancel = self.new_cancel();
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let on_complete = self.on_complete.clone();
+        let leak_guard = LeakGuard::new(id);
+        // Weak, as the queued coroutines are stored inside the limiter.
+        let limiter = self.limiter.as_ref().map(Rc::downgrade);
+        let (started_sender, started) = if limiter.is_some() {
+            let (sender, receiver) = oneshot::channel();
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+        let result = CoroutineResult {
+            receiver,
+            cancel: cancel.clone(),
+            id,
+            started,
+        };
+
+        let perform = move |context, stack| {
+            // Once running, keep the limiter alive so we can pass the slot on.
+            let limiter = limiter.and_then(|limiter| limiter.upgrade());
+            register(&cancel, handle.tokio_handle_ref().map(Handle::id));
+            if let Some(started) = started_sender {
+                let _ = started.send(());
+            }
+            let my_context = CoroutineContext {
+                handle,
                 parent_context: context,
                 stack,
                 cleanup_strategy,
+                priority,
+                fair,
+                values,
+                cancel,
+                span: CoroutineSpan::start(id),
             };
-            CONTEXTS.with(|c| c.borrow_mut().push(my_context));
-            let mut panic_result = None;
-            let result = match panic::catch_unwind(AssertUnwindSafe(task)) {
-                Ok(res) => TaskResult::Finished(res),
-                Err(panic) => {
-                    if panic.is::<Dropped>() {
-                        TaskResult::Lost
-                    } else if propagate_panic {
-                        panic_result = Some(panic);
-                        TaskResult::PanicPropagated
-                    } else {
-                        TaskResult::Panicked(panic)
-                    }
-                },
+            push_context(my_context);
+            let (result, panic_result) = run_task(task, propagate_panic);
+            let status = match result {
+                TaskResult::Finished(_) => CompletionStatus::Succeeded,
+                TaskResult::Panicked(_) | TaskResult::PanicPropagated => CompletionStatus::Panicked,
+                TaskResult::Lost => CompletionStatus::Lost,
             };
             // We are not interested in errors. They just mean the receiver is no longer
             // interested, which is fine by us.
             drop(sender.send(result));
-            let my_context = CONTEXTS.with(|c| c.borrow_mut().pop().unwrap());
+            let my_context = pop_context().unwrap();
+            unregister(&my_context.cancel);
+            leak_guard.release();
+            my_context.span.complete(status);
+            let mut panic_result = panic_result;
+            if let Some(on_complete) = on_complete {
+                // We are still on the coroutine's stack, the panic can't unwind out of here.
+                // Carry it out the same way as a propagated panic of the task.
+                let info = CompletionInfo { id, status };
+                if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| on_complete(info))) {
+                    panic_result = panic_result.or(Some(panic));
+                }
+            }
+            if let Some(limiter) = limiter {
+                Limiter::release(&limiter, &my_context.handle);
+            }
             (my_context.parent_context, my_context.stack, panic_result)
         };
-        Switch::run_new_coroutine(self.stack_size, Box::new(Some(perform)))?;
 
-        Ok(CoroutineResult { receiver })
+        let perform: BoxedTask = Box::new(Some(perform));
+        (perform, result)
+    }
+
+    /// Creates the cancellation state for a new coroutine.
+    ///
+    /// Unless independent, it is linked to the currently running coroutine, if any.
+    fn new_cancel(&self) -> Rc<CancelState> {
+        let cancel = Rc::new(CancelState::default());
+        if !self.independent {
+            CONTEXTS.with(|c| {
+                if let Some(parent) = c.borrow().last() {
+                    parent.cancel.adopt(&cancel);
+                }
+            });
+        }
+        cancel
     }
 
     /// Spawns a coroutine with configuration from the builder.
@@ -284,7 +2241,8 @@ impl Coroutine {
     /// A future that'll resolve once the coroutine terminates and will yield the result of
     /// `task`, or an error explaining why the coroutine failed.
     ///
-    /// This returns a `StackError` if the configured stack size is invalid.
+    /// This returns a `SpawnError` if the configured stack size is invalid or if the reactor is
+    /// no longer running.
     ///
     /// # Examples
     ///
@@ -310,7 +2268,7 @@ impl Coroutine {
     /// If the coroutine panics, the panic is propagated. This usually means the `core.run`, unless
     /// the panic happens before the first suspension point, in which case it is the `spawn` itself
     /// which panics.
-    pub fn spawn<R, Task>(&self, task: Task) -> Result<CoroutineResult<R>, StackError>
+    pub fn spawn<R, Task>(&self, task: Task) -> Result<CoroutineResult<R>, SpawnError>
     where
         R: 'static,
         Task: FnOnce() -> R + 'static,
@@ -320,6 +2278,25 @@ impl Coroutine {
         self.spawn_inner(AssertUnwindSafe(task), true)
     }
 
+    /// Spawns a coroutine and reports where it was placed.
+    ///
+    /// This is the same as [`spawn`](#method.spawn), but the result comes bundled with the id of
+    /// the reactor the coroutine runs on and the id of the coroutine itself. Schedulers juggling
+    /// several reactors can use these to route follow-up work to the same reactor.
+    pub fn spawn_with_ids<R, Task>(&self, task: Task) -> Result<SpawnHandle<R>, SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + 'static,
+    {
+        let reactor_id = self.handle.tokio_handle_ref().map(assign_reactor_id);
+        let result = self.spawn(task)?;
+        Ok(SpawnHandle {
+            coroutine_id: result.id(),
+            result,
+            reactor_id,
+        })
+    }
+
     /// Spawns a coroutine, preventing the panics in it from killing the parent task.
     ///
     /// This is just like [spawn](#method.spawn), but any panic in the coroutine is captured and
@@ -327,7 +2304,7 @@ impl Coroutine {
     ///
     /// Note that you need to ensure the `task` is [unwind
     /// safe](https://doc.rust-lang.org/std/panic/trait.UnwindSafe.html) for that reason.
-    pub fn spawn_catch_panic<R, Task>(&self, task: Task) -> Result<CoroutineResult<R>, StackError>
+    pub fn spawn_catch_panic<R, Task>(&self, task: Task) -> Result<CoroutineResult<R>, SpawnError>
     where
         R: 'static,
         Task: FnOnce() -> R + UnwindSafe + 'static,
@@ -335,183 +2312,4772 @@ impl Coroutine {
         self.spawn_inner(task, false)
     }
 
-    /// Waits for completion of a future.
+    /// Spawns a coroutine that starts on a later turn of the reactor.
     ///
-    /// This suspends the execution of the current coroutine until the provided future is
-    /// completed, possibly switching to other coroutines in the meantime.
+    /// This is like [`spawn`](#method.spawn), except the coroutine doesn't run before this
+    /// returns. Its start is registered through `Handle::spawn_fn` (or the equivalent on
+    /// reactors other than `tokio_core`) and happens once the reactor gets to it.
     ///
-    /// This is the low-level implementation of the waiting. It is expected user code will use the
-    /// interface in [`prelude`](../prelude/index.html) instead.
+    /// The effect is the same as with [`defer_start`](#method.defer_start), but only for this one
+    /// coroutine ‒ the builder is left as it is. This comes handy when most coroutines should
+    /// start right away, but some are spawned from places that must not be re-entered.
+    pub fn spawn_lazy_fn<R, Task>(&self, task: Task) -> Result<CoroutineResult<R>, SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + 'static,
+    {
+        let mut deferred = self.clone();
+        deferred.defer_start = true;
+        deferred.spawn(task)
+    }
+
+    /// Spawns a coroutine that sends its result into a sink.
+    ///
+    /// This is useful when many coroutines produce results for a single collector ‒ instead of
+    /// waiting for each `CoroutineResult` separately, the collector reads them from a channel.
+    ///
+    /// Panics are caught, like with [`spawn_catch_panic`](#method.spawn_catch_panic), and sent as
+    /// `Err(TaskFailed::Panicked)`. The result is sent once the coroutine terminates, by a future
+    /// spawned onto the reactor. If the sink refuses it (eg. the receiving side is gone), the
+    /// result is silently dropped.
     ///
     /// # Parameters
     ///
-    /// * `fut`: The future to wait on.
+    /// * `sink`: Where to send the result, for example the sending side of a channel.
+    /// * `task`: The closure to run inside the coroutine.
     ///
     /// # Returns
     ///
-    /// * `Ok(result)` with the result the future resolved to.
-    /// * `Err(Dropped)` when the reactor was dropped before the future had a chance to resolve.
+    /// This returns a `SpawnError` if the configured stack size is invalid or if the reactor is
+    /// no longer running.
+    pub fn spawn_into<R, Task, S>(&self, sink: S, task: Task) -> Result<(), SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + UnwindSafe + 'static,
+        S: Sink<SinkItem = Result<R, TaskFailed>> + 'static,
+    {
+        let result = self.spawn_catch_panic(task)?;
+        let forward = result
+            .then(move |result| sink.send(result))
+            .map(|_sink| ())
+            .map_err(|_| ());
+        self.handle.spawn_task(Box::new(forward));
+        Ok(())
+    }
+
+    /// Spawns a coroutine that gets restarted if it panics.
     ///
-    /// # Panics
+    /// This is similar to [`spawn_catch_panic`](#method.spawn_catch_panic), but if the `task`
+    /// panics, it is run again, in a new coroutine (the old one is completely gone by then). This
+    /// happens at most `max_restarts` times, after that the last panic is reported through the
+    /// result.
     ///
-    /// If called outside of a coroutine (there's nothing to suspend).
+    /// As the task is run again after it panicked, it is up to it to make sure its state isn't
+    /// broken by the panic.
     ///
-    /// Also, panics from withit the provided future are propagated into the calling coroutine.
-    pub fn wait<I, E, Fut>(mut fut: Fut) -> Result<Result<I, E>, Dropped>
+    /// # Parameters
+    ///
+    /// * `max_restarts`: How many times the task may be restarted after panicking.
+    /// * `task`: The closure to run inside the coroutine.
+    ///
+    /// # Returns
+    ///
+    /// A future that'll resolve once the task terminates without a panic (or the restarts run
+    /// out) and will yield the result of `task`, or the error explaining the failure.
+    ///
+    /// This returns a `SpawnError` if the configured stack size is invalid or if the reactor is
+    /// no longer running.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate tokio_core;
+    /// use corona::Coroutine;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let mut attempt = 0;
+    /// let coroutine = Coroutine::new(core.handle())
+    ///     .spawn_supervised(2, move || {
+    ///         attempt += 1;
+    ///         if attempt < 2 {
+    ///             panic!("Not yet");
+    ///         }
+    ///         attempt
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(2, core.run(coroutine).unwrap());
+    /// # }
+    /// ```
+    pub fn spawn_supervised<R, Task>(&self, max_restarts: usize, task: Task)
+        -> Result<CoroutineResult<R>, SpawnError>
     where
-        Fut: Future<Item = I, Error = E>,
+        R: 'static,
+        Task: FnMut() -> R + 'static,
     {
-        // Grimoire marginalia (eg. a sidenote on the magic here).
-        //
-        // This is probably the hearth of the library both in the importance and complexity to
-        // understand. We want to wait for the future to finish.
-        //
-        // To do that we do the following:
-        // • Prepare a space for the result on our own stack.
-        // • Prepare a wrapper future that'll do some bookkeeping around the user's future ‒ for
-        //   example makes sure the wrapper future has the same signature and can be spawned onto
-        //   the reactor.
-        // • Switch to our parent context with the instruction to install the future for us into
-        //   the reactor.
-        //
-        // Some time later, as the reactor runs, the future resolves. It'll do the following:
-        // • Store the result into the prepared space on our stack.
-        // • Switch the context back to us.
-        // • This function resumes, picks ups the result from its stack and returns it.
-        //
-        // There are few unsafe blocks here, some of them looking a bit dangerous. So, some
-        // rationale why this should be in fact safe.
-        //
-        // The handle.spawn() requires a 'static future. It is because the future will almost
-        // certainly live longer than the stack frame that spawned it onto the reactor. Therefore,
-        // the future must own anything it'll touch in some unknown later time.
-        //
-        // However, this is true in our case. The closure that runs in a coroutine is required to
-        // be 'static. Therefore, anything non-'static must live on the coroutine's stack. And the
-        // future has the only pointer to the stack of this coroutine, therefore effectively owns
-        // the stack and everything on it.
-        //
-        // In other words, the stack is there for as long as the future waits idle in the reactor
-        // and won't go away before the future either resolves or is dropped. There's a small trick
-        // in the `drop` implementation and the future itself to ensure this is true even when
-        // switching the contexts (it is true when we switch to this coroutine, but not after we
-        // leave it, so the future's implementation must not touch the things afterwards.
-        let my_context = CONTEXTS.with(|c| {
-            c.borrow_mut().pop().expect("Can't wait outside of a coroutine")
-        });
+        let task = Rc::new(RefCell::new(task));
+        let cancel = self.new_cancel();
+        // The incarnations are cancelled through the restarter, not through whoever happens to
+        // start them.
+        let mut builder = self.clone();
+        builder.independent(true);
+        let current = Restarter::start(&builder, &task, &cancel)?;
+        let (sender, receiver) = oneshot::channel();
+        let id = current.id;
+        self.handle.spawn_task(Box::new(Restarter {
+            builder,
+            task,
+            restarts_left: max_restarts,
+            current,
+            sender: Some(sender),
+            cancel: cancel.clone(),
+        }));
+        Ok(CoroutineResult {
+            receiver,
+            cancel,
+            id,
+            started: None,
+        })
+    }
+
+    /// Prepares a coroutine that starts only once its result is polled.
+    ///
+    /// This is like [`spawn`](#method.spawn), but the `task` doesn't start right away. It starts
+    /// when the returned future is polled for the first time (similar to `future::lazy`). This is
+    /// useful for building pipelines that should do work only on demand.
+    ///
+    /// The stack is allocated already by this call, so configuration errors are reported early.
+    ///
+    /// # Parameters
+    ///
+    /// * `task`: The closure to run inside the coroutine.
+    ///
+    /// # Returns
+    ///
+    /// A future that starts the coroutine on the first poll and then resolves once the coroutine
+    /// terminates.
+    ///
+    /// This returns a `SpawnError` if the configured stack size is invalid or if the reactor is
+    /// no longer running.
+    ///
+    /// # Panic handling
+    ///
+    /// Panics are propagated, like with `spawn`. A panic before the first suspension point falls
+    /// out of the first poll of the returned future.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate tokio_core;
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    /// use corona::Coroutine;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let started = Rc::new(Cell::new(false));
+    /// let started_cp = started.clone();
+    /// let coroutine = Coroutine::new(core.handle())
+    ///     .lazy(move || started_cp.set(true))
+    ///     .unwrap();
+    /// assert!(!started.get());
+    /// core.run(coroutine).unwrap();
+    /// assert!(started.get());
+    /// # }
+    /// ```
+    pub fn lazy<R, Task>(&self, task: Task) -> Result<LazyCoroutine<R>, SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + 'static,
+    {
+        self.check_spawn()?;
+        let stack = stack_cache::get(self.stack_size, self.prefault_stack)?;
+        let (perform, result) = self.prepare(AssertUnwindSafe(task), true);
+        Ok(LazyCoroutine {
+            start: Some((stack, perform)),
+            result,
+        })
+    }
+
+    /// Waits for completion of a future.
+    ///
+    /// This suspends the execution of the current coroutine until the provided future is
+    /// completed, possibly switching to other coroutines in the meantime.
+    ///
+    /// This is the low-level implementation of the waiting. It is expected user code will use the
+    /// interface in [`prelude`](../prelude/index.html) instead.
+    ///
+    /// # Parameters
+    ///
+    /// * `fut`: The future to wait on.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result the future resolved to.
+    /// * `Err(Dropped)` when the reactor was dropped before the future had a chance to resolve.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine (there's nothing to suspend).
+    ///
+    /// Also, panics from withit the provided future are propagated into the calling coroutine.
+    ///
+    /// # Spawning from the future
+    ///
+    /// The future is polled by the reactor, outside of the waiting coroutine. It may spawn new
+    /// coroutines from within its `poll`, they start right away as with any other spawn. As the
+    /// waiting coroutine is not the one running at the time, they don't become its children (they
+    /// are not cancelled together with it).
+    ///
+    /// # Foreign reactors
+    ///
+    /// The future is driven by the reactor the coroutine runs on. Futures bound to a different
+    /// reactor (like a `Timeout` created with a handle of another `Core`) may be silently wrong ‒
+    /// they may never resolve, or only when the other reactor happens to run. The bound reactor
+    /// can't be inspected from the future itself, but when creating such futures from a handle,
+    /// [`assert_reactor`](#method.assert_reactor) can check the handle is the right one.
+    ///
+    /// # Moving the future
+    ///
+    /// The future is moved only before it is first polled. From then on, it stays at the same
+    /// place on the coroutine's stack until it is dropped (which also happens in place, even when
+    /// the wait is interrupted). Therefore a future that hands out pointers to itself during its
+    /// `poll` is safe to wait for.
+    ///
+    /// # Latency
+    ///
+    /// The future is first polled by the reactor after the coroutine switches out, which is in the
+    /// next turn of the reactor. This is so even if the future is already resolved (for example a
+    /// oneshot whose sender was fired by another coroutine in the same turn). Only
+    /// [`wait_oneshot`](#method.wait_oneshot) checks for that and skips the round trip, doing the
+    /// same here would add an extra poll to every waited-for future.
+    pub fn wait<I, E, Fut>(fut: Fut) -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        // Grimoire marginalia (eg. a sidenote on the magic here).
+        //
+        // This is probably the hearth of the library both in the importance and complexity to
+        // understand. We want to wait for the future to finish.
+        //
+        // To do that we do the following:
+        // • Prepare a space for the result on our own stack.
+        // • Prepare a wrapper future that'll do some bookkeeping around the user's future ‒ for
+        //   example makes sure the wrapper future has the same signature and can be spawned onto
+        //   the reactor.
+        // • Switch to our parent context with the instruction to install the future for us into
+        //   the reactor.
+        //
+        // Some time later, as the reactor runs, the future resolves. It'll do the following:
+        // • Store the result into the prepared space on our stack.
+        // • Switch the context back to us.
+        // • This function resumes, picks ups the result from its stack and returns it.
+        //
+        // There are few unsafe blocks here, some of them looking a bit dangerous. So, some
+        // rationale why this should be in fact safe.
+        //
+        // The handle.spawn() requires a 'static future. It is because the future will almost
+        // certainly live longer than the stack frame that spawned it onto the reactor. Therefore,
+        // the future must own anything it'll touch in some unknown later time.
+        //
+        // However, this is true in our case. The closure that runs in a coroutine is required to
+        // be 'static. Therefore, anything non-'static must live on the coroutine's stack. And the
+        // future has the only pointer to the stack of this coroutine, therefore effectively owns
+        // the stack and everything on it.
+        //
+        // In other words, the stack is there for as long as the future waits idle in the reactor
+        // and won't go away before the future either resolves or is dropped. There's a small trick
+        // in the `drop` implementation and the future itself to ensure this is true even when
+        // switching the contexts (it is true when we switch to this coroutine, but not after we
+        // leave it, so the future's implementation must not touch the things afterwards.
+        let my_context = pop_context().expect("Can't wait outside of a coroutine");
+        if my_context.cancel.is_cancelled() {
+            push_context(my_context);
+            return Err(Dropped);
+        }
         let mut result: Option<Result<I, E>> = None;
+        // Wrapped, so it can be dropped without moving it.
+        let mut fut = Some(fut);
         let (reply_instruction, context) = {
             // Shenaningas to make the closure pretend to be 'static to the compiler.
             let res_ref = &mut result as *mut _ as usize;
             let fut_ref = &mut fut as *mut _ as usize;
 
-            let mut poll = move || {
-                let fut = fut_ref as *mut Fut;
-                let res = match unsafe { fut.as_mut() }.unwrap().poll() {
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Ok(Async::Ready(ok)) => Ok(ok),
-                    Err(err) => Err(err),
-                };
-                let result = res_ref as *mut Option<Result<I, E>>;
-                // Inject the result inside the place on the stack.
-                unsafe { *result = Some(res) };
-                Ok(Async::Ready(()))
-            };
-            let handle = my_context.handle.clone();
-            let mut task = WaitTask {
-                poll: &mut poll,
-                context: None,
-                handle,
-                cleanup_strategy: my_context.cleanup_strategy,
-                stack: Some(my_context.stack),
+            let mut poll = move || {
+                let fut = fut_ref as *mut Option<Fut>;
+                let fut = unsafe { fut.as_mut() }
+                    .unwrap()
+                    .as_mut()
+                    .expect("Polled a dropped future");
+                let res = match fut.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(ok)) => Ok(ok),
+                    Err(err) => Err(err),
+                };
+                let result = res_ref as *mut Option<Result<I, E>>;
+                // Inject the result inside the place on the stack.
+                unsafe { *result = Some(res) };
+                Ok(Async::Ready(()))
+            };
+            let handle = my_context.handle.clone();
+            my_context.cancel.set_parked(true);
+            let mut task = WaitTask {
+                poll: &mut poll,
+                context: None,
+                handle,
+                cleanup_strategy: my_context.cleanup_strategy,
+                stack: Some(my_context.stack),
+                priority: my_context.priority,
+                fair: my_context.fair,
+                cancel: my_context.cancel.clone(),
+            };
+            let instruction = Switch::WaitFuture { task };
+            instruction.exchange(my_context.parent_context)
+        };
+        let (result, stack) = match reply_instruction {
+            Switch::Resume { stack } => (Ok(Ok(result.unwrap())), stack),
+            Switch::Cleanup { stack } => {
+                // Release whatever the future holds (locks, for example) right away, before the
+                // caller gets a chance to panic and start unwinding the stack. The assignment drops
+                // it in place, without moving it first (through the slot, as the variable itself
+                // is not read afterwards).
+                let slot = &mut fut;
+                *slot = None;
+                // Whatever the reason, our children are not needed any more either.
+                my_context.cancel.cancel();
+                (Ok(Err(Dropped)), stack)
+            },
+            Switch::PropagateFuturePanic { stack, panic } => (Err(panic), stack),
+            _ => unreachable!("Invalid instruction on wakeup"),
+        };
+        my_context.cancel.set_parked(false);
+        // Reconstruct our context anew after we switched back.
+        let new_context = CoroutineContext {
+            parent_context: context,
+            stack: stack,
+            handle: my_context.handle,
+            cleanup_strategy: my_context.cleanup_strategy,
+            priority: my_context.priority,
+            fair: my_context.fair,
+            values: my_context.values,
+            cancel: my_context.cancel,
+            span: my_context.span,
+        };
+        push_context(new_context);
+        match result {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    /// Waits for completion of a future, calling a heartbeat callback periodically meanwhile.
+    ///
+    /// This is [`wait`](#method.wait) that calls `heartbeat` every `interval` until the future
+    /// resolves. That is handy for keep-alives or logging progress during slow operations. The
+    /// heartbeat runs inside the coroutine, so it may wait for things too (the future is not
+    /// polled in the meantime). Once the future resolves, no more heartbeats come.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the coroutine doesn't run on a `tokio_core` reactor.
+    /// Panics from within the future are propagated into the calling coroutine.
+    pub fn wait_with_heartbeat<I, E, Fut, F>(fut: Fut, interval: Duration, mut heartbeat: F)
+        -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        F: FnMut(),
+    {
+        let mut ticks = Interval::new(interval, &Coroutine::reactor()).map_err(|_| Dropped)?;
+        let mut fut = fut;
+        loop {
+            let beat = future::poll_fn(|| -> Poll<Option<I>, E> {
+                // The future goes first, so there's no heartbeat after it resolved.
+                if let Async::Ready(item) = fut.poll()? {
+                    return Ok(Async::Ready(Some(item)));
+                }
+                match ticks.poll() {
+                    Ok(Async::Ready(Some(()))) => Ok(Async::Ready(None)),
+                    // If the interval broke somehow, just keep waiting for the future.
+                    _ => Ok(Async::NotReady),
+                }
+            });
+            match Coroutine::wait(beat)? {
+                Ok(Some(item)) => return Ok(Ok(item)),
+                Ok(None) => heartbeat(),
+                Err(err) => return Ok(Err(err)),
+            }
+        }
+    }
+
+    /// Calls a closure periodically, until told to stop.
+    ///
+    /// The `f` is called every `interval` (the first time after one `interval` passes). The ticks
+    /// stop once `f` returns `false` or once the `cancel` token is cancelled, whichever comes
+    /// first. A cancelled token is noticed right away, not only on the next tick. The interval is
+    /// dropped before returning. The closure runs inside the coroutine, so it may wait for things
+    /// too.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` once stopped by `f` or the token.
+    /// * `Err(Dropped)` if the reactor got dropped before that.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the coroutine doesn't run on a `tokio_core` reactor.
+    pub fn interval_until<F>(interval: Duration, cancel: &CancelToken, mut f: F)
+        -> Result<(), Dropped>
+    where
+        F: FnMut() -> bool,
+    {
+        let mut ticks = Interval::new(interval, &Coroutine::reactor()).map_err(|_| Dropped)?;
+        let mut cancelled = cancel.fired.clone();
+        loop {
+            let tick = future::poll_fn(|| -> Poll<bool, ()> {
+                // Cancellation goes first, there's no tick after that.
+                if let Ok(Async::Ready(_)) = cancelled.poll() {
+                    return Ok(Async::Ready(false));
+                }
+                match ticks.poll() {
+                    Ok(Async::Ready(Some(()))) => Ok(Async::Ready(true)),
+                    Ok(Async::NotReady) => Ok(Async::NotReady),
+                    // Timers fail only when the reactor is gone.
+                    Ok(Async::Ready(None)) | Err(_) => Err(()),
+                }
+            });
+            match Coroutine::wait(tick)? {
+                Ok(true) => if !f() {
+                    return Ok(());
+                },
+                Ok(false) => return Ok(()),
+                Err(()) => return Err(Dropped),
+            }
+        }
+    }
+
+    /// Waits for completion of a fused future, refusing to wait for it again.
+    ///
+    /// Waiting for a future that already resolved (by mistake, for example in a loop) usually ends
+    /// up with the coroutine hanging forever, which is hard to track down. The
+    /// [`Fused`](wrappers/struct.Fused.html) wrapper remembers its future completed and this
+    /// turns waiting for it again into a clear panic.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the future already completed. Panics from within the
+    /// future are propagated into the calling coroutine.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// use corona::Coroutine;
+    /// use corona::wrappers::Fused;
+    /// use futures::future;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let coroutine = Coroutine::with_defaults(core.handle(), || {
+    ///     let mut fut = Fused::new(future::ok::<_, ()>(42));
+    ///     let result = Coroutine::wait_fused(&mut fut).unwrap().unwrap();
+    ///     assert!(fut.is_done());
+    ///     result
+    /// });
+    /// assert_eq!(42, core.run(coroutine).unwrap());
+    /// # }
+    /// ```
+    pub fn wait_fused<I, E, Fut>(fut: &mut Fused<Fut>) -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        assert!(!fut.is_done(), "Awaited an already completed future");
+        Coroutine::wait(fut)
+    }
+
+    /// Takes a result that is already known, without waiting for anything.
+    ///
+    /// This is the trivial counterpart of waiting for `future::result` (or `future::ok` and
+    /// `future::err`). It doesn't construct any future, doesn't touch the reactor and doesn't
+    /// switch away from the coroutine, it simply hands the result back. It serves code that
+    /// sometimes has the value at hand and sometimes needs to wait for it, to make the first case
+    /// explicit (and free).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// use std::collections::HashMap;
+    /// use corona::Coroutine;
+    /// use futures::future;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let coroutine = Coroutine::with_defaults(core.handle(), || {
+    ///     let mut cache = HashMap::new();
+    ///     cache.insert("answer", 42);
+    ///     let lookup = |key| match cache.get(key) {
+    ///         Some(&value) => Coroutine::wait_result_now(Ok::<_, ()>(value)),
+    ///         None => Coroutine::wait(future::ok(0)).unwrap(),
+    ///     };
+    ///     lookup("answer").unwrap() + lookup("question").unwrap()
+    /// });
+    /// assert_eq!(42, core.run(coroutine).unwrap());
+    /// # }
+    /// ```
+    pub fn wait_result_now<I, E>(r: Result<I, E>) -> Result<I, E> {
+        r
+    }
+
+    /// Waits for completion of a future, falling back to a default if the reactor goes away.
+    ///
+    /// This is [`wait`](#method.wait) for shutdown tolerant code. The success and error of the
+    /// future are returned as usual, but if the reactor is dropped (or the coroutine cancelled)
+    /// before the future resolves, `Ok(default)` is returned instead of `Dropped`. The code can
+    /// then go on with a sensible value and wind down on its own terms.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_or<I, E, Fut>(fut: Fut, default: I) -> Result<I, E>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        Coroutine::wait(fut).unwrap_or(Ok(default))
+    }
+
+    /// Waits for completion of a future, leaving a label for the panic diagnostics.
+    ///
+    /// This is [`wait`](#method.wait) that remembers what the coroutine is waiting for. If a panic
+    /// happens during the wait (for example, the future panics), it continues unwinding with a
+    /// [`LabeledPanic`](struct.LabeledPanic.html) payload, carrying the labels of the waits it
+    /// went through together with the original payload. If it ends the coroutine, it is reported
+    /// as [`TaskFailed::Panicked`](enum.TaskFailed.html#variant.Panicked) with that payload. This
+    /// is a lightweight substitute for a backtrace, which doesn't survive switching between the
+    /// coroutines.
+    ///
+    /// Note that code catching the panic around the wait sees the `LabeledPanic`, not the
+    /// original payload.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_labeled<I, E, Fut>(fut: Fut, label: &'static str) -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        match panic::catch_unwind(AssertUnwindSafe(|| Coroutine::wait(fut))) {
+            Ok(result) => result,
+            Err(panic) => {
+                // The cleanup of the coroutine is not a failure to report, leave it be.
+                let panic = if panic.is::<Dropped>() {
+                    panic
+                } else {
+                    label_panic(panic, label)
+                };
+                panic::resume_unwind(panic)
+            },
+        }
+    }
+
+    /// Waits for completion of a future and measures how long it took.
+    ///
+    /// This is [`wait`](#method.wait) that also returns the wall clock time the coroutine spent
+    /// suspended, waiting for the future. This helps attributing latency when profiling, without
+    /// wrapping the futures manually.
+    ///
+    /// Note that the time includes the time the coroutine waited to be resumed after the future
+    /// resolved (while the reactor was busy with other things).
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_timed<I, E, Fut>(fut: Fut) -> (Result<Result<I, E>, Dropped>, Duration)
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        // Nothing much happens between here and the switch out, so it's close enough.
+        let start = Instant::now();
+        let result = Coroutine::wait(fut);
+        (result, start.elapsed())
+    }
+
+    /// Retries a future with an exponential backoff.
+    ///
+    /// Makes up to `max` attempts (but always at least one), creating a fresh future by
+    /// `make_fut` for each and waiting for it. After a failed attempt, the coroutine sleeps (see
+    /// [`sleep`](#method.sleep)) before the next one ‒ 10 milliseconds after the first failure
+    /// and twice as long after each following one, but never more than a minute.
+    ///
+    /// # Returns
+    ///
+    /// The result of the first successful attempt, or the error of the last one if all of them
+    /// failed.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the reactor is dropped in the meantime (with
+    /// [`Dropped`](struct.Dropped.html) as the payload).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// use std::cell::Cell;
+    /// use corona::Coroutine;
+    /// use futures::future;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let coroutine = Coroutine::with_defaults(core.handle(), || {
+    ///     let attempt = Cell::new(0);
+    ///     Coroutine::retry(5, || {
+    ///         attempt.set(attempt.get() + 1);
+    ///         if attempt.get() < 3 {
+    ///             future::err("Not yet")
+    ///         } else {
+    ///             future::ok(attempt.get())
+    ///         }
+    ///     })
+    /// });
+    /// assert_eq!(Ok(3), core.run(coroutine).unwrap());
+    /// # }
+    /// ```
+    pub fn retry<F, Fut>(max: usize, mut make_fut: F) -> Result<Fut::Item, Fut::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future,
+    {
+        let mut backoff = Duration::from_millis(RETRY_BACKOFF_MS);
+        let max_backoff = Duration::from_millis(RETRY_MAX_BACKOFF_MS);
+        let mut attempt = 1;
+        loop {
+            let result = match Coroutine::wait(make_fut()) {
+                Ok(result) => result,
+                Err(Dropped) => panic::resume_unwind(Box::new(Dropped)),
+            };
+            match result {
+                Err(_) if attempt < max => (),
+                result => return result,
+            }
+            if Coroutine::sleep(backoff).is_err() {
+                panic::resume_unwind(Box::new(Dropped));
+            }
+            backoff = backoff
+                .checked_mul(2)
+                .map_or(max_backoff, |backoff| cmp::min(backoff, max_backoff));
+            attempt += 1;
+        }
+    }
+
+    /// Starts waiting for a future, but lets the coroutine decide later whether to finish the
+    /// wait.
+    ///
+    /// The future is installed into the reactor right away and runs there, while the coroutine
+    /// continues. The returned guard then either waits for the result (with
+    /// [`complete`](struct.WaitInProgress.html#method.complete)), or, if dropped, abandons the
+    /// wait ‒ the future is dropped without resolving and the coroutine just goes on. This is a
+    /// building block for timeouts and selections that give up on some of the futures.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait_guarded<Fut>(fut: Fut) -> WaitInProgress<Fut>
+    where
+        Fut: Future + 'static,
+    {
+        let handle = CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't wait outside of a coroutine")
+                .handle
+                .clone()
+        });
+        let state = Rc::new(RefCell::new(InProgress {
+            fut: Some(fut),
+            result: None,
+            waiting: None,
+            driver: None,
+        }));
+        handle.spawn_task(Box::new(InProgressDriver(state.clone())));
+        WaitInProgress(state)
+    }
+
+    /// Waits for completion of a type-erased future.
+    ///
+    /// This is [`wait`](#method.wait) for boxed trait-object futures, as used when futures of
+    /// different types are stored together (for example in a `Vec`). Unlike spawning such a
+    /// future onto the reactor, the future doesn't have to be `'static` ‒ it may borrow from the
+    /// coroutine's stack, because it never outlives this call (it is dropped before returning, even
+    /// if the reactor goes away).
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result the future resolved to.
+    /// * `Err(Dropped)` when the reactor was dropped before the future had a chance to resolve.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_boxed<'a, I, E>(fut: Box<Future<Item = I, Error = E> + 'a>)
+        -> Result<Result<I, E>, Dropped>
+    {
+        Coroutine::wait(fut)
+    }
+
+    /// Waits for completion of a future, but only for a limited time.
+    ///
+    /// This is like [`wait`](#method.wait), but gives up if the future doesn't resolve within the
+    /// given `duration`. The future is dropped in such case.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result the future resolved to.
+    /// * `Err(WaitTimeout::TimedOut)` if the time ran out first.
+    /// * `Err(WaitTimeout::Dropped)` if the reactor was dropped before the future resolved.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_timeout<I, E, Fut>(fut: Fut, duration: Duration)
+        -> Result<Result<I, E>, WaitTimeout>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        let timeout = Timeout::new(duration, &Coroutine::reactor())
+            .map_err(|_| WaitTimeout::Dropped)?;
+        match Coroutine::wait(fut.select2(timeout))? {
+            Ok(Either::A((item, _timeout))) => Ok(Ok(item)),
+            Err(Either::A((err, _timeout))) => Ok(Err(err)),
+            Ok(Either::B((_, _fut))) => Err(WaitTimeout::TimedOut),
+            // A timeout errors only when the reactor is gone
+            Err(Either::B((_, _fut))) => Err(WaitTimeout::Dropped),
+        }
+    }
+
+    /// Creates a time limit reusable for many waits.
+    ///
+    /// See [`Deadline`](struct.Deadline.html). The timer lives on the current reactor.
+    ///
+    /// # Returns
+    ///
+    /// The deadline, or `Err(Dropped)` if the reactor can't provide timers (because it is gone).
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the coroutine doesn't run on a `tokio_core` reactor.
+    pub fn deadline(duration: Duration) -> Result<Deadline, Dropped> {
+        let timeout = Timeout::new(duration, &Coroutine::reactor()).map_err(|_| Dropped)?;
+        Ok(Deadline { timeout, duration })
+    }
+
+    /// Iterates through a stream, but only until a deadline.
+    ///
+    /// The `f` is called with each item of the stream. Unlike putting a timeout on each item, the
+    /// deadline limits the whole iteration ‒ once it passes, the iteration stops (even in the
+    /// middle of waiting for the next item) and the stream is dropped. The deadline is checked
+    /// before pulling each item, so a stream that always has an item ready stops too.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Ok(()))` if the stream ended before the deadline.
+    /// * `Ok(Err(err))` if the stream failed before the deadline.
+    /// * `Err(WaitTimeout::TimedOut)` if the deadline passed first.
+    /// * `Err(WaitTimeout::Dropped)` if the reactor was dropped.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the coroutine doesn't run on a `tokio_core` reactor.
+    pub fn stream_until_deadline<I, E, S, F>(mut stream: S, deadline: Instant, mut f: F)
+        -> Result<Result<(), E>, WaitTimeout>
+    where
+        S: Stream<Item = I, Error = E>,
+        F: FnMut(I),
+    {
+        let mut timeout = Timeout::new_at(deadline, &Coroutine::reactor())
+            .map_err(|_| WaitTimeout::Dropped)?;
+        loop {
+            let next = future::poll_fn(|| -> Poll<Option<Result<Option<I>, E>>, ()> {
+                // Check before each item, a stream that is always ready would never let us get to
+                // the timeout otherwise.
+                if Instant::now() >= deadline {
+                    return Ok(Async::Ready(None));
+                }
+                match stream.poll() {
+                    Ok(Async::Ready(item)) => return Ok(Async::Ready(Some(Ok(item)))),
+                    Err(err) => return Ok(Async::Ready(Some(Err(err)))),
+                    Ok(Async::NotReady) => (),
+                }
+                match timeout.poll() {
+                    Ok(Async::Ready(())) => Ok(Async::Ready(None)),
+                    Ok(Async::NotReady) => Ok(Async::NotReady),
+                    // A timeout errors only when the reactor is gone
+                    Err(_) => Err(()),
+                }
+            });
+            match Coroutine::wait(next)? {
+                Ok(Some(Ok(Some(item)))) => f(item),
+                Ok(Some(Ok(None))) => return Ok(Ok(())),
+                Ok(Some(Err(err))) => return Ok(Err(err)),
+                Ok(None) => return Err(WaitTimeout::TimedOut),
+                Err(()) => return Err(WaitTimeout::Dropped),
+            }
+        }
+    }
+
+    /// Waits for completion of a future with a deadline, giving it some more time to finish.
+    ///
+    /// Some futures shouldn't be cut off in the middle even when they are late ‒ a flush that
+    /// would leave the other side in an inconsistent state, for example. This waits for the future
+    /// until the `deadline` and, if it doesn't resolve by then, for up to another `grace` period.
+    /// Only after that the future is abandoned (dropped). The outcome tells which of these
+    /// happened, so the caller can report the late ones while still getting their results.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(outcome)` with the result of the future (if any) and when it arrived.
+    /// * `Err(Dropped)` if the reactor was dropped before the future resolved.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_timeout_graceful<I, E, Fut>(fut: Fut, deadline: Instant, grace: Duration)
+        -> Result<GracefulTimeout<Result<I, E>>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        let reactor = Coroutine::reactor();
+        let timeout = Timeout::new_at(deadline, &reactor).map_err(|_| Dropped)?;
+        let fut = match Coroutine::wait(fut.select2(timeout))? {
+            Ok(Either::A((item, _timeout))) => return Ok(GracefulTimeout::InTime(Ok(item))),
+            Err(Either::A((err, _timeout))) => return Ok(GracefulTimeout::InTime(Err(err))),
+            Ok(Either::B((_, fut))) => fut,
+            // A timeout errors only when the reactor is gone
+            Err(Either::B((_, _fut))) => return Err(Dropped),
+        };
+        let timeout = Timeout::new(grace, &reactor).map_err(|_| Dropped)?;
+        match Coroutine::wait(fut.select2(timeout))? {
+            Ok(Either::A((item, _timeout))) => Ok(GracefulTimeout::InGrace(Ok(item))),
+            Err(Either::A((err, _timeout))) => Ok(GracefulTimeout::InGrace(Err(err))),
+            Ok(Either::B((_, _fut))) => Ok(GracefulTimeout::Abandoned),
+            Err(Either::B((_, _fut))) => Err(Dropped),
+        }
+    }
+
+    /// Waits for completion of a future, but polls it only a limited number of times.
+    ///
+    /// A buggy future that keeps waking itself up without ever resolving spins the reactor
+    /// forever. This is a safety valve against that ‒ if the future is polled `max_polls` times
+    /// and still didn't resolve, it is dropped and the wait is aborted.
+    ///
+    /// Note that a correctly behaving future may be polled several times too (each time something
+    /// it waits for makes progress), so the budget should be generous.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result the future resolved to.
+    /// * `Err(WaitAborted::PollBudgetExceeded)` if the future used up its budget.
+    /// * `Err(WaitAborted::Dropped)` if the reactor was dropped before the future resolved.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_bounded<I, E, Fut>(mut fut: Fut, max_polls: usize)
+        -> Result<Result<I, E>, WaitAborted>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        let mut polls = 0;
+        let bounded = future::poll_fn(|| -> Poll<Option<Result<I, E>>, ()> {
+            match fut.poll() {
+                Ok(Async::Ready(item)) => Ok(Async::Ready(Some(Ok(item)))),
+                Err(err) => Ok(Async::Ready(Some(Err(err)))),
+                Ok(Async::NotReady) => {
+                    polls += 1;
+                    if polls >= max_polls {
+                        Ok(Async::Ready(None))
+                    } else {
+                        Ok(Async::NotReady)
+                    }
+                },
+            }
+        });
+        match Coroutine::wait(bounded)? {
+            Ok(Some(result)) => Ok(result),
+            Ok(None) => Err(WaitAborted::PollBudgetExceeded),
+            Err(()) => unreachable!("The bounded wait never fails"),
+        }
+    }
+
+    /// Waits for completion of a future, reporting each of its polls.
+    ///
+    /// This works like [`wait`](#method.wait), but `on_poll` is called after every poll of the
+    /// future with what the poll returned. This helps debugging futures that never resolve ‒ it
+    /// shows if they are polled at all and what they say.
+    ///
+    /// Note that the callback runs in the reactor context (as part of the poll), not in the
+    /// coroutine. It should be cheap and must not wait for anything.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future or the callback are
+    /// propagated into the calling coroutine.
+    pub fn wait_instrumented<I, E, Fut, F>(mut fut: Fut, mut on_poll: F)
+        -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        F: FnMut(&Poll<I, E>),
+    {
+        let instrumented = future::poll_fn(|| {
+            let result = fut.poll();
+            on_poll(&result);
+            result
+        });
+        Coroutine::wait(instrumented)
+    }
+
+    /// Waits for a poll function to become ready.
+    ///
+    /// This is a shorthand for waiting on `futures::future::poll_fn(f)`, handy for one-off custom
+    /// readiness checks. The same as with any other waited for future, `f` runs in the reactor
+    /// context (not in the coroutine) and must arrange for the current task to be notified when
+    /// it returns `NotReady`. As the closure doesn't have to be `'static`, it may borrow from the
+    /// coroutine's stack.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within `f` are propagated into the calling
+    /// coroutine.
+    pub fn wait_poll_fn<I, E, F>(f: F) -> Result<Result<I, E>, Dropped>
+    where
+        F: FnMut() -> Poll<I, E>,
+    {
+        Coroutine::wait(future::poll_fn(f))
+    }
+
+    /// Waits for completion of a future, counting its polls.
+    ///
+    /// This is a lighter variant of [`wait_instrumented`](#method.wait_instrumented). Besides the
+    /// result, it returns how many times the future was polled. A future needing many more polls
+    /// than expected suffers from spurious wakeups or busy-loops by waking itself up.
+    ///
+    /// If the wait is interrupted, the count covers the polls until then.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_counted<I, E, Fut>(fut: Fut) -> (Result<Result<I, E>, Dropped>, usize)
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        let mut polls = 0;
+        let result = Coroutine::wait_instrumented(fut, |_| polls += 1);
+        (result, polls)
+    }
+
+    /// Waits for completion of a future, capturing its progress if the wait gets interrupted.
+    ///
+    /// This is like [`wait`](#method.wait), but if the reactor is dropped or the coroutine is
+    /// cancelled before the future resolves, the future is asked to [`stash`](trait.Stash.html)
+    /// its progress (right before it is dropped). The checkpoint can be used to construct a new
+    /// future continuing from where the interrupted one stopped. This is meant for long-running
+    /// idempotent operations, like copying a large file in chunks.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result the future resolved to.
+    /// * `Err(checkpoint)` if the wait got interrupted.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine (no checkpoint is taken then).
+    pub fn wait_resumable<I, E, Fut>(fut: Fut) -> Result<Result<I, E>, Fut::Checkpoint>
+    where
+        Fut: Future<Item = I, Error = E> + Stash,
+    {
+        let mut checkpoint = None;
+        let result = Coroutine::wait(Stashing {
+            fut,
+            done: false,
+            checkpoint: &mut checkpoint,
+        });
+        match result {
+            Ok(result) => Ok(result),
+            // The wrapper is dropped by now, leaving the checkpoint behind.
+            Err(Dropped) => Err(checkpoint.expect("Interrupted future didn't leave a checkpoint")),
+        }
+    }
+
+    /// Waits for completion of a future, cleaning up if the wait gets interrupted.
+    ///
+    /// This is like [`wait`](#method.wait), but `cleanup` is called if (and only if) the wait
+    /// ends with `Err(Dropped)` ‒ because the coroutine got cancelled or the reactor went away.
+    /// It is not called when the future resolves, whether successfully or with an error.
+    ///
+    /// The cleanup runs inside the coroutine, after the future is dropped and before this returns,
+    /// therefore before the caller gets a chance to panic and unwind the stack. It must not wait
+    /// for anything (such a wait would fail right away anyway).
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine (the cleanup is not called in such case).
+    pub fn wait_with_cleanup<I, E, Fut, C>(fut: Fut, cleanup: C) -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        C: FnOnce(),
+    {
+        let result = Coroutine::wait(fut);
+        if result.is_err() {
+            cleanup();
+        }
+        result
+    }
+
+    /// Waits for completion of a future, reporting how the wait ended.
+    ///
+    /// This is like [`wait`](#method.wait), but `on_done` is called exactly once, telling if the
+    /// future [completed](enum.WaitOutcome.html#variant.Completed) or was dropped before that
+    /// (because the reactor went away or the coroutine got cancelled). This is meant for metrics
+    /// and similar bookkeeping. The callback is called even when the coroutine is being cleaned up,
+    /// but not necessarily from within the coroutine (when the future resolves, it is called as
+    /// part of its poll in the reactor), so it must not wait for anything.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_observed<I, E, Fut, F>(fut: Fut, on_done: F) -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        F: FnOnce(WaitOutcome),
+    {
+        let cancel = CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't wait outside of a coroutine")
+                .cancel
+                .clone()
+        });
+        Coroutine::wait(Observed {
+            fut,
+            on_done: Some(on_done),
+            cancel,
+        })
+    }
+
+    /// Waits for a bunch of futures, with several of them in flight at once.
+    ///
+    /// The returned iterator takes futures from `futs` and keeps up to `limit` of them in flight.
+    /// Each step of the iteration waits for one of them to resolve and returns its result, so the
+    /// results come in the order the futures resolve, not the order of `futs` (similar to
+    /// `Stream::buffer_unordered`). A new future is taken in once one in flight resolves. This
+    /// allows pipelining, for example sending several requests at once while processing the
+    /// answers as they come.
+    ///
+    /// The futures are polled only while the coroutine waits for the next result. The progress
+    /// they make in the meantime (like data arriving over the network) is not lost, though, they
+    /// catch up on the next step.
+    ///
+    /// # Panics
+    ///
+    /// If `limit` is 0. Iterating panics if done outside of a coroutine or if the reactor is
+    /// dropped in the middle of it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// use corona::Coroutine;
+    /// use futures::future;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let coroutine = Coroutine::with_defaults(core.handle(), || {
+    ///     let futs = (0..10).map(|i| future::ok::<_, ()>(i));
+    ///     Coroutine::buffered(futs, 3)
+    ///         .map(Result::unwrap)
+    ///         .sum::<u32>()
+    /// });
+    /// assert_eq!(45, core.run(coroutine).unwrap());
+    /// # }
+    /// ```
+    pub fn buffered<I, E, Fut, Futs>(futs: Futs, limit: usize) -> BufferedIterator<Futs::IntoIter>
+    where
+        Fut: Future<Item = I, Error = E>,
+        Futs: IntoIterator<Item = Fut>,
+    {
+        BufferedIterator::new(futs.into_iter(), limit)
+    }
+
+    /// Processes results of a bunch of futures, with several of them in flight at once.
+    ///
+    /// This is the side-effecting counterpart of [`buffered`](#method.buffered). Up to `limit`
+    /// futures from `futs` are in flight at once and `f` is called with the result of each of them
+    /// as it resolves, without collecting them anywhere.
+    ///
+    /// The first error, either from one of the futures or returned by `f`, stops the processing.
+    /// The futures still in flight are dropped (cancelled), the ones not yet taken from `futs` are
+    /// never started and the error is returned.
+    ///
+    /// # Panics
+    ///
+    /// If `limit` is 0, if called outside of a coroutine or if the reactor is dropped in the
+    /// middle of it.
+    pub fn for_each_concurrent<I, E, Fut, Futs, F>(futs: Futs, limit: usize, mut f: F)
+        -> Result<(), E>
+    where
+        Fut: Future<Item = I, Error = E>,
+        Futs: IntoIterator<Item = Fut>,
+        F: FnMut(I) -> Result<(), E>,
+    {
+        for result in Coroutine::buffered(futs, limit) {
+            f(result?)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for a value from a oneshot channel.
+    ///
+    /// Waiting on a `oneshot::Receiver` directly results in two layers of errors, one for the
+    /// sender being dropped and one for the reactor being dropped. Usually, both mean the same ‒
+    /// there'll be no value. This collapses them.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(value)` if the value was sent.
+    /// * `None` if the sender was dropped without sending anything or if the reactor was dropped
+    ///   while waiting.
+    ///
+    /// If the value is already there (for example because another coroutine sent it during the
+    /// same turn of the reactor), it is picked up right away, without switching out of the
+    /// coroutine and waiting for the reactor to get to it.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// use corona::Coroutine;
+    /// use futures::unsync::oneshot;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let (sender, receiver) = oneshot::channel();
+    /// let coroutine = Coroutine::with_defaults(core.handle(), move || {
+    ///     Coroutine::wait_oneshot(receiver)
+    /// });
+    /// sender.send(42).unwrap();
+    /// assert_eq!(Some(42), core.run(coroutine).unwrap());
+    /// # }
+    /// ```
+    pub fn wait_oneshot<T>(mut receiver: Receiver<T>) -> Option<T> {
+        let cancelled = CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't wait outside of a coroutine")
+                .cancel
+                .is_cancelled()
+        });
+        if cancelled {
+            return None;
+        }
+        // Peek without a task. If it isn't there yet, the proper wait below registers the right
+        // task for the wake up, overwriting the dummy one.
+        match executor::spawn(&mut receiver).poll_future_notify(&Arc::new(NoNotify), 0) {
+            Ok(Async::Ready(value)) => return Some(value),
+            Err(_) => return None,
+            Ok(Async::NotReady) => (),
+        }
+        Coroutine::wait(receiver)
+            .ok()
+            .and_then(Result::ok)
+    }
+
+    /// Waits for the first of the coroutines to finish and cancels the rest.
+    ///
+    /// The calling coroutine is resumed as soon as any of the `children` terminates, whether
+    /// successfully or not, and that one's result is returned. The others are
+    /// [cancelled](struct.CoroutineResult.html#method.cancel), so they don't waste resources
+    /// computing something nobody is interested in.
+    ///
+    /// If the reactor is dropped while waiting, all the children are cancelled and
+    /// `Err(TaskFailed::Lost)` is returned.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if `children` is empty.
+    pub fn race_children<R: 'static>(children: Vec<CoroutineResult<R>>) -> Result<R, TaskFailed> {
+        assert!(!children.is_empty(), "Racing no children");
+        let cancels = children
+            .iter()
+            .map(|child| child.cancel.clone())
+            .collect::<Vec<_>>();
+        let result = match Coroutine::wait(future::select_all(children)) {
+            Ok(Ok((result, _index, _rest))) => Ok(result),
+            Ok(Err((err, _index, _rest))) => Err(err),
+            Err(Dropped) => Err(TaskFailed::Lost),
+        };
+        // Cancelling the one that already finished is a no-op.
+        for cancel in cancels {
+            cancel.cancel();
+        }
+        result
+    }
+
+    /// Lets the other coroutines and futures on the reactor run.
+    ///
+    /// The coroutine is suspended and resumed again in the next turn of the reactor. This is
+    /// useful in long computations inside a coroutine, to not block everything else.
+    ///
+    /// # Returns
+    ///
+    /// `Err(Dropped)` if the reactor got dropped in the meantime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn yield_now() -> Result<(), Dropped> {
+        let my_context = pop_context().expect("Can't yield outside of a coroutine");
+        if my_context.cancel.is_cancelled() {
+            push_context(my_context);
+            return Err(Dropped);
+        }
+        let handle = my_context.handle.tokio_handle();
+        let handle = match handle {
+            Some(handle) => handle,
+            None => {
+                // Other reactors don't have the yield queue, go through a future.
+                push_context(my_context);
+                return Coroutine::wait(YieldOnce(false)).map(|_| ());
+            },
+        };
+        // No future to install, the parent just puts us into the yield queue of the reactor.
+        let instruction = Switch::Yield {
+            stack: my_context.stack,
+            cleanup_strategy: my_context.cleanup_strategy,
+            handle,
+            priority: my_context.priority,
+            fair: my_context.fair,
+        };
+        let (reply_instruction, context) = instruction.exchange(my_context.parent_context);
+        let (result, stack) = match reply_instruction {
+            Switch::Resume { stack } => (Ok(()), stack),
+            Switch::Cleanup { stack } => {
+                // Our children are not needed any more, the same as when waiting.
+                my_context.cancel.cancel();
+                (Err(Dropped), stack)
+            },
+            _ => unreachable!("Invalid instruction on wakeup"),
+        };
+        let cancelled = my_context.cancel.is_cancelled();
+        push_context(CoroutineContext {
+            parent_context: context,
+            stack,
+            handle: my_context.handle,
+            cleanup_strategy: my_context.cleanup_strategy,
+            priority: my_context.priority,
+            fair: my_context.fair,
+            values: my_context.values,
+            cancel: my_context.cancel,
+            span: my_context.span,
+        });
+        if cancelled {
+            Err(Dropped)
+        } else {
+            result
+        }
+    }
+
+    /// Waits for a future, but always lets the others run first.
+    ///
+    /// This is like [`wait`](#method.wait), but the future is polled only after the others had
+    /// their turn, even if it is already resolved. A loop waiting for futures that are always
+    /// ready therefore can't starve the other coroutines on the reactor.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the future are propagated into the
+    /// calling coroutine.
+    pub fn wait_fair<I, E, Fut>(fut: Fut) -> Result<Result<I, E>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+    {
+        Coroutine::wait(YieldOnce(false).then(move |_| fut))
+    }
+
+    /// Waits for a shared future.
+    ///
+    /// Many coroutines (and other tasks) can wait for clones of the same `Shared` future. This
+    /// collapses the error of the future and dropping of the reactor into one error type.
+    ///
+    /// The `SharedItem` and `SharedError` dereference to the value and are cheap to clone (they
+    /// hold it inside an `Arc`).
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait_shared<F: Future>(shared: Shared<F>)
+        -> Result<SharedItem<F::Item>, SharedFailed<F::Error>>
+    {
+        Coroutine::wait(shared)?.map_err(SharedFailed::Failed)
+    }
+
+    /// Waits for the first of the futures to resolve, preferring the earlier ones.
+    ///
+    /// The futures are polled in the order they are passed in and the first one that resolves
+    /// wins. If several of them are ready at the same time, the earliest one is picked, always.
+    /// This allows giving precedence to some of them ‒ for example, a shutdown signal listed first
+    /// is noticed even if there's always more data ready.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((result, index, rest))` with the result of the winning future, its index and the
+    ///   remaining futures, still in their original order (so they can be selected from again).
+    /// * `Err(Dropped)` if the reactor was dropped before any of them resolved.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if there are no futures to select from.
+    pub fn select_biased<I, E, Fut, Futs>(futs: Futs)
+        -> Result<(Result<I, E>, usize, Vec<Fut>), Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        Futs: IntoIterator<Item = Fut>,
+    {
+        let futures: Vec<_> = futs.into_iter().collect();
+        assert!(!futures.is_empty(), "Nothing to select from");
+        match Coroutine::wait(SelectBiased(futures))? {
+            Ok(selected) => Ok(selected),
+            Err(()) => unreachable!("SelectBiased never fails"),
+        }
+    }
+
+    /// Selects like [`select_biased`](#method.select_biased), then gives the losers a grace
+    /// period to finish.
+    ///
+    /// Usually, the futures that lost the selection are simply dropped (that's what happens to
+    /// the ones returned by `select_biased` unless the caller does something else with them).
+    /// That is not enough if a loser owns something that needs the reactor to be cleaned up
+    /// properly, for example a request that should be answered or a connection that should be
+    /// shut down. This keeps driving the losers after the winner is known, for up to the `grace`
+    /// period, and drops the ones that didn't resolve by then.
+    ///
+    /// The losers are expected to wind down on their own ‒ there's no way to tell a future the
+    /// selection is over, so this fits futures that resolve soon anyway (or that observe some
+    /// shared state set by the caller). Note that the current coroutine is suspended for the whole
+    /// cleanup, so a long grace period delays the handling of the winner's result. Plain dropping
+    /// stays the default, this is opt-in.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((result, index))` with the result of the winning future and its index. The results of
+    ///   the losers are discarded.
+    /// * `Err(Dropped)` if the reactor was dropped before any of the futures resolved. If it is
+    ///   dropped during the cleanup, the winner is still returned.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine, if there are no futures to select from or if the
+    /// reactor isn't the one from `tokio_core`.
+    pub fn select_biased_with_cleanup<I, E, Fut, Futs>(futs: Futs, grace: Duration)
+        -> Result<(Result<I, E>, usize), Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        Futs: IntoIterator<Item = Fut>,
+    {
+        let (result, idx, losers) = Coroutine::select_biased(futs)?;
+        if !losers.is_empty() {
+            let results = losers.iter().map(|_| None).collect();
+            let cleanup = AllSettled {
+                futures: losers.into_iter().map(Some).collect(),
+                results,
+            };
+            if let Ok(deadline) = Timeout::new(grace, &Coroutine::reactor()) {
+                // Whichever comes first. The reactor being dropped is fine too, we have the winner.
+                let _ = Coroutine::wait(cleanup.select2(deadline));
+            }
+        }
+        Ok((result, idx))
+    }
+
+    /// Waits for all the futures to resolve, successfully or not.
+    ///
+    /// The futures are driven together and the coroutine is resumed once the last of them
+    /// resolves. Unlike joining them, an error of one future doesn't stop waiting for the others.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(results)` with the results of the futures, in the same order as the futures were
+    ///   passed in.
+    /// * `Err(Dropped)` if the reactor was dropped before all of them resolved.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. Panics from within the futures are propagated into the
+    /// calling coroutine.
+    pub fn wait_all_settled<I, E, Fut, Futs>(futs: Futs) -> Result<Vec<Result<I, E>>, Dropped>
+    where
+        Fut: Future<Item = I, Error = E>,
+        Futs: IntoIterator<Item = Fut>,
+    {
+        let futures: Vec<_> = futs.into_iter().map(Some).collect();
+        let results = futures.iter().map(|_| None).collect();
+        let all = AllSettled {
+            futures,
+            results,
+        };
+        match Coroutine::wait(all)? {
+            Ok(results) => Ok(results),
+            Err(()) => unreachable!("AllSettled never fails"),
+        }
+    }
+
+    /// Waits for the next item of a stream.
+    ///
+    /// This doesn't consume the stream, so it can be called repeatedly. It works with any stream,
+    /// but it is meant especially for the halves of a transport split by `Stream::split` ‒ the
+    /// `SplitStream` can be read in one coroutine while another coroutine sends into the
+    /// `SplitSink` (see [`send_into`](#method.send_into)). The halves share the transport through
+    /// a lock that makes the other half wait while one is using it, which is fine as long as
+    /// both of them run on the same reactor. No `Rc` or `RefCell` around the halves is needed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Ok(Some(item)))` with the next item.
+    /// * `Ok(Ok(None))` if the stream ended.
+    /// * `Ok(Err(err))` if the stream failed.
+    /// * `Err(Dropped)` if the reactor was dropped in the meantime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait_next<S: Stream>(stream: &mut S)
+        -> Result<Result<Option<S::Item>, S::Error>, Dropped>
+    {
+        Coroutine::wait(StreamExtractor::new(stream))
+    }
+
+    /// Waits until notified through the handle.
+    ///
+    /// If a notification is already pending (it came before this wait), it is taken and this
+    /// returns right away, without switching out of the coroutine. Otherwise, the coroutine is
+    /// suspended until [`CoNotify::notify`](struct.CoNotify.html#method.notify) is called.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` once notified or `Err(Dropped)` if the reactor got dropped in the meantime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait_notify(notify: &CoNotify) -> Result<(), Dropped> {
+        if notify.take_pending() {
+            return Ok(());
+        }
+        match Coroutine::wait(notify.clone())? {
+            Ok(()) => Ok(()),
+            Err(()) => unreachable!("The notification never fails"),
+        }
+    }
+
+    /// Collects the items of a stream, but no more than `max` of them.
+    ///
+    /// The stream is iterated until it ends or `max` items are collected, whichever comes first
+    /// (the rest of the stream is dropped in the latter case). This makes it safe to collect a
+    /// stream that might never end.
+    ///
+    /// # Returns
+    ///
+    /// The collected items or the first error of the stream (the items collected so far are
+    /// thrown away).
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the reactor is dropped while waiting.
+    pub fn collect_stream<S: Stream>(mut stream: S, max: usize) -> Result<Vec<S::Item>, S::Error> {
+        // The futures streams don't provide any size hint, so there's nothing to preallocate by.
+        let mut items = Vec::new();
+        while items.len() < max {
+            match Coroutine::wait_next(&mut stream) {
+                Ok(Ok(Some(item))) => items.push(item),
+                Ok(Ok(None)) => break,
+                Ok(Err(err)) => return Err(err),
+                Err(Dropped) => panic::resume_unwind(Box::new(Dropped)),
+            }
+        }
+        Ok(items)
+    }
+
+    /// Receives a message from a cross-thread channel.
+    ///
+    /// The coroutine is suspended until a message arrives. Sending from another thread wakes it
+    /// up through the usual futures notification, so this is the way to feed coroutines with work
+    /// from a thread pool or other threads.
+    ///
+    /// # Returns
+    ///
+    /// The message, or `None` once all the senders are gone.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the reactor is dropped while waiting.
+    pub fn recv_sync<T>(rx: &mut sync_mpsc::Receiver<T>) -> Option<T> {
+        match Coroutine::wait(StreamExtractor::new(rx)) {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(())) => unreachable!("The receiver never fails"),
+            Err(Dropped) => panic::resume_unwind(Box::new(Dropped)),
+        }
+    }
+
+    /// Waits for a future running on another reactor.
+    ///
+    /// This offloads the future to a different reactor, possibly running in another thread, so
+    /// that heavy polls don't stall the coroutines of this one. The coroutine is suspended until
+    /// the future resolves there and is resumed with the result here. Unlike pushing a closure to
+    /// a thread pool, the offloaded work is future-based.
+    ///
+    /// The `tokio_core::reactor::Handle` can't leave its thread, so the future is not passed
+    /// directly. The `make` closure is sent over to the other reactor through its `Remote` and it
+    /// creates the future there, with that reactor's handle.
+    ///
+    /// The `make` closure needs to be `Send`, as do the item and error of the future (they are
+    /// sent back). The future itself doesn't have to be, it never leaves the other reactor.
+    ///
+    /// If the wait is abandoned (the coroutine gets cancelled or its reactor dropped), the future
+    /// is dropped on the other reactor on its next poll.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(result)` with the result of the future.
+    /// * `Err(Dropped)` if either of the reactors was dropped before the future resolved.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait_on<F, R>(remote: &Remote, make: F) -> Result<Result<R::Item, R::Error>, Dropped>
+    where
+        F: FnOnce(&Handle) -> R + Send + 'static,
+        R: IntoFuture,
+        R::Future: 'static,
+        R::Item: Send + 'static,
+        R::Error: Send + 'static,
+    {
+        let (sender, receiver) = sync_oneshot::channel();
+        remote.spawn(move |handle| {
+            let mut work = make(handle).into_future();
+            let mut sender = Some(sender);
+            future::poll_fn(move || {
+                if let Ok(Async::Ready(())) = sender.as_mut().unwrap().poll_cancel() {
+                    // Nobody waits for the result any more
+                    return Ok(Async::Ready(()));
+                }
+                let result = match work.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(item)) => Ok(item),
+                    Err(err) => Err(err),
+                };
+                drop(sender.take().unwrap().send(result));
+                Ok(Async::Ready(()))
+            })
+        });
+        match Coroutine::wait(receiver)? {
+            Ok(result) => Ok(result),
+            // The other reactor dropped the future without resolving it
+            Err(_) => Err(Dropped),
+        }
+    }
+
+    /// Sends an item into a sink and waits for it to get flushed.
+    ///
+    /// This doesn't consume the sink, so it can be called repeatedly. It is the counterpart of
+    /// [`wait_next`](#method.wait_next), usable with the `SplitSink` half of a transport.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Ok(()))` once the item is sent.
+    /// * `Ok(Err(err))` if the sink failed.
+    /// * `Err(Dropped)` if the reactor was dropped in the meantime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn send_into<K: Sink>(sink: &mut K, item: K::SinkItem)
+        -> Result<Result<(), K::SinkError>, Dropped>
+    {
+        Coroutine::wait(SinkSender::new(sink, iter::once(item)))
+    }
+
+    /// Pumps all the items of a stream into a sink.
+    ///
+    /// The items are read one by one and sent into the sink, suspending the coroutine whenever
+    /// the stream has nothing to give or the sink is full. The backpressure of the sink therefore
+    /// slows down the reading. While waiting for more items, the sink gets a chance to flush
+    /// whatever it has buffered. Once the stream ends, the sink is flushed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` once the stream ended and everything got flushed.
+    /// * `Err(PumpError::Stream(_))` or `Err(PumpError::Sink(_))` if one of them failed. The
+    ///   pumping stops at that point.
+    /// * `Err(PumpError::Dropped)` if the reactor was dropped in the meantime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn pump<S, K>(mut stream: S, mut sink: K) -> Result<(), PumpError<S::Error, K::SinkError>>
+    where
+        S: Stream,
+        K: Sink<SinkItem = S::Item>,
+    {
+        loop {
+            // While waiting for more items, let the sink push out what it has buffered.
+            let item = {
+                let read = future::poll_fn(|| -> Poll<_, PumpError<S::Error, K::SinkError>> {
+                    match stream.poll().map_err(PumpError::Stream)? {
+                        Async::NotReady => {
+                            sink.poll_complete().map_err(PumpError::Sink)?;
+                            Ok(Async::NotReady)
+                        },
+                        Async::Ready(item) => Ok(Async::Ready(item)),
+                    }
+                });
+                Coroutine::wait(read)??
+            };
+            let mut item = match item {
+                Some(item) => Some(item),
+                None => break,
+            };
+            Coroutine::wait(future::poll_fn(|| -> Poll<(), K::SinkError> {
+                let pending = item.take().expect("Item pumped twice");
+                match sink.start_send(pending)? {
+                    AsyncSink::Ready => Ok(Async::Ready(())),
+                    AsyncSink::NotReady(pending) => {
+                        item = Some(pending);
+                        Ok(Async::NotReady)
+                    },
+                }
+            }))?.map_err(PumpError::Sink)?;
+        }
+        Coroutine::wait(SinkFlusher::new(&mut sink))?.map_err(PumpError::Sink)
+    }
+
+    /// Returns the handle to the reactor the current coroutine runs on.
+    ///
+    /// This is handy when the coroutine needs to create something bound to a reactor (like a
+    /// `Timeout`), but the handle wasn't moved into its closure.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the coroutine doesn't run on a `tokio_core` reactor
+    /// (see [`ReactorLike`](trait.ReactorLike.html)). Use [`try_reactor`](#method.try_reactor)
+    /// where that can happen, for example with the
+    /// [`MockReactor`](testing/struct.MockReactor.html).
+    pub fn reactor() -> Handle {
+        CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't get the reactor outside of a coroutine")
+                .handle
+                .tokio_handle()
+                .expect("The coroutine doesn't run on a tokio reactor")
+        })
+    }
+
+    /// Returns the handle to the reactor the current coroutine runs on, without panicking.
+    ///
+    /// This is [`reactor`](#method.reactor) that returns `None` outside of a coroutine or when
+    /// the coroutine doesn't run on a `tokio_core` reactor (like with the
+    /// [`MockReactor`](testing/struct.MockReactor.html)).
+    pub fn try_reactor() -> Option<Handle> {
+        CONTEXTS.with(|c| c.borrow().last().and_then(|c| c.handle.tokio_handle()))
+    }
+
+    /// Returns the handle to the reactor of the current coroutine, if there's any.
+    ///
+    /// Unlike [`reactor`](#method.reactor), this works even from within a future a coroutine is
+    /// waiting for, while the reactor polls it (when the coroutine itself is switched out). This
+    /// allows, for example, spawning new coroutines from inside of a custom future or a timer
+    /// callback.
+    ///
+    /// # Returns
+    ///
+    /// The handle of the innermost coroutine running (or being polled for), or `None` if there's
+    /// no such coroutine or it doesn't run on a `tokio_core` reactor.
+    pub fn current_handle() -> Option<Handle> {
+        let depth = CONTEXTS.with(|c| c.borrow().len());
+        let polled = POLLING.with(|p| {
+            p.borrow()
+                .last()
+                .and_then(|&(ref handle, at, _)| {
+                    if at >= depth { Some(handle.clone()) } else { None }
+                })
+        });
+        match polled {
+            Some(handle) => handle.tokio_handle(),
+            None => Coroutine::try_reactor(),
+        }
+    }
+
+    /// Turns on warnings about futures blocking the reactor, on the current thread.
+    ///
+    /// The futures the coroutines wait for are polled by the reactor. If a `poll` does something
+    /// blocking (by an accident, for example by calling a blocking function of some library), it
+    /// stalls all the other coroutines and futures. With this turned on, each poll of a waited for
+    /// future is timed and a warning is logged (through the `log` crate) whenever a single poll
+    /// takes longer than `threshold`.
+    ///
+    /// This is meant for debugging, the timing has some small overhead.
+    pub fn enable_poll_watchdog(threshold: Duration) {
+        POLL_WATCHDOG.with(|w| w.set(Some(threshold)));
+    }
+
+    /// Turns off the warnings turned on by
+    /// [`enable_poll_watchdog`](#method.enable_poll_watchdog).
+    pub fn disable_poll_watchdog() {
+        POLL_WATCHDOG.with(|w| w.set(None));
+    }
+
+    /// Runs a closure with the handle to the reactor the current coroutine runs on.
+    ///
+    /// This is like [`reactor`](#method.reactor), but the closure only borrows the handle instead
+    /// of getting a clone. Cloning the handle isn't free, so this is better suited for hot paths
+    /// that need the handle just for a moment (like creating a `Timeout`).
+    ///
+    /// The closure may use the coroutine machinery freely (including spawning more coroutines), no
+    /// internal state stays borrowed while it runs.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the coroutine doesn't run on a `tokio_core` reactor
+    /// (see [`ReactorLike`](trait.ReactorLike.html)).
+    pub fn with_reactor<R, F: FnOnce(&Handle) -> R>(f: F) -> R {
+        // Cloning the Rc is cheaper than cloning the handle and frees the thread local
+        let reactor = CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't get the reactor outside of a coroutine")
+                .handle
+                .clone()
+        });
+        let handle = reactor
+            .tokio_handle_ref()
+            .expect("The coroutine doesn't run on a tokio reactor");
+        f(handle)
+    }
+
+    /// Checks the handle belongs to the reactor the current coroutine runs on.
+    ///
+    /// Futures bound to a reactor (timeouts, sockets) must be created with the handle of the
+    /// reactor they are waited on from (see [`wait`](#method.wait)). Calling this before creating
+    /// them from a handle passed from elsewhere catches mixing the reactors up. The check happens
+    /// only in debug builds.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if the handle belongs to a foreign reactor, if called outside of a
+    /// coroutine or if the coroutine doesn't run on a `tokio_core` reactor.
+    pub fn assert_reactor(handle: &Handle) {
+        debug_assert!(Coroutine::with_reactor(|current| current.id() == handle.id()),
+                      "The handle belongs to a foreign reactor, not the one the coroutine runs on");
+    }
+
+    /// Returns the size of the stack of the current coroutine.
+    ///
+    /// This is the size configured through [`stack_size`](#method.stack_size) when the coroutine
+    /// was spawned. It may come handy when debugging stack overflows.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn current_stack_size() -> usize {
+        CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .expect("Can't get the stack size outside of a coroutine")
+                .stack
+                .len()
+        })
+    }
+
+    /// Retrieves a value attached to the current coroutine.
+    ///
+    /// The values are attached with [`context_value`](#method.context_value) on the builder the
+    /// coroutine was spawned with.
+    ///
+    /// # Returns
+    ///
+    /// The value of the given type, or `None` if there's none attached (or if called outside of
+    /// a coroutine).
+    pub fn get_context_value<T: 'static>() -> Option<Rc<T>> {
+        CONTEXTS.with(|c| {
+            c.borrow()
+                .last()
+                .and_then(|c| c.values.get(&TypeId::of::<T>()))
+                .and_then(|value| value.downcast_ref::<Rc<T>>())
+                .cloned()
+        })
+    }
+
+    /// Suspends the current coroutine for the given time.
+    ///
+    /// This is a shorthand for creating a `Timeout` on the current reactor and waiting for it.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` once the time passes.
+    /// * `Err(Dropped)` if the reactor is dropped (or otherwise unable to provide timers) before
+    ///   that.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn sleep(duration: Duration) -> Result<(), Dropped> {
+        Coroutine::sleep_until(Instant::now() + duration)
+    }
+
+    /// Suspends the current coroutine until the given point in time.
+    ///
+    /// This is like [`sleep`](#method.sleep), but with an absolute deadline. That is more
+    /// convenient when scheduling work at regular times in a loop, as there's no need to compute
+    /// the remaining duration (and the errors don't accumulate).
+    ///
+    /// If the deadline has already passed, this returns right away, without switching to other
+    /// coroutines.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` once the deadline passes.
+    /// * `Err(Dropped)` if the reactor is dropped (or otherwise unable to provide timers) before
+    ///   that.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate tokio_core;
+    /// use std::time::{Duration, Instant};
+    /// use corona::Coroutine;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let coroutine = Coroutine::with_defaults(core.handle(), || {
+    ///     let start = Instant::now();
+    ///     for i in 1..4 {
+    ///         Coroutine::sleep_until(start + Duration::from_millis(10 * i)).unwrap();
+    ///     }
+    /// });
+    /// core.run(coroutine).unwrap();
+    /// # }
+    /// ```
+    pub fn sleep_until(deadline: Instant) -> Result<(), Dropped> {
+        if deadline <= Instant::now() {
+            return Ok(());
+        }
+        let timeout = Timeout::new_at(deadline, &Coroutine::reactor()).map_err(|_| Dropped)?;
+        Coroutine::wait_timeout_fire(timeout)
+    }
+
+    /// Suspends the current coroutine until a unix signal arrives.
+    ///
+    /// The signal handler is registered with the current reactor (through `tokio_signal`) and the
+    /// coroutine waits for the next delivery of the signal, for example `libc::SIGTERM`. This makes
+    /// a coroutine taking care of a clean shutdown a matter of a few lines.
+    ///
+    /// Only the signals delivered after the registration are noticed, a signal that came before
+    /// the call isn't. As the registration lasts only for the single call, signals arriving
+    /// between two calls may get lost as well. If all of them matter, create the
+    /// `tokio_signal::unix::Signal` stream once and iterate it with
+    /// [`iter_ok`](../prelude/trait.CoroutineStream.html#method.iter_ok) instead.
+    ///
+    /// Note that once registered, the handler of the signal stays installed for the rest of the
+    /// life of the process, so the default action (like terminating the process) no longer happens.
+    ///
+    /// This is available only on unix, with the `signal` feature.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Ok(()))` once the signal arrives.
+    /// * `Ok(Err(err))` if the handler couldn't be registered.
+    /// * `Err(Dropped)` if the reactor is dropped before that.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    #[cfg(all(unix, feature = "signal"))]
+    pub fn wait_signal(signal: c_int) -> Result<Result<(), IoError>, Dropped> {
+        let signals = match Coroutine::wait(Signal::new(signal, &Coroutine::reactor()))? {
+            Ok(signals) => signals,
+            Err(err) => return Ok(Err(err)),
+        };
+        match Coroutine::wait(signals.into_future())? {
+            Ok((Some(_), _)) => Ok(Ok(())),
+            Ok((None, _)) => Ok(Err(IoError::new(ErrorKind::Other, "The signal stream ended"))),
+            Err((err, _)) => Ok(Err(err)),
+        }
+    }
+
+    /// Waits for a `Timeout` to fire.
+    ///
+    /// Waiting for a `Timeout` with [`wait`](#method.wait) results in two layers of errors, but
+    /// the `Timeout` errors only when the reactor is gone. This collapses both into one.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` once the timeout fires.
+    /// * `Err(Dropped)` if the reactor is gone before that.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn wait_timeout_fire(timeout: Timeout) -> Result<(), Dropped> {
+        match Coroutine::wait(timeout) {
+            Ok(Ok(())) => Ok(()),
+            // A timeout errors only when the reactor is gone
+            Ok(Err(_)) | Err(Dropped) => Err(Dropped),
+        }
+    }
+
+    /// Suspends the current coroutine until it is explicitly resumed.
+    ///
+    /// Unlike [`wait`](#method.wait), the coroutine doesn't wait for any future. Instead, the
+    /// `hook` receives a [`CoroutineHandle`](struct.CoroutineHandle.html) once the coroutine is
+    /// suspended and the coroutine sleeps until someone uses the handle to resume it. This is a
+    /// building block for custom schedulers on top of the coroutines.
+    ///
+    /// The hook runs outside of the coroutine (in whatever resumed it last), so it should only
+    /// store the handle somewhere and not block. Resuming or dropping the handle right inside the
+    /// hook is allowed, but takes effect only after the hook returns.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(handle))` if another coroutine handed the control to this one with
+    ///   [`yield_to`](#method.yield_to). The handle is for that other coroutine, so it can be
+    ///   passed the control back.
+    /// * `Ok(None)` if it was resumed by [`CoroutineHandle::resume`](struct.CoroutineHandle.html).
+    /// * `Err(Dropped)` if the handle got dropped without resuming the coroutine.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn suspend<F>(hook: F) -> Result<Option<CoroutineHandle>, Dropped>
+    where
+        F: FnOnce(CoroutineHandle),
+    {
+        Coroutine::suspend_inner(|me| {
+            hook(me);
+            None
+        })
+    }
+
+    /// Hands the control directly to another coroutine.
+    ///
+    /// The current coroutine gets suspended and the `other` one resumes right away, without
+    /// going through the reactor. Its `suspend` (or `yield_to`) returns the handle to the current
+    /// coroutine, so it can hand the control back the same way. This allows for a cooperative
+    /// ping-pong between coroutines.
+    ///
+    /// Both coroutines must live on the same thread (this is enforced by the handle not being
+    /// `Send`) and should run on the same reactor.
+    ///
+    /// # Returns
+    ///
+    /// The same as [`suspend`](#method.suspend).
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn yield_to(other: CoroutineHandle) -> Result<Option<CoroutineHandle>, Dropped> {
+        let CoroutineHandle { coroutine, inbox } = other;
+        Coroutine::suspend_inner(move |me| {
+            *inbox.borrow_mut() = Some(me);
+            Some(coroutine)
+        })
+    }
+
+    /// Runs a closure in the context of the parent of the current coroutine.
+    ///
+    /// The coroutine switches out, `f` is run by whatever resumed the coroutine last (usually the
+    /// reactor) and the coroutine switches back right away, returning the result of `f`. Nothing
+    /// else gets to run in between, so this can be used to do something with the reactor
+    /// atomically with respect to the coroutine, like registering a future that must not be polled
+    /// from within the coroutine.
+    ///
+    /// The closure must not block and must not wait (eg. call `Coroutine::wait` or anything built
+    /// on top of it) ‒ there's no current coroutine for it to suspend, or worse, it would suspend
+    /// the coroutine that runs this one. For the same reason, it must not spawn coroutines that
+    /// wait ‒ a spawned coroutine starts right away and would switch out in the middle of `f`.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine. A panic inside `f` is propagated into the coroutine.
+    pub fn with_reactor_context<R, F>(f: F) -> R
+    where
+        F: FnOnce(&Handle) -> R,
+    {
+        let handle = Coroutine::reactor();
+        let mut result = None;
+        {
+            let result = &mut result;
+            // We resume ourselves from the hook, so the handle can't get dropped.
+            Coroutine::suspend_inner(move |me| {
+                    *result = Some(panic::catch_unwind(AssertUnwindSafe(|| f(&handle))));
+                    Some(me.coroutine)
+                })
+                .expect("Dropped while running in reactor context");
+        }
+        match result.expect("The reactor context closure didn't run") {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    /// Switches to the parent with the `Suspend` instruction.
+    ///
+    /// The `hook` is run by the parent and it may return the coroutine to switch into next.
+    fn suspend_inner<F>(hook: F) -> Result<Option<CoroutineHandle>, Dropped>
+    where
+        F: FnOnce(CoroutineHandle) -> Option<ReadyCoroutine>,
+    {
+        // This works much like `wait`, except that instead of spawning a future that would resume
+        // us, our parent passes the suspended coroutine to the hook.
+        let my_context = pop_context().expect("Can't suspend outside of a coroutine");
+        let inbox = Rc::new(RefCell::new(None));
+        let mut hook = Some(hook);
+        let (reply_instruction, context) = {
+            // The same 'static pretending as in wait.
+            let hook_ref = &mut hook as *mut _ as usize;
+            let inbox = inbox.clone();
+            let mut receive = move |coroutine| {
+                let hook = hook_ref as *mut Option<F>;
+                let hook = unsafe { hook.as_mut() }
+                    .unwrap()
+                    .take()
+                    .expect("Coroutine suspended twice");
+                hook(CoroutineHandle {
+                    coroutine,
+                    inbox: inbox.clone(),
+                })
+            };
+            my_context.cancel.set_parked(true);
+            let instruction = Switch::Suspend {
+                stack: my_context.stack,
+                cleanup_strategy: my_context.cleanup_strategy,
+                hook: &mut receive,
+            };
+            instruction.exchange(my_context.parent_context)
+        };
+        let (result, stack) = match reply_instruction {
+            Switch::Resume { stack } => (Ok(inbox.borrow_mut().take()), stack),
+            Switch::Cleanup { stack } => (Err(Dropped), stack),
+            _ => unreachable!("Invalid instruction on wakeup"),
+        };
+        my_context.cancel.set_parked(false);
+        push_context(CoroutineContext {
+            parent_context: context,
+            stack,
+            handle: my_context.handle,
+            cleanup_strategy: my_context.cleanup_strategy,
+            priority: my_context.priority,
+            fair: my_context.fair,
+            values: my_context.values,
+            cancel: my_context.cancel,
+            span: my_context.span,
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::{Error as IoError, ErrorKind};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{future, stream, StartSend, Stream};
+    #[cfg(all(unix, feature = "signal"))]
+    use libc;
+    use log;
+    use futures::unsync::mpsc;
+    use tokio_core::reactor::{Core, Timeout};
+
+    use super::*;
+
+    /// Test spawning and execution of tasks.
+    #[test]
+    fn spawn_some() {
+        let mut core = Core::new().unwrap();
+        let s1 = Rc::new(AtomicBool::new(false));
+        let s2 = Rc::new(AtomicBool::new(false));
+        let s1c = s1.clone();
+        let s2c = s2.clone();
+        let handle = core.handle();
+
+        let mut builder = Coroutine::new(handle);
+        builder.stack_size(40960);
+        let builder_inner = builder.clone();
+
+        let result = builder.spawn(move || {
+                let result = builder_inner.spawn(move || {
+                        s2c.store(true, Ordering::Relaxed);
+                        42
+                    })
+                    .unwrap();
+                s1c.store(true, Ordering::Relaxed);
+                result
+            })
+            .unwrap();
+
+        // Both coroutines run to finish
+        assert!(s1.load(Ordering::Relaxed), "The outer closure didn't run");
+        assert!(s2.load(Ordering::Relaxed), "The inner closure didn't run");
+        // The result gets propagated through.
+        let extract = result.and_then(|r| r);
+        assert_eq!(42, core.run(extract).unwrap());
+    }
+
+    /// Wait for a future to complete.
+    #[test]
+    fn future_wait() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (sender, receiver) = oneshot::channel();
+        let all_done = Coroutine::with_defaults(core.handle(), move || {
+            let msg = Coroutine::wait(receiver).unwrap().unwrap();
+            msg
+        });
+        Coroutine::with_defaults(core.handle(), move || {
+            let timeout = Timeout::new(Duration::from_millis(50), &handle).unwrap();
+            Coroutine::wait(timeout).unwrap().unwrap();
+            sender.send(42).unwrap();
+        });
+        assert_eq!(42, core.run(all_done).unwrap());
+    }
+
+    /// An already sent value is picked up without switching out of the coroutine, a pending one
+    /// is still waited for.
+    #[test]
+    fn wait_oneshot_fast() {
+        let mut core = Core::new().unwrap();
+        let (ready_sender, ready) = oneshot::channel();
+        let (late_sender, late) = oneshot::channel();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            ready_sender.send(1).unwrap();
+            let before = ::switch::switch_count();
+            let first = Coroutine::wait_oneshot(ready);
+            let switches = ::switch::switch_count() - before;
+            (first, switches, Coroutine::wait_oneshot(late))
+        });
+        late_sender.send(2).unwrap();
+        assert_eq!((Some(1), 0, Some(2)), core.run(coroutine).unwrap());
+    }
+
+    /// The lazily spawned coroutine runs only once the core is driven.
+    #[test]
+    fn spawn_lazy_fn() {
+        let mut core = Core::new().unwrap();
+        let ran = Rc::new(Cell::new(false));
+        let ran_cp = ran.clone();
+        let builder = Coroutine::new(core.handle());
+        let result = builder.spawn_lazy_fn(move || ran_cp.set(true)).unwrap();
+        assert!(!ran.get());
+        core.run(result).unwrap();
+        assert!(ran.get());
+        // The builder itself still starts the coroutines right away
+        let ran_cp = ran.clone();
+        builder.spawn(move || ran_cp.set(false)).unwrap();
+        assert!(!ran.get());
+    }
+
+    /// Cancelling the token stops the interval before the next tick.
+    #[test]
+    fn interval_until_cancelled() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let token = CancelToken::new();
+            let token_cp = token.clone();
+            let start = Instant::now();
+            let mut ticks = 0;
+            Coroutine::interval_until(Duration::from_millis(50), &token, || {
+                ticks += 1;
+                if ticks == 2 {
+                    token_cp.cancel();
+                }
+                true
+            }).unwrap();
+            assert!(token.is_cancelled());
+            (ticks, start.elapsed())
+        });
+        let (ticks, elapsed) = core.run(coroutine).unwrap();
+        assert_eq!(2, ticks);
+        // It didn't wait for the third tick
+        assert!(elapsed < Duration::from_millis(150));
+    }
+
+    /// The interval stops once the closure says so.
+    #[test]
+    fn interval_until_stopped() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let mut ticks = 0;
+            Coroutine::interval_until(Duration::from_millis(10), &CancelToken::new(), || {
+                ticks += 1;
+                ticks < 3
+            }).unwrap();
+            ticks
+        });
+        assert_eq!(3, core.run(coroutine).unwrap());
+    }
+
+    /// Values that can't leave the thread flow through the wait and stay usable afterwards.
+    #[test]
+    fn wait_not_send() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<Rc<RefCell<Vec<u32>>>>();
+        let shared = Rc::new(RefCell::new(vec![1]));
+        let shared_cp = shared.clone();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let local = Rc::new(Cell::new(2));
+            let received = Coroutine::wait(receiver.map(|v| (v, local))).unwrap().unwrap();
+            let (value, local) = received;
+            value.borrow_mut().push(local.get());
+            Rc::strong_count(&value)
+        });
+        sender.send(shared_cp).unwrap();
+        assert_eq!(2, core.run(coroutine).unwrap());
+        assert_eq!(vec![1, 2], *shared.borrow());
+    }
+
+    /// The iteration through an endless stream stops at the deadline.
+    #[test]
+    fn stream_until_deadline() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let ticks = Interval::new(Duration::from_millis(50), &Coroutine::reactor()).unwrap();
+            let start = Instant::now();
+            let mut count = 0;
+            let result = Coroutine::stream_until_deadline(ticks, start + Duration::from_millis(275),
+                                                          |()| count += 1);
+            (result.map(|_| ()), count, start.elapsed())
+        });
+        let (result, count, elapsed) = core.run(coroutine).unwrap();
+        assert_eq!(Err(WaitTimeout::TimedOut), result);
+        assert!(count >= 3 && count <= 6, "Unexpected number of ticks {}", count);
+        assert!(elapsed < Duration::from_millis(1000));
+    }
+
+    /// An endless stream that is always ready still stops at the deadline.
+    #[test]
+    fn stream_until_deadline_ready() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let deadline = Instant::now() + Duration::from_millis(10);
+            let mut count = 0;
+            let result = Coroutine::stream_until_deadline(stream::iter_ok::<_, ()>(0..),
+                                                          deadline, |_| count += 1);
+            (result.map(|_| ()), count > 0)
+        });
+        assert_eq!((Err(WaitTimeout::TimedOut), true), core.run(coroutine).unwrap());
+    }
+
+    /// A stream ending before the deadline is fully iterated.
+    #[test]
+    fn stream_until_deadline_end() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let mut items = Vec::new();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let result = Coroutine::stream_until_deadline(stream::iter_ok::<_, ()>(vec![1, 2, 3]),
+                                                          deadline, |i| items.push(i));
+            (result, items)
+        });
+        assert_eq!((Ok(Ok(())), vec![1, 2, 3]), core.run(coroutine).unwrap());
+    }
+
+    /// Collecting an endless stream stops at the cap, a short stream is collected whole.
+    #[test]
+    fn collect_stream() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let ticks = Interval::new(Duration::from_millis(1), &Coroutine::reactor()).unwrap();
+            let capped = Coroutine::collect_stream(ticks, 5).unwrap();
+            let short = Coroutine::collect_stream(stream::iter_ok::<_, ()>(vec![1, 2]), 5);
+            (capped.len(), short)
+        });
+        assert_eq!((5, Ok(vec![1, 2])), core.run(coroutine).unwrap());
+    }
+
+    /// A callback wakes up the coroutine, no matter if it comes before or during the wait.
+    #[test]
+    fn wait_notify() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let notify = CoNotify::new();
+        // One notification before the coroutine even started
+        notify.notify();
+        let notify_cp = notify.clone();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let before = ::switch::switch_count();
+            Coroutine::wait_notify(&notify_cp).unwrap();
+            // That one was picked up right away
+            assert_eq!(before, ::switch::switch_count());
+            let notify = notify_cp.clone();
+            // Simulates a callback from some library, a bit later
+            let callback = Timeout::new(Duration::from_millis(10), &handle)
+                .unwrap()
+                .then(move |_| {
+                    notify.notify();
+                    Ok(())
+                });
+            handle.spawn(callback);
+            Coroutine::wait_notify(&notify_cp).unwrap();
+        });
+        core.run(coroutine).unwrap();
+        assert!(!notify.take_pending());
+    }
+
+    /// A stack that can't be allocated is reported together with its size.
+    #[test]
+    fn stack_error_size() {
+        let core = Core::new().unwrap();
+        let size = usize::max_value() / 2;
+        let mut builder = Coroutine::new(core.handle());
+        builder.stack_size(size);
+        match builder.spawn(|| ()) {
+            Err(err @ SpawnError::Stack { .. }) => {
+                assert!(err.to_string().contains(&size.to_string()), "Size missing in {}", err);
+            },
+            _ => panic!("The stack should have failed to allocate"),
+        }
+    }
+
+    /// A future checking it never moves once polled, up to and including its drop.
+    struct Unmoved {
+        polls_left: usize,
+        addr: usize,
+        moved: Rc<Cell<Option<bool>>>,
+    }
+
+    impl Unmoved {
+        fn new(polls_left: usize) -> (Self, Rc<Cell<Option<bool>>>) {
+            let moved = Rc::new(Cell::new(None));
+            let fut = Unmoved {
+                polls_left,
+                addr: 0,
+                moved: moved.clone(),
+            };
+            (fut, moved)
+        }
+
+        fn check(&mut self) {
+            let addr = self as *mut _ as usize;
+            if self.addr == 0 {
+                self.addr = addr;
+            } else if self.addr != addr {
+                self.moved.set(Some(true));
+            }
+        }
+    }
+
+    impl Future for Unmoved {
+        type Item = ();
+        type Error = ();
+        fn poll(&mut self) -> Poll<(), ()> {
+            self.check();
+            if self.polls_left == 0 {
+                Ok(Async::Ready(()))
+            } else {
+                self.polls_left -= 1;
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    impl Drop for Unmoved {
+        fn drop(&mut self) {
+            self.check();
+            if self.moved.get().is_none() {
+                self.moved.set(Some(false));
+            }
+        }
+    }
+
+    /// The future stays in place while waited for, both when it resolves and when it gets dropped.
+    #[test]
+    fn wait_unmoved() {
+        let mut core = Core::new().unwrap();
+        let (fut, moved) = Unmoved::new(3);
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait(fut).unwrap().unwrap();
+        });
+        core.run(result).unwrap();
+        assert_eq!(Some(false), moved.get());
+
+        let mut core = Core::new().unwrap();
+        let (fut, moved) = Unmoved::new(usize::max_value());
+        Coroutine::with_defaults(core.handle(), move || {
+            assert_eq!(Err(Dropped), Coroutine::wait(fut));
+        });
+        // Poll it a few times before interrupting the wait
+        for _ in 0..3 {
+            core.turn(Some(Duration::from_millis(1)));
+        }
+        assert!(moved.get().is_none());
+        drop(core);
+        assert_eq!(Some(false), moved.get());
+    }
+
+    /// Bad stack sizes are caught when configuring the builder.
+    #[test]
+    fn try_stack_size() {
+        let mut core = Core::new().unwrap();
+        let page_size = stack_cache::page_size();
+        let mut builder = Coroutine::new(core.handle());
+        builder.stack_size(4 * page_size);
+        let invalid = InvalidStackSize {
+            size: 4 * page_size + 1,
+            page_size,
+        };
+        assert_eq!(Some(invalid), builder.try_stack_size(4 * page_size + 1).err());
+        assert_eq!(Some(0), builder.try_stack_size(0).err().map(|e| e.size));
+        // The builder stays with the previous, valid, size
+        assert_eq!(4 * page_size, builder.stack_size);
+
+        let rounded = Coroutine::stack_size_rounded(4 * page_size + 1);
+        assert_eq!(5 * page_size, rounded);
+        assert_eq!(4 * page_size, Coroutine::stack_size_rounded(4 * page_size));
+        assert_eq!(page_size, Coroutine::stack_size_rounded(0));
+        builder.try_stack_size(rounded).unwrap();
+        assert_eq!(42, builder.spawn(|| 42).unwrap().block(&mut core).unwrap());
+    }
+
+    /// The cleanup runs when the coroutine gets cancelled during the wait.
+    #[test]
+    fn wait_with_cleanup_cancelled() {
+        let mut core = Core::new().unwrap();
+        let cleaned = Rc::new(Cell::new(false));
+        let cleaned_cp = cleaned.clone();
+        let (_sender, receiver) = oneshot::channel::<()>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let result = Coroutine::wait_with_cleanup(receiver, || cleaned_cp.set(true));
+            // Already cleaned up by the time the wait returns
+            assert!(cleaned_cp.get());
+            result
+        });
+        assert!(!cleaned.get());
+        coroutine.cancel();
+        assert_eq!(Err(Dropped), core.run(coroutine).unwrap());
+        assert!(cleaned.get());
+    }
+
+    /// The cleanup doesn't run when the future resolves, not even with an error.
+    #[test]
+    fn wait_with_cleanup_completed() {
+        let mut core = Core::new().unwrap();
+        let cleaned = Rc::new(Cell::new(false));
+        let cleaned_cp = cleaned.clone();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let cleanup = || cleaned_cp.set(true);
+            let ok = Coroutine::wait_with_cleanup(future::ok::<_, ()>(42), cleanup);
+            let err = Coroutine::wait_with_cleanup(future::err::<(), _>(42), cleanup);
+            (ok, err)
+        });
+        assert_eq!((Ok(Ok(42)), Ok(Err(42))), core.run(coroutine).unwrap());
+        assert!(!cleaned.get());
+    }
+
+    /// Coroutines on the same reactor share its id, ones on different reactors don't.
+    #[test]
+    fn spawn_with_ids() {
+        let mut core1 = Core::new().unwrap();
+        let mut core2 = Core::new().unwrap();
+        let builder1 = Coroutine::new(core1.handle());
+        let first = builder1.spawn_with_ids(|| 1).unwrap();
+        let second = builder1.spawn_with_ids(|| 2).unwrap();
+        let third = Coroutine::new(core2.handle()).spawn_with_ids(|| 3).unwrap();
+
+        assert!(first.reactor_id.is_some());
+        assert_eq!(first.reactor_id, second.reactor_id);
+        assert!(third.reactor_id.is_some());
+        assert_ne!(first.reactor_id, third.reactor_id);
+        assert_ne!(first.coroutine_id, second.coroutine_id);
+        assert_eq!(first.coroutine_id, first.result.id());
+
+        assert_eq!(1, core1.run(first.result).unwrap());
+        assert_eq!(2, core1.run(second.result).unwrap());
+        assert_eq!(3, core2.run(third.result).unwrap());
+    }
+
+    /// The ids of reactors that are gone are forgotten, but not handed out again.
+    #[test]
+    fn reactor_ids_pruned() {
+        let core1 = Core::new().unwrap();
+        let first = assign_reactor_id(&core1.handle());
+        drop(core1);
+        let core2 = Core::new().unwrap();
+        let second = assign_reactor_id(&core2.handle());
+        assert_ne!(first, second);
+        let known: Vec<_> =
+            REACTOR_IDS.with(|r| r.borrow().ids.values().map(|&(id, _)| id).collect());
+        assert_eq!(vec![second], known);
+    }
+
+    /// A coroutine waiting for its own result gets a panic instead of hanging forever.
+    #[test]
+    fn await_self() {
+        let mut core = Core::new().unwrap();
+        let me = Rc::new(RefCell::new(None));
+        let me_cp = me.clone();
+        let (sender, receiver) = oneshot::channel();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            // Let the spawner store our result
+            Coroutine::yield_now().unwrap();
+            let me: CoroutineResult<()> = me_cp.borrow_mut().take().unwrap();
+            let panicked = panic::catch_unwind(AssertUnwindSafe(|| Coroutine::wait(me)));
+            let msg = panicked
+                .unwrap_err()
+                .downcast::<&'static str>()
+                .map(|msg| *msg)
+                .unwrap_or("No message");
+            sender.send(msg).unwrap();
+        });
+        *me.borrow_mut() = Some(coroutine);
+        let msg = core.run(receiver).unwrap();
+        assert_eq!("A coroutine cannot await its own completion", msg);
+    }
+
+    /// A timeout created and driven on a reactor in another thread.
+    #[test]
+    fn wait_on() {
+        let (remote_sender, remote_receiver) = ::std::sync::mpsc::channel();
+        let (stop, stopped) = sync_oneshot::channel::<()>();
+        let other = thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            remote_sender.send(core.remote()).unwrap();
+            let _ = core.run(stopped);
+        });
+        let remote = remote_receiver.recv().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait_on(&remote, |handle| {
+                Timeout::new(Duration::from_millis(50), handle)
+                    .unwrap()
+                    .map(|()| thread::current().id())
+            })
+        });
+        let fired_in = core.run(coroutine).unwrap().unwrap().unwrap();
+        assert_ne!(thread::current().id(), fired_in);
+        stop.send(()).unwrap();
+        other.join().unwrap();
+    }
+
+    /// The coroutines get the stack sizes of their profiles.
+    #[test]
+    fn stack_profiles() {
+        let mut core = Core::new().unwrap();
+        let page_size = stack_cache::page_size();
+        let profiles = [
+            StackProfile::Tiny,
+            StackProfile::Small,
+            StackProfile::Medium,
+            StackProfile::Large,
+        ];
+        let mut builder = Coroutine::new(core.handle());
+        let mut previous = 0;
+        for &profile in &profiles {
+            let size = profile.size();
+            assert_eq!(0, size % page_size);
+            assert!(size > previous);
+            previous = size;
+            let coroutine = builder.profile(profile).spawn(Coroutine::current_stack_size).unwrap();
+            assert_eq!(size, core.run(coroutine).unwrap());
+        }
+
+        assert!(Coroutine::set_profile_size(StackProfile::Tiny, page_size + 1).is_err());
+        Coroutine::set_profile_size(StackProfile::Tiny, 3 * page_size).unwrap();
+        let coroutine = builder
+            .profile(StackProfile::Tiny)
+            .spawn(Coroutine::current_stack_size)
+            .unwrap();
+        assert_eq!(3 * page_size, core.run(coroutine).unwrap());
+        StackProfile::Tiny.reset_size();
+        assert_eq!(4 * page_size, StackProfile::Tiny.size());
+    }
+
+    /// The observer learns how each of the waits ended.
+    #[test]
+    fn wait_observed() {
+        let outcomes = Rc::new(RefCell::new(Vec::new()));
+        let observed = |outcomes: &Rc<RefCell<Vec<WaitOutcome>>>, first_done: Sender<()>| {
+            let outcomes = outcomes.clone();
+            move || {
+                let (_sender, receiver) = oneshot::channel::<()>();
+                let outcomes_cp = outcomes.clone();
+                let ready = Coroutine::wait_observed(future::ok::<_, ()>(42), move |outcome| {
+                    outcomes_cp.borrow_mut().push(outcome)
+                });
+                assert_eq!(Ok(Ok(42)), ready);
+                let _ = first_done.send(());
+                // This one never resolves
+                Coroutine::wait_observed(receiver, move |outcome| {
+                    outcomes.borrow_mut().push(outcome)
+                }).map(|_| ())
+            }
+        };
+
+        // Let the first wait complete before interrupting the second one.
+        let mut core = Core::new().unwrap();
+        let (first_done, first) = oneshot::channel();
+        Coroutine::with_defaults(core.handle(), observed(&outcomes, first_done));
+        core.run(first).unwrap();
+        drop(core);
+        assert_eq!(vec![WaitOutcome::Completed, WaitOutcome::Dropped], *outcomes.borrow());
+
+        outcomes.borrow_mut().clear();
+        let mut core = Core::new().unwrap();
+        let (first_done, first) = oneshot::channel();
+        let coroutine = Coroutine::with_defaults(core.handle(), observed(&outcomes, first_done));
+        core.run(first).unwrap();
+        coroutine.cancel();
+        assert_eq!(Err(Dropped), core.run(coroutine).unwrap());
+        assert_eq!(vec![WaitOutcome::Completed, WaitOutcome::Cancelled], *outcomes.borrow());
+    }
+
+    /// A timeout fires and is waited for without the nested results.
+    #[test]
+    fn wait_timeout_fire() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let timeout = Timeout::new(Duration::from_millis(10), &handle).unwrap();
+            Coroutine::wait_timeout_fire(timeout)
+        });
+        assert_eq!(Ok(()), core.run(coroutine).unwrap());
+    }
+
+    /// Sleeping until an instant in the past returns right away.
+    #[test]
+    fn sleep_until_past() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let start = Instant::now();
+            Coroutine::sleep_until(start - Duration::from_millis(100)).unwrap();
+            start.elapsed()
+        });
+        assert!(core.run(coroutine).unwrap() < Duration::from_millis(50));
+    }
+
+    /// Sleeping until an instant in the future suspends for about the right time.
+    #[test]
+    fn sleep_until_future() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let start = Instant::now();
+            Coroutine::sleep_until(start + Duration::from_millis(50)).unwrap();
+            start.elapsed()
+        });
+        let elapsed = core.run(coroutine).unwrap();
+        assert!(elapsed >= Duration::from_millis(45));
+        assert!(elapsed < Duration::from_millis(500));
+    }
+
+    /// Receiving a sent value from a oneshot.
+    #[test]
+    fn wait_oneshot_deliver() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait_oneshot(receiver)
+        });
+        Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait(future::ok::<_, ()>(())).unwrap().unwrap();
+            sender.send(42).unwrap();
+        });
+        assert_eq!(Some(42), core.run(coroutine).unwrap());
+    }
+
+    /// All the results are collected, failures don't stop the others.
+    #[test]
+    fn wait_all_settled_mixed() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let delayed = |ms, result: Result<u32, u32>| {
+                Timeout::new(Duration::from_millis(ms), &handle)
+                    .unwrap()
+                    .then(move |_| result)
+            };
+            let futures = vec![
+                delayed(50, Ok(1)),
+                delayed(0, Err(2)),
+                delayed(20, Ok(3)),
+                delayed(10, Err(4)),
+            ];
+            Coroutine::wait_all_settled(futures).unwrap()
+        });
+        let results = core.run(coroutine).unwrap();
+        assert_eq!(vec![Ok(1), Err(2), Ok(3), Err(4)], results);
+    }
+
+    /// Nothing to wait for.
+    #[test]
+    fn wait_all_settled_empty() {
+        let result = Coroutine::run(|| {
+            Coroutine::wait_all_settled(Vec::<future::FutureResult<(), ()>>::new()).unwrap()
+        });
+        assert!(result.unwrap().is_empty());
+    }
+
+    /// Coroutines looping over ready futures with `wait_fair` take turns.
+    #[test]
+    fn wait_fair_alternates() {
+        let mut core = Core::new().unwrap();
+        let counter = Rc::new(Cell::new(0));
+        let counter_cp = counter.clone();
+        let observer = Coroutine::with_defaults(core.handle(), move || {
+            (0..10)
+                .map(|_| {
+                    Coroutine::wait_fair(future::ok::<(), ()>(())).unwrap().unwrap();
+                    counter_cp.get()
+                })
+                .collect::<Vec<_>>()
+        });
+        let counter_cp = counter.clone();
+        let competitor = Coroutine::with_defaults(core.handle(), move || {
+            for _ in 0..20 {
+                counter_cp.set(counter_cp.get() + 1);
+                Coroutine::yield_now().unwrap();
+            }
+        });
+        let (seen, ()) = core.run(observer.join(competitor)).unwrap();
+        // The competitor made progress between each two resumptions of the observer
+        assert!(seen.windows(2).all(|w| w[0] < w[1]), "Starved: {:?}", seen);
+        assert_eq!(20, counter.get());
+    }
+
+    /// Cancelling a coroutine cancels its children, but not the independent ones.
+    #[test]
+    fn cancel_cascade() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let child_cancelled = Rc::new(Cell::new(false));
+        let independent_cancelled = Rc::new(Cell::new(false));
+        let child_cp = child_cancelled.clone();
+        let independent_cp = independent_cancelled.clone();
+        let parent = Coroutine::with_defaults(core.handle(), move || {
+            let child = Coroutine::with_defaults(handle.clone(), move || {
+                child_cp.set(Coroutine::wait(future::empty::<(), ()>()).is_err());
+            });
+            let independent = Coroutine::new(handle)
+                .independent(true)
+                .spawn(move || {
+                    independent_cp.set(Coroutine::wait(future::empty::<(), ()>()).is_err());
+                })
+                .unwrap();
+            let cancelled = Coroutine::wait(future::empty::<(), ()>()).is_err();
+            // Further waits fail right away
+            assert!(Coroutine::wait(future::ok::<(), ()>(())).is_err());
+            (cancelled, child, independent)
+        });
+        parent.cancel();
+        let (cancelled, child, _independent) = core.run(parent).unwrap();
+        assert!(cancelled);
+        core.run(child).unwrap();
+        assert!(child_cancelled.get());
+        core.turn(Some(Duration::from_millis(10)));
+        assert!(!independent_cancelled.get());
+        assert_eq!(0, context_depth());
+    }
+
+    /// A future woken up by a notification from another thread.
+    ///
+    /// It stores the task and the other thread flips the flag and notifies it.
+    struct Foreign(Arc<Mutex<(bool, Option<task::Task>)>>);
+
+    impl Future for Foreign {
+        type Item = ();
+        type Error = ();
+        fn poll(&mut self) -> Poll<(), ()> {
+            let mut state = self.0.lock().unwrap();
+            if state.0 {
+                Ok(Async::Ready(()))
+            } else {
+                state.1 = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    /// The waiting coroutine gets woken up by a notification from a different OS thread.
+    #[test]
+    fn wait_foreign_notify() {
+        let mut core = Core::new().unwrap();
+        let state = Arc::new(Mutex::new((false, None)));
+        let future = Foreign(state.clone());
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait(future).unwrap().unwrap();
+            42
+        });
+        let notifier = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut state = state.lock().unwrap();
+            state.0 = true;
+            state.1
+                .take()
+                .expect("The future wasn't polled yet")
+                .notify();
+        });
+        assert_eq!(42, core.run(coroutine).unwrap());
+        notifier.join().unwrap();
+    }
+
+    /// A minimal mutex for coroutines, for the tests of releasing locks on cancellation.
+    #[derive(Clone, Default)]
+    struct CoMutex(Rc<RefCell<(bool, Vec<task::Task>)>>);
+
+    struct CoMutexGuard(CoMutex);
+
+    impl CoMutex {
+        fn lock(&self) -> Box<Future<Item = CoMutexGuard, Error = ()>> {
+            let mutex = self.clone();
+            Box::new(future::poll_fn(move || {
+                let mut state = mutex.0.borrow_mut();
+                if state.0 {
+                    state.1.push(task::current());
+                    Ok(Async::NotReady)
+                } else {
+                    state.0 = true;
+                    Ok(Async::Ready(CoMutexGuard(mutex.clone())))
+                }
+            }))
+        }
+        fn is_locked(&self) -> bool {
+            (self.0).borrow().0
+        }
+    }
+
+    impl Drop for CoMutexGuard {
+        fn drop(&mut self) {
+            let waiting = {
+                let mut state = (self.0).0.borrow_mut();
+                state.0 = false;
+                mem::replace(&mut state.1, Vec::new())
+            };
+            for task in waiting {
+                task.notify();
+            }
+        }
+    }
+
+    /// A lock held by the waited-for future is released when the waiting coroutine is cancelled,
+    /// already before the wait returns.
+    #[test]
+    fn cancel_releases_lock() {
+        let mut core = Core::new().unwrap();
+        let mutex = CoMutex::default();
+        let (locked_sender, locked) = oneshot::channel();
+        let holder_mutex = mutex.clone();
+        let holder = Coroutine::with_defaults(core.handle(), move || {
+            let guard = match Coroutine::wait(holder_mutex.lock()) {
+                Ok(Ok(guard)) => guard,
+                _ => return false,
+            };
+            let _ = locked_sender.send(());
+            let holding = future::empty::<(), ()>().then(move |result| {
+                drop(guard);
+                result
+            });
+            let cancelled = Coroutine::wait(holding).is_err();
+            cancelled && !holder_mutex.is_locked()
+        });
+        // Let the holder take the lock first.
+        core.run(locked).unwrap();
+        assert!(mutex.is_locked());
+        let waiter_mutex = mutex.clone();
+        let waiter = Coroutine::with_defaults(core.handle(), move || {
+            match Coroutine::wait(waiter_mutex.lock()) {
+                Ok(Ok(_guard)) => true,
+                _ => false,
+            }
+        });
+        holder.cancel();
+        assert!(core.run(holder).unwrap());
+        assert!(core.run(waiter).unwrap());
+        assert!(!mutex.is_locked());
+    }
+
+    /// A cancelled coroutine with the leaking strategy isn't woken up.
+    #[test]
+    fn cancel_leak_always() {
+        let mut core = Core::new().unwrap();
+        let woken = Rc::new(Cell::new(false));
+        let woken_cp = woken.clone();
+        let coroutine = Coroutine::new(core.handle())
+            .cleanup_strategy(CleanupStrategy::LeakAlways)
+            .spawn(move || {
+                drop(Coroutine::wait(future::empty::<(), ()>()));
+                woken_cp.set(true);
+            })
+            .unwrap();
+        coroutine.cancel();
+        core.turn(Some(Duration::from_millis(10)));
+        assert!(!woken.get());
+        // The leaked coroutine still holds its clone
+        assert_eq!(2, Rc::strong_count(&woken));
+    }
+
+    /// The first child to finish wins, the others get cancelled.
+    #[test]
+    fn race_children_cancels() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let cancelled = Rc::new(Cell::new(0));
+        let cancelled_cp = cancelled.clone();
+        let race = Coroutine::with_defaults(core.handle(), move || {
+            let children = (0..3)
+                .map(|i| {
+                    let cancelled = cancelled_cp.clone();
+                    Coroutine::with_defaults(handle.clone(), move || {
+                        if i == 1 {
+                            Coroutine::sleep(Duration::from_millis(10)).unwrap();
+                        } else if Coroutine::sleep(Duration::from_secs(10)).is_err() {
+                            cancelled.set(cancelled.get() + 1);
+                        }
+                        i
+                    })
+                })
+                .collect();
+            Coroutine::race_children(children)
+        });
+        assert_eq!(1, core.run(race).unwrap().unwrap());
+        let start = Instant::now();
+        while cancelled.get() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5), "Losers not cancelled");
+            core.turn(Some(Duration::from_millis(10)));
+        }
+    }
+
+    /// The coroutine knows the size of its stack.
+    #[test]
+    fn current_stack_size() {
+        let mut core = Core::new().unwrap();
+        let sizes = Coroutine::new(core.handle())
+            .stack_size(4096 * 20)
+            .spawn(|| {
+                let outer = Coroutine::current_stack_size();
+                let inner = Coroutine::new(Coroutine::reactor())
+                    .stack_size(4096 * 30)
+                    .spawn(Coroutine::current_stack_size)
+                    .unwrap();
+                // Back in the outer one after waiting
+                let inner = Coroutine::wait(inner).unwrap().unwrap();
+                (outer, inner, Coroutine::current_stack_size())
+            })
+            .unwrap();
+        assert_eq!((4096 * 20, 4096 * 30, 4096 * 20), core.run(sizes).unwrap());
+    }
+
+    /// Pumping items from one bounded channel to another one.
+    ///
+    /// The channels are the thread-safe ones, the unsync ones in the pinned version of futures
+    /// don't wake the receiver when the sender is dropped.
+    #[test]
+    fn pump_channels() {
+        let mut core = Core::new().unwrap();
+        let (mut in_sender, in_receiver) = sync_mpsc::channel(1);
+        let (out_sender, out_receiver) = sync_mpsc::channel(1);
+        let pump = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::pump(in_receiver, out_sender)
+        });
+        let feed = Coroutine::with_defaults(core.handle(), move || {
+            for i in 0..10 {
+                in_sender = Coroutine::wait(in_sender.send(i)).unwrap().unwrap();
+            }
+        });
+        let collected = core.run(out_receiver.collect()).unwrap();
+        assert_eq!((0..10).collect::<Vec<_>>(), collected);
+        core.run(feed).unwrap();
+        assert!(core.run(pump).unwrap().is_ok());
+    }
+
+    /// The borrowed reactor can be used to create timeouts.
+    #[test]
+    fn with_reactor_timeout() {
+        let mut core = Core::new().unwrap();
+        let result = Coroutine::with_defaults(core.handle(), || {
+            let timeout = Coroutine::with_reactor(|handle| {
+                Timeout::new(Duration::from_millis(10), handle).unwrap()
+            });
+            Coroutine::wait(timeout).unwrap().unwrap();
+            Coroutine::with_reactor(|handle| handle.id() == Coroutine::reactor().id())
+        });
+        assert!(core.run(result).unwrap());
+    }
+
+    /// The graceful shutdown lets the quick coroutines finish and cancels the rest.
+    #[test]
+    fn shutdown_graceful() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let quick = (0..2)
+            .map(|_| {
+                let handle = handle.clone();
+                Coroutine::with_defaults(core.handle(), move || {
+                    let timeout = Timeout::new(Duration::from_millis(10), &handle).unwrap();
+                    Coroutine::wait(timeout).unwrap().unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        let stuck = (0..3)
+            .map(|_| {
+                Coroutine::with_defaults(core.handle(), || {
+                    Coroutine::wait(future::empty::<(), ()>()).is_err()
+                })
+            })
+            .collect::<Vec<_>>();
+        let report = Coroutine::shutdown_graceful(&mut core, Duration::from_millis(100));
+        assert_eq!(ShutdownReport { completed: 2, cancelled: 3 }, report);
+        for result in quick {
+            core.run(result).unwrap();
+        }
+        for result in stuck {
+            // They noticed the cancellation
+            assert!(core.run(result).unwrap());
+        }
+        // Spawning works again afterwards
+        Coroutine::new(core.handle()).spawn(|| ()).unwrap();
+    }
+
+    /// A leaked straggler no longer counts as running and deferred coroutines are counted too.
+    #[test]
+    fn shutdown_graceful_leaked() {
+        let mut core = Core::new().unwrap();
+        let _leaked = Coroutine::new(core.handle())
+            .cleanup_strategy(CleanupStrategy::LeakAlways)
+            .spawn(|| Coroutine::wait(future::empty::<(), ()>()).is_err())
+            .unwrap();
+        let _deferred = Coroutine::new(core.handle())
+            .defer_start(true)
+            .spawn(|| ())
+            .unwrap();
+        let report = Coroutine::shutdown_graceful(&mut core, Duration::from_millis(50));
+        assert_eq!(ShutdownReport { completed: 1, cancelled: 1 }, report);
+        assert_eq!(0, Coroutine::reactor_stats_of(&core.handle()).alive);
+        let report = Coroutine::shutdown_graceful(&mut core, Duration::from_millis(50));
+        assert_eq!(ShutdownReport { completed: 0, cancelled: 0 }, report);
+    }
+
+    /// Waiting for boxed futures of different types.
+    #[test]
+    fn wait_boxed() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            let base = 40;
+            let timeout = Timeout::new(Duration::from_millis(10), &handle).unwrap();
+            let futures: Vec<Box<Future<Item = u32, Error = ()>>> = vec![
+                Box::new(future::ok::<u32, ()>(1)),
+                Box::new(timeout.map(|()| 2).map_err(|_| ())),
+                Box::new(future::lazy(move || Ok::<_, ()>(base + 2))),
+                Box::new(future::err::<u32, ()>(())),
+            ];
+            futures
+                .into_iter()
+                .map(|fut| Coroutine::wait_boxed(fut).unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(vec![Ok(1), Ok(2), Ok(42), Err(())], core.run(result).unwrap());
+    }
+
+    /// When both are ready, the first one always wins.
+    #[test]
+    fn select_biased() {
+        let mut core = Core::new().unwrap();
+        let result = Coroutine::with_defaults(core.handle(), || {
+            (0..10)
+                .map(|_| {
+                    let (shutdown_sender, shutdown) = oneshot::channel::<()>();
+                    let (data_sender, data) = mpsc::unbounded::<()>();
+                    shutdown_sender.send(()).unwrap();
+                    data_sender.unbounded_send(()).unwrap();
+                    let branches: Vec<Box<Future<Item = &str, Error = ()>>> = vec![
+                        Box::new(shutdown.map(|_| "shutdown").map_err(|_| ())),
+                        Box::new(data.into_future().map(|_| "data").map_err(|_| ())),
+                    ];
+                    let (result, idx, rest) = Coroutine::select_biased(branches).unwrap();
+                    assert_eq!(1, rest.len());
+                    (result.unwrap(), idx)
+                })
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(vec![("shutdown", 0); 10], core.run(result).unwrap());
+    }
+
+    /// The loser that needs a reactor turn to finish gets it during the grace period, the one
+    /// that would take too long is dropped.
+    #[test]
+    fn select_biased_with_cleanup() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let cleaned = Rc::new(Cell::new(false));
+        let cleaned_cp = cleaned.clone();
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            let short = Timeout::new(Duration::from_millis(10), &handle).unwrap();
+            let long = Timeout::new(Duration::from_secs(10), &handle).unwrap();
+            let branches: Vec<Box<Future<Item = &str, Error = ()>>> = vec![
+                Box::new(future::ok::<_, ()>("winner")),
+                Box::new(short.then(move |_| {
+                    cleaned_cp.set(true);
+                    Ok::<_, ()>("cleaned")
+                })),
+                Box::new(long.map(|_| "stuck").map_err(|_| ())),
+            ];
+            let start = Instant::now();
+            let grace = Duration::from_millis(100);
+            let selected = Coroutine::select_biased_with_cleanup(branches, grace).unwrap();
+            (selected, start.elapsed())
+        });
+        let ((result, idx), elapsed) = core.run(result).unwrap();
+        assert_eq!((Ok("winner"), 0), (result, idx));
+        assert!(cleaned.get());
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(10));
+    }
+
+    /// The time spent waiting is measured.
+    #[test]
+    fn wait_timed() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let elapsed = Coroutine::with_defaults(core.handle(), move || {
+            let timeout = Timeout::new(Duration::from_millis(30), &handle).unwrap();
+            let (result, elapsed) = Coroutine::wait_timed(timeout);
+            result.unwrap().unwrap();
+            elapsed
+        });
+        let elapsed = core.run(elapsed).unwrap();
+        assert!(elapsed >= Duration::from_millis(30));
+        // Generous, the machine might be busy.
+        assert!(elapsed < Duration::from_millis(500));
+    }
+
+    /// The check accepts the right reactor.
+    #[test]
+    fn assert_reactor_same() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::assert_reactor(&handle);
+        });
+        core.run(coroutine).unwrap();
+    }
+
+    /// Creating a timeout with a handle of another core is caught.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "foreign reactor")]
+    fn assert_reactor_foreign() {
+        let mut core = Core::new().unwrap();
+        let other = Core::new().unwrap();
+        let other_handle = other.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::assert_reactor(&other_handle);
+            let timeout = Timeout::new(Duration::from_millis(10), &other_handle).unwrap();
+            Coroutine::wait(timeout).unwrap().unwrap();
+        });
+        core.run(coroutine).unwrap();
+    }
+
+    /// A coroutine with deferred start doesn't run until the reactor does.
+    #[test]
+    fn defer_start() {
+        let mut core = Core::new().unwrap();
+        let ran = Rc::new(Cell::new(false));
+        let ran_cp = ran.clone();
+        let result = Coroutine::new(core.handle())
+            .defer_start(true)
+            .spawn(move || ran_cp.set(true))
+            .unwrap();
+        assert!(!ran.get());
+        core.run(result).unwrap();
+        assert!(ran.get());
+    }
+
+    /// An in-memory transport, with its own end of two channels.
+    struct Duplex {
+        incoming: mpsc::UnboundedReceiver<u32>,
+        outgoing: mpsc::UnboundedSender<u32>,
+    }
+
+    impl Stream for Duplex {
+        type Item = u32;
+        type Error = ();
+        fn poll(&mut self) -> Poll<Option<u32>, ()> {
+            self.incoming.poll()
+        }
+    }
+
+    impl Sink for Duplex {
+        type SinkItem = u32;
+        type SinkError = ();
+        fn start_send(&mut self, item: u32) -> StartSend<u32, ()> {
+            self.outgoing.start_send(item).map_err(|_| ())
+        }
+        fn poll_complete(&mut self) -> Poll<(), ()> {
+            self.outgoing.poll_complete().map_err(|_| ())
+        }
+    }
+
+    /// The halves of a split transport used from two different coroutines.
+    #[test]
+    fn split_echo() {
+        let mut core = Core::new().unwrap();
+        let (to_server, incoming) = mpsc::unbounded();
+        let (outgoing, from_server) = mpsc::unbounded();
+        let (mut sink, mut stream) = Duplex { incoming, outgoing }.split();
+        let (relay_sender, mut relay_receiver) = mpsc::unbounded();
+        let reader = Coroutine::with_defaults(core.handle(), move || {
+            while let Some(msg) = Coroutine::wait_next(&mut stream).unwrap().unwrap() {
+                relay_sender.unbounded_send(msg).unwrap();
+            }
+        });
+        let writer = Coroutine::with_defaults(core.handle(), move || {
+            while let Some(msg) = Coroutine::wait_next(&mut relay_receiver).unwrap().unwrap() {
+                Coroutine::send_into(&mut sink, msg * 2).unwrap().unwrap();
+            }
+        });
+        for i in 0..5 {
+            to_server.unbounded_send(i).unwrap();
+        }
+        drop(to_server);
+        core.run(reader).unwrap();
+        core.run(writer).unwrap();
+        // Both halves are gone by now, so the transport is closed
+        let echoed = core.run(from_server.collect()).unwrap();
+        assert_eq!(vec![0, 2, 4, 6, 8], echoed);
+    }
+
+    /// A future that never resolves and notes when it gets dropped.
+    struct Pending(Rc<Cell<bool>>);
+
+    impl Future for Pending {
+        type Item = ();
+        type Error = ();
+        fn poll(&mut self) -> Poll<(), ()> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl Drop for Pending {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    /// Taking an already known result doesn't switch anywhere, unlike waiting for a ready future.
+    #[test]
+    fn wait_result_now() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let before = ::switch::switch_count();
+            assert_eq!(Ok(42), Coroutine::wait_result_now(Ok::<_, ()>(42)));
+            assert_eq!(before, ::switch::switch_count());
+            Coroutine::wait(future::ok::<_, ()>(42)).unwrap().unwrap();
+            assert!(::switch::switch_count() > before);
+        });
+        core.run(coroutine).unwrap();
+    }
+
+    /// No more than the limit of coroutines run at once, the rest waits in a queue.
+    #[test]
+    fn max_concurrent() {
+        let mut core = Core::new().unwrap();
+        let active = Rc::new(Cell::new(0));
+        let max_active = Rc::new(Cell::new(0));
+        let mut builder = Coroutine::new(core.handle());
+        builder.max_concurrent(2);
+        let mut results = (0..5)
+            .map(|i| {
+                let active = active.clone();
+                let max_active = max_active.clone();
+                builder
+                    .spawn(move || {
+                        active.set(active.get() + 1);
+                        max_active.set(max_active.get().max(active.get()));
+                        Coroutine::sleep(Duration::from_millis(10)).unwrap();
+                        active.set(active.get() - 1);
+                        i
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        // Only the first two got to run
+        assert_eq!(2, active.get());
+        let started = results[4].started();
+        core.run(started).unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], core.run(future::join_all(results)).unwrap());
+        assert_eq!(2, max_active.get());
+    }
+
+    /// The queued coroutines run even after the builder is gone and the limiter is freed then.
+    #[test]
+    fn max_concurrent_dropped_builder() {
+        let mut core = Core::new().unwrap();
+        let mut builder = Coroutine::new(core.handle());
+        builder.max_concurrent(1);
+        let limiter = Rc::downgrade(builder.limiter.as_ref().unwrap());
+        let results = (0..3)
+            .map(|i| builder.spawn(move || Coroutine::yield_now().map(|()| i)).unwrap())
+            .collect::<Vec<_>>();
+        drop(builder);
+        assert_eq!(vec![Ok(0), Ok(1), Ok(2)], core.run(future::join_all(results)).unwrap());
+        assert!(limiter.upgrade().is_none());
+    }
+
+    /// Yielding coroutines take turns.
+    #[test]
+    fn yield_alternates() {
+        let mut core = Core::new().unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let coroutines = ["a", "b"]
+            .iter()
+            .map(|&name| {
+                let log = log.clone();
+                Coroutine::with_defaults(core.handle(), move || {
+                    for _ in 0..3 {
+                        log.borrow_mut().push(name);
+                        Coroutine::yield_now().unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        core.run(future::join_all(coroutines)).unwrap();
+        assert_eq!(vec!["a", "b", "a", "b", "a", "b"], *log.borrow());
+    }
+
+    /// A yielded coroutine is cleaned up when the reactor goes away.
+    #[test]
+    fn yield_dropped() {
+        let core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), Coroutine::yield_now);
+        drop(core);
+        assert_eq!(Err(Dropped), coroutine.wait().unwrap());
+    }
+
+    /// Dropping the guard abandons the wait and drops the future.
+    #[test]
+    fn wait_guarded_abandon() {
+        let mut core = Core::new().unwrap();
+        let dropped = Rc::new(Cell::new(false));
+        let dropped_cp = dropped.clone();
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            let guard = Coroutine::wait_guarded(Pending(dropped_cp.clone()));
+            // Let the reactor start polling it
+            Coroutine::yield_now().unwrap();
+            assert!(!dropped_cp.get());
+            drop(guard);
+            // Dropped right away, without waiting for anything
+            dropped_cp.get()
+        });
+        assert!(core.run(result).unwrap());
+        assert!(dropped.get());
+    }
+
+    /// The guarded wait can be completed, getting the result.
+    #[test]
+    fn wait_guarded_complete() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            let timeout = Timeout::new(Duration::from_millis(10), &handle).unwrap();
+            let guard = Coroutine::wait_guarded(timeout.map(|()| 42));
+            guard.complete().unwrap().unwrap()
+        });
+        assert_eq!(42, core.run(result).unwrap());
+    }
+
+    /// Each thread runs coroutines on its own thread reactor.
+    #[test]
+    fn thread_reactor() {
+        let threads = (0..4u64)
+            .map(|i| {
+                thread::spawn(move || {
+                    Coroutine::init_thread_reactor();
+                    let results = (0..3u64)
+                        .map(|j| {
+                            Coroutine::per_thread_spawn(move || {
+                                Coroutine::sleep(Duration::from_millis(10 * j)).unwrap();
+                                // Spawning from within a coroutine works too
+                                let inner = Coroutine::per_thread_spawn(move || i * 10 + j);
+                                Coroutine::wait(inner).unwrap().unwrap()
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    let all = Coroutine::run_thread_reactor(future::join_all(results)).unwrap();
+                    Coroutine::drop_thread_reactor();
+                    all
+                })
+            })
+            .collect::<Vec<_>>();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let i = i as u64;
+            assert_eq!(vec![i * 10, i * 10 + 1, i * 10 + 2], thread.join().unwrap());
+        }
+    }
+
+    /// Messages sent from another thread wake the receiving coroutine.
+    #[test]
+    fn recv_sync() {
+        let mut core = Core::new().unwrap();
+        let (sender, mut receiver) = sync_mpsc::channel(1);
+        let sum = Coroutine::with_defaults(core.handle(), move || {
+            let mut sum = 0;
+            while let Some(num) = Coroutine::recv_sync(&mut receiver) {
+                sum += num;
+            }
+            sum
+        });
+        let producer = thread::spawn(move || {
+            let mut sender = sender;
+            for i in 0..10 {
+                sender = sender.send(i).wait().unwrap();
+            }
+        });
+        assert_eq!(45, core.run(sum).unwrap());
+        producer.join().unwrap();
+    }
+
+    /// The completion callback reports how each coroutine ended.
+    #[test]
+    #[cfg(not(corona_panic_abort))]
+    fn on_complete() {
+        let mut core = Core::new().unwrap();
+        let completed = Rc::new(RefCell::new(Vec::new()));
+        let completed_cp = completed.clone();
+        let mut builder = Coroutine::new(core.handle());
+        builder.on_complete(Rc::new(move |info: CompletionInfo| {
+            completed_cp.borrow_mut().push(info);
+        }));
+        let ok = builder.spawn(|| Coroutine::yield_now().unwrap()).unwrap();
+        let failed = builder
+            .spawn_catch_panic(|| {
+                Coroutine::yield_now().unwrap();
+                panic!("Test");
+            })
+            .unwrap();
+        let (ok_id, failed_id) = (ok.id(), failed.id());
+        assert_ne!(ok_id, failed_id);
+        assert!(completed.borrow().is_empty());
+        core.run(ok).unwrap();
+        assert!(core.run(failed).is_err());
+        let mut completed = completed.borrow().clone();
+        completed.sort_by_key(|info| info.id);
+        assert_eq!(vec![
+            CompletionInfo {
+                id: ok_id,
+                status: CompletionStatus::Succeeded,
+            },
+            CompletionInfo {
+                id: failed_id,
+                status: CompletionStatus::Panicked,
+            },
+        ], completed);
+    }
+
+    /// A panicking completion callback is propagated out of the reactor.
+    #[test]
+    #[cfg(not(corona_panic_abort))]
+    fn on_complete_panic() {
+        let mut core = Core::new().unwrap();
+        let mut builder = Coroutine::new(core.handle());
+        builder.on_complete(Rc::new(|_| panic!("Test")));
+        let result = builder.spawn(|| Coroutine::yield_now().unwrap()).unwrap();
+        panic::catch_unwind(AssertUnwindSafe(|| core.run(result))).unwrap_err();
+    }
+
+    /// The heartbeat fires periodically while waiting and stops afterwards.
+    #[test]
+    fn wait_with_heartbeat() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let beats = Coroutine::with_defaults(core.handle(), move || {
+            let timeout = Timeout::new(Duration::from_millis(100), &handle).unwrap();
+            let mut beats = 0;
+            Coroutine::wait_with_heartbeat(timeout, Duration::from_millis(30), || beats += 1)
+                .unwrap()
+                .unwrap();
+            let after = beats;
+            // No more beats once done
+            Coroutine::sleep(Duration::from_millis(50)).unwrap();
+            assert_eq!(after, beats);
+            beats
+        });
+        let beats = core.run(beats).unwrap();
+        // Roughly 3, but the timers are not exact
+        assert!(beats >= 2 && beats <= 4, "Unexpected number of heartbeats: {}", beats);
+    }
+
+    /// The retry gives up on the failures with a growing pause between them, until it succeeds.
+    #[test]
+    fn retry() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let attempts = Cell::new(0);
+            let start = Instant::now();
+            let result = Coroutine::retry(3, || {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() <= 2 {
+                    future::err("Failed")
+                } else {
+                    future::ok(42)
+                }
+            });
+            (result, attempts.get(), start.elapsed())
+        });
+        let (result, attempts, elapsed) = core.run(coroutine).unwrap();
+        assert_eq!(Ok(42), result);
+        assert_eq!(3, attempts);
+        // Backed off for 10 and then 20 milliseconds
+        assert!(elapsed >= Duration::from_millis(30));
+    }
+
+    /// The retry returns the last error once it runs out of attempts.
+    #[test]
+    fn retry_exhausted() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let attempts = Cell::new(0);
+            let result = Coroutine::retry(2, || {
+                attempts.set(attempts.get() + 1);
+                future::err::<(), _>(attempts.get())
+            });
+            (result, attempts.get())
+        });
+        assert_eq!((Err(2), 2), core.run(coroutine).unwrap());
+    }
+
+    /// The leak check flags a parked coroutine, but not a completed one.
+    #[test]
+    #[cfg(all(feature = "leak-check", not(corona_panic_abort)))]
+    fn assert_no_leaks() {
+        let mut core = Core::new().unwrap();
+        let done = Coroutine::with_defaults(core.handle(), || ());
+        core.run(done).unwrap();
+        Coroutine::assert_no_leaks();
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let parked = Coroutine::with_defaults(core.handle(), move || {
+            let _ = Coroutine::wait(receiver);
+        });
+        assert!(panic::catch_unwind(Coroutine::assert_no_leaks).is_err());
+
+        sender.send(()).unwrap();
+        core.run(parked).unwrap();
+        Coroutine::assert_no_leaks();
+    }
+
+    /// A panic during a labeled wait carries the label.
+    #[test]
+    #[cfg(not(corona_panic_abort))]
+    fn wait_labeled_panic() {
+        let mut core = Core::new().unwrap();
+        let result = Coroutine::new(core.handle())
+            .spawn_catch_panic(|| {
+                // This one is done by the time of the panic
+                Coroutine::wait_labeled(future::ok::<_, ()>(()), "warm up").unwrap().unwrap();
+                let boom = future::lazy(|| -> Result<(), ()> { panic!("Boom") });
+                Coroutine::wait_labeled(boom, "loading config").unwrap().unwrap();
+            })
+            .unwrap();
+        let failed = core.run(result).unwrap_err();
+        assert!(failed.to_string().contains("loading config"));
+        match failed {
+            TaskFailed::Panicked(panic) => {
+                let labeled = panic.downcast_ref::<LabeledPanic>().unwrap();
+                assert_eq!(vec!["loading config"], labeled.trail);
+                assert_eq!(Some(&"Boom"), labeled.payload.downcast_ref::<&str>());
+            },
+            other => panic!("Unexpected failure {:?}", other),
+        }
+    }
+
+    /// A labeled panic caught inside the coroutine leaves no label behind for later panics.
+    #[test]
+    #[cfg(not(corona_panic_abort))]
+    fn wait_labeled_caught() {
+        let mut core = Core::new().unwrap();
+        let result = Coroutine::new(core.handle())
+            .spawn_catch_panic(|| {
+                let boom = future::lazy(|| -> Result<(), ()> { panic!("Boom") });
+                let caught = panic::catch_unwind(AssertUnwindSafe(|| {
+                    Coroutine::wait_labeled(boom, "caught").unwrap().unwrap();
+                }));
+                assert!(caught.unwrap_err().is::<LabeledPanic>());
+                panic!("Later");
+            })
+            .unwrap();
+        match core.run(result).unwrap_err() {
+            TaskFailed::Panicked(panic) => assert_eq!(Some(&"Later"), panic.downcast_ref()),
+            other => panic!("Unexpected failure {:?}", other),
+        }
+    }
+
+    /// A coroutine with a prefaulted stack runs as usual.
+    #[test]
+    fn prefault_stack() {
+        let mut core = Core::new().unwrap();
+        // An unusual size, so the stack is freshly allocated and not taken from the cache.
+        let result = Coroutine::new(core.handle())
+            .stack_size(4096 * 37)
+            .prefault_stack(true)
+            .spawn(|| {
+                Coroutine::wait(future::ok::<_, ()>(42)).unwrap().unwrap()
+            })
+            .unwrap();
+        assert_eq!(42, core.run(result).unwrap());
+    }
+
+    /// Several coroutines sending their results into the same channel.
+    #[test]
+    fn spawn_into_channel() {
+        let mut core = Core::new().unwrap();
+        let builder = Coroutine::new(core.handle());
+        let (sender, receiver) = mpsc::unbounded();
+        for i in 0..3 {
+            builder
+                .spawn_into(sender.clone(), move || {
+                    Coroutine::sleep(Duration::from_millis(10 * (3 - i))).unwrap();
+                    i
+                })
+                .unwrap();
+        }
+        drop(sender);
+        let mut results = core.run(receiver.collect())
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(vec![0, 1, 2], results);
+    }
+
+    /// A panic is sent as an error.
+    #[cfg(not(corona_panic_abort))]
+    #[test]
+    fn spawn_into_panic() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = mpsc::unbounded::<Result<(), TaskFailed>>();
+        Coroutine::new(core.handle())
+            .spawn_into(sender, || panic!("Test"))
+            .unwrap();
+        match core.run(receiver.into_future()) {
+            Ok((Some(Err(TaskFailed::Panicked(_))), _)) => (),
+            _ => panic!("The panic didn't arrive"),
+        }
+    }
+
+    /// Two coroutines waiting for the same shared future both get the value.
+    #[test]
+    fn wait_shared_twice() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let shared = receiver.shared();
+        let handle = core.handle();
+        let waiter = |shared: Shared<Receiver<u32>>| {
+            Coroutine::with_defaults(handle.clone(), move || {
+                *Coroutine::wait_shared(shared).unwrap()
+            })
+        };
+        let first = waiter(shared.clone());
+        let second = waiter(shared);
+        sender.send(42).unwrap();
+        assert_eq!((42, 42), core.run(first.join(second)).unwrap());
+    }
+
+    /// The reactor goes away while waiting.
+    #[test]
+    fn wait_shared_dropped() {
+        let (_sender, receiver) = oneshot::channel::<u32>();
+        let result = {
+            let core = Core::new().unwrap();
+            Coroutine::with_defaults(core.handle(), move || {
+                match Coroutine::wait_shared(receiver.shared()) {
+                    Err(SharedFailed::Dropped) => true,
+                    _ => false,
+                }
+            })
+        };
+        assert!(result.wait().unwrap());
+    }
+
+    /// The sender goes away without sending anything.
+    #[test]
+    fn wait_oneshot_canceled() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait_oneshot(receiver)
+        });
+        drop(sender);
+        assert_eq!(None, core.run(coroutine).unwrap());
+    }
+
+    /// The reactor goes away while waiting for the value.
+    #[test]
+    fn wait_oneshot_dropped() {
+        let core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait_oneshot(receiver)
+        });
+        drop(core);
+        assert_eq!(None, coroutine.wait().unwrap());
+        drop(sender);
+    }
+
+    /// The default is used when the reactor goes away during the wait.
+    #[test]
+    fn wait_or_dropped() {
+        let core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait_or(receiver, 42)
+        });
+        drop(core);
+        assert_eq!(Ok(42), coroutine.wait().unwrap());
+        drop(sender);
+    }
+
+    /// The real value wins if the future resolves.
+    #[test]
+    fn wait_or_resolved() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::wait_or(receiver, 42)
+        });
+        sender.send(12).unwrap();
+        assert_eq!(Ok(12), core.run(coroutine).unwrap());
+    }
+
+    /// A lazy coroutine doesn't run until polled.
+    #[test]
+    fn lazy_start() {
+        let mut core = Core::new().unwrap();
+        let started = Rc::new(AtomicBool::new(false));
+        let started_cp = started.clone();
+        let coroutine = Coroutine::new(core.handle())
+            .lazy(move || {
+                started_cp.store(true, Ordering::Relaxed);
+                Coroutine::wait(future::ok::<_, ()>(42)).unwrap().unwrap()
+            })
+            .unwrap();
+        // Drive the core for a while, but not the coroutine itself.
+        let timeout = Timeout::new(Duration::from_millis(10), &core.handle()).unwrap();
+        core.run(timeout).unwrap();
+        assert!(!started.load(Ordering::Relaxed));
+        assert_eq!(42, core.run(coroutine).unwrap());
+        assert!(started.load(Ordering::Relaxed));
+    }
+
+    /// A lazy coroutine that is never polled never runs.
+    #[test]
+    fn lazy_never_polled() {
+        let core = Core::new().unwrap();
+        let started = Rc::new(AtomicBool::new(false));
+        let started_cp = started.clone();
+        let coroutine = Coroutine::new(core.handle())
+            .lazy(move || started_cp.store(true, Ordering::Relaxed))
+            .unwrap();
+        drop(coroutine);
+        assert!(!started.load(Ordering::Relaxed));
+        assert_eq!(1, Rc::strong_count(&started));
+    }
+
+    /// A future that resolves in time is not bothered by the timeout.
+    #[test]
+    fn wait_timeout_in_time() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            Coroutine::wait_timeout(future::ok::<_, ()>(42), Duration::from_millis(50))
+        });
+        assert_eq!(Ok(Ok(42)), core.run(coroutine).unwrap());
+    }
+
+    /// One deadline used for several waits, each getting the full time.
+    #[test]
+    fn deadline_reuse() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let mut deadline = Coroutine::deadline(Duration::from_millis(50)).unwrap();
+            assert_eq!(Duration::from_millis(50), deadline.duration());
+            let quick = deadline.wait(future::ok::<_, ()>(1));
+            let (_sender, receiver) = oneshot::channel::<u32>();
+            let slow = deadline.wait(receiver);
+            // Longer than the deadline, so it would have expired without the reset
+            Coroutine::sleep(Duration::from_millis(80)).unwrap();
+            let delayed = Timeout::new(Duration::from_millis(10), &Coroutine::reactor())
+                .unwrap()
+                .map(|()| 3);
+            let late = deadline.wait(delayed);
+            (quick, slow, late.map(|r| r.map_err(|_| ())))
+        });
+        let (quick, slow, late) = core.run(coroutine).unwrap();
+        assert_eq!(Ok(Ok(1)), quick);
+        assert_eq!(Err(WaitTimeout::TimedOut), slow);
+        assert_eq!(Ok(Ok(3)), late);
+    }
+
+    /// A future that never resolves times out and gets dropped.
+    #[test]
+    fn wait_timeout_expired() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let result = Coroutine::wait_timeout(receiver, Duration::from_millis(50));
+            // The receiver is gone by now
+            assert!(sender.is_canceled());
+            result
+        });
+        assert_eq!(Err(WaitTimeout::TimedOut), core.run(coroutine).unwrap());
+    }
+
+    /// The reactor statistics count the parked coroutines, each reactor separately.
+    #[test]
+    fn reactor_stats() {
+        let mut core = Core::new().unwrap();
+        let other = Core::new().unwrap();
+        let handle = core.handle();
+        let mut senders = Vec::new();
+        let mut parked = Vec::new();
+        for _ in 0..3 {
+            let (sender, receiver) = oneshot::channel::<()>();
+            senders.push(sender);
+            parked.push(Coroutine::with_defaults(core.handle(), move || {
+                let _ = Coroutine::wait(receiver);
+            }));
+        }
+        let (_other_sender, other_receiver) = oneshot::channel::<()>();
+        Coroutine::with_defaults(other.handle(), move || {
+            let _ = Coroutine::wait(other_receiver);
+        });
+        let expected = ReactorStats {
+            alive: 3,
+            parked: 3,
+            runnable: 0,
+        };
+        assert_eq!(expected, Coroutine::reactor_stats_of(&handle));
+        let inside = Coroutine::with_defaults(core.handle(), Coroutine::reactor_stats);
+        let expected = ReactorStats {
+            alive: 4,
+            parked: 3,
+            runnable: 1,
+        };
+        assert_eq!(expected, core.run(inside).unwrap());
+
+        for sender in senders {
+            sender.send(()).unwrap();
+        }
+        core.run(future::join_all(parked)).unwrap();
+        assert_eq!(ReactorStats::default(), Coroutine::reactor_stats_of(&handle));
+        assert_eq!(1, Coroutine::reactor_stats_of(&other.handle()).parked);
+    }
+
+    /// Waiting for a fused future the second time panics clearly instead of hanging.
+    #[test]
+    #[cfg(not(corona_panic_abort))]
+    fn wait_fused_twice() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let mut fut = Fused::new(receiver);
+            assert_eq!(42, Coroutine::wait_fused(&mut fut).unwrap().unwrap());
+            let again = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _ = Coroutine::wait_fused(&mut fut);
+            }));
+            *again.unwrap_err().downcast::<&str>().unwrap()
+        });
+        sender.send(42).unwrap();
+        assert_eq!("Awaited an already completed future", core.run(coroutine).unwrap());
+    }
+
+    /// A child coroutine can be spawned from within the poll of a future the parent waits for.
+    #[test]
+    fn spawn_from_poll() {
+        let mut core = Core::new().unwrap();
+        assert!(Coroutine::current_handle().is_none());
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let spawning = future::poll_fn(|| -> Poll<CoroutineResult<u32>, ()> {
+                let handle = Coroutine::current_handle().expect("No handle in the poll");
+                Ok(Async::Ready(Coroutine::with_defaults(handle, || 42)))
+            });
+            let child = Coroutine::wait(spawning).unwrap().unwrap();
+            Coroutine::wait(child).unwrap().unwrap()
+        });
+        assert_eq!(42, core.run(coroutine).unwrap());
+    }
+
+    /// A future resolving after the deadline, but within the grace period, still delivers.
+    #[test]
+    fn wait_timeout_graceful_in_grace() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let late = Timeout::new(Duration::from_millis(50), &handle)
+                .unwrap()
+                .map(|()| 42)
+                .map_err(|_| ());
+            let deadline = Instant::now() + Duration::from_millis(10);
+            Coroutine::wait_timeout_graceful(late, deadline, Duration::from_secs(10))
+        });
+        assert_eq!(GracefulTimeout::InGrace(Ok(42)), core.run(coroutine).unwrap().unwrap());
+    }
+
+    /// A future missing both the deadline and the grace period is abandoned.
+    #[test]
+    fn wait_timeout_graceful_abandoned() {
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = oneshot::channel::<u32>();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let deadline = Instant::now() + Duration::from_millis(10);
+            let result = Coroutine::wait_timeout_graceful(receiver, deadline,
+                                                          Duration::from_millis(10));
+            // The receiver is gone by now
+            assert!(sender.is_canceled());
+            result
+        });
+        assert_eq!(GracefulTimeout::Abandoned, core.run(coroutine).unwrap().unwrap());
+    }
+
+    /// A future that keeps waking itself up without resolving runs out of its poll budget.
+    #[test]
+    fn wait_bounded_exceeded() {
+        let mut core = Core::new().unwrap();
+        let polls = Rc::new(Cell::new(0));
+        let polls_cp = polls.clone();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let spinning = future::poll_fn(move || -> Poll<(), ()> {
+                polls_cp.set(polls_cp.get() + 1);
+                task::current().notify();
+                Ok(Async::NotReady)
+            });
+            Coroutine::wait_bounded(spinning, 10)
+        });
+        assert_eq!(Err(WaitAborted::PollBudgetExceeded), core.run(coroutine).unwrap());
+        assert_eq!(10, polls.get());
+    }
+
+    /// A future resolving within the budget is not bothered by it.
+    #[test]
+    fn wait_bounded_in_budget() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            Coroutine::wait_bounded(future::ok::<_, ()>(42), 1)
+        });
+        assert_eq!(Ok(Ok(42)), core.run(coroutine).unwrap());
+    }
+
+    /// Timing out on a result doesn't stop the coroutine.
+    #[test]
+    fn result_with_timeout() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let finished = Rc::new(Cell::new(false));
+        let finished_cp = finished.clone();
+        let slow = Coroutine::with_defaults(handle.clone(), move || {
+            Coroutine::sleep(Duration::from_millis(50)).unwrap();
+            finished_cp.set(true);
+        });
+        let waited = core.run(slow.with_timeout(Duration::from_millis(10), &handle));
+        assert_eq!(None, waited.unwrap());
+        assert!(!finished.get());
+        // The coroutine is still alive and finishes later on
+        let later = Coroutine::with_defaults(handle.clone(), || {
+            Coroutine::sleep(Duration::from_millis(100)).unwrap();
+        });
+        core.run(later).unwrap();
+        assert!(finished.get());
+        // A quick one makes it in time
+        let quick = Coroutine::with_defaults(handle.clone(), || 42);
+        let waited = core.run(quick.with_timeout(Duration::from_secs(10), &handle));
+        assert_eq!(Some(42), waited.unwrap());
+    }
+
+    /// The awaiter that timed out gets `None`, but the coroutine still runs to completion.
+    #[test]
+    fn result_timeout_completes_later() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (done_sender, done) = oneshot::channel();
+        let slow = Coroutine::with_defaults(handle.clone(), move || {
+            Coroutine::sleep(Duration::from_millis(50)).unwrap();
+            let _ = done_sender.send(42);
+        });
+        let waited = core.run(slow.with_timeout(Duration::from_millis(10), &handle));
+        assert_eq!(None, waited.unwrap());
+        assert_eq!(42, core.run(done).unwrap());
+    }
+
+    /// Joining a child from another coroutine, with a time limit.
+    #[test]
+    fn join_timeout() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let finished = Rc::new(Cell::new(false));
+        let finished_cp = finished.clone();
+        let result = Coroutine::with_defaults(handle.clone(), move || {
+            let slow = Coroutine::new(Coroutine::reactor()).spawn(move || {
+                Coroutine::sleep(Duration::from_millis(50)).unwrap();
+                finished_cp.set(true);
+            }).unwrap();
+            assert_eq!(None, slow.join_timeout(Duration::from_millis(10)).unwrap());
+            let quick = Coroutine::new(Coroutine::reactor()).spawn(|| 42).unwrap();
+            quick.join_timeout(Duration::from_secs(10)).unwrap()
+        });
+        assert_eq!(Some(42), core.run(result).unwrap());
+        assert!(!finished.get());
+        // The slow child is left running and finishes on its own
+        let later = Coroutine::with_defaults(handle, || {
+            Coroutine::sleep(Duration::from_millis(100)).unwrap();
+        });
+        core.run(later).unwrap();
+        assert!(finished.get());
+    }
+
+    /// A signal sent to ourselves wakes the waiting coroutine.
+    #[cfg(all(unix, feature = "signal"))]
+    #[test]
+    fn wait_signal() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        // Keep a handler installed all the time, so raising the signal too early doesn't kill us.
+        let _guard = core.run(Signal::new(libc::SIGUSR2, &handle)).unwrap();
+        let woken = Rc::new(Cell::new(false));
+        let woken_cp = woken.clone();
+        let waiter = Coroutine::with_defaults(handle.clone(), move || {
+            Coroutine::wait_signal(libc::SIGUSR2).unwrap().unwrap();
+            woken_cp.set(true);
+        });
+        let woken_cp = woken.clone();
+        let raiser = Coroutine::with_defaults(handle, move || {
+            // Keep raising it until the waiter notices, in case it didn't register yet.
+            while !woken_cp.get() {
+                assert_eq!(0, unsafe { libc::raise(libc::SIGUSR2) });
+                Coroutine::sleep(Duration::from_millis(10)).unwrap();
+            }
+        });
+        core.run(raiser).unwrap();
+        core.run(waiter).unwrap();
+        assert!(woken.get());
+    }
+
+    thread_local! {
+        static LOGGED: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    /// A logger capturing the warnings of the current thread.
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                LOGGED.with(|l| l.borrow_mut().push(format!("{}", record.args())));
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger;
+
+    /// A slow poll of a future gets reported by the watchdog, the quick ones don't.
+    #[test]
+    fn poll_watchdog() {
+        // Only this test sets a logger, but don't insist on being the first.
+        let _ = log::set_logger(&CAPTURE_LOGGER);
+        log::set_max_level(log::LevelFilter::Warn);
+        Coroutine::enable_poll_watchdog(Duration::from_millis(10));
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            Coroutine::wait(future::ok::<_, ()>(1)).unwrap().unwrap();
+            let slow = future::lazy(|| {
+                thread::sleep(Duration::from_millis(50));
+                Ok::<_, ()>(2)
+            });
+            Coroutine::wait(slow).unwrap().unwrap()
+        });
+        assert_eq!(2, core.run(coroutine).unwrap());
+        Coroutine::disable_poll_watchdog();
+        let logged = LOGGED.with(|l| l.borrow().clone());
+        assert_eq!(1, logged.len());
+        assert!(logged[0].contains("blocking the reactor"));
+    }
+
+    /// The buffered futures come out in the order they resolve, with only limited number in
+    /// flight.
+    #[test]
+    fn buffered() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let active = Rc::new(Cell::new(0));
+            let max_active = Rc::new(Cell::new(0));
+            let futs = {
+                let active = active.clone();
+                let max_active = max_active.clone();
+                vec![300, 20, 40, 10].into_iter().map(move |ms| {
+                    active.set(active.get() + 1);
+                    max_active.set(max_active.get().max(active.get()));
+                    let active = active.clone();
+                    Timeout::new(Duration::from_millis(ms), &Coroutine::reactor())
+                        .unwrap()
+                        .map(move |()| {
+                            active.set(active.get() - 1);
+                            ms
+                        })
+                })
             };
-            let instruction = Switch::WaitFuture { task };
-            instruction.exchange(my_context.parent_context)
-        };
-        let (result, stack) = match reply_instruction {
-            Switch::Resume { stack } => (Ok(Ok(result.unwrap())), stack),
-            Switch::Cleanup { stack } => (Ok(Err(Dropped)), stack),
-            Switch::PropagateFuturePanic { stack, panic } => (Err(panic), stack),
-            _ => unreachable!("Invalid instruction on wakeup"),
-        };
-        // Reconstruct our context anew after we switched back.
-        let new_context = CoroutineContext {
-            parent_context: context,
-            stack: stack,
-            handle: my_context.handle,
-            cleanup_strategy: my_context.cleanup_strategy,
+            let order = Coroutine::buffered(futs, 2)
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+            (order, max_active.get())
+        });
+        // The 300 holds one slot the whole time, the rest go through the other one (and finish
+        // well before it).
+        assert_eq!((vec![20, 40, 10, 300], 2), core.run(coroutine).unwrap());
+    }
+
+    /// Futures processed with a limit on how many run at once.
+    #[test]
+    fn for_each_concurrent() {
+        let active = Rc::new(Cell::new(0));
+        let max_active = Rc::new(Cell::new(0));
+        let futs = |count: u64| {
+            let active = active.clone();
+            let max_active = max_active.clone();
+            (0..count).map(move |i| {
+                let active = active.clone();
+                let max_active = max_active.clone();
+                // Started by the reactor while the coroutine is switched out
+                future::lazy(move || {
+                    active.set(active.get() + 1);
+                    max_active.set(max_active.get().max(active.get()));
+                    let handle = Coroutine::current_handle().unwrap();
+                    Timeout::new(Duration::from_millis(10 + i % 7), &handle)
+                        .unwrap()
+                        .map(move |()| {
+                            active.set(active.get() - 1);
+                            i
+                        })
+                })
+            })
         };
-        CONTEXTS.with(|c| c.borrow_mut().push(new_context));
-        match result {
-            Ok(result) => result,
-            Err(panic) => panic::resume_unwind(panic),
+        let mut core = Core::new().unwrap();
+        let first = futs(20);
+        let all = Coroutine::with_defaults(core.handle(), move || {
+            let mut seen = Vec::new();
+            Coroutine::for_each_concurrent(first, 5, |i| {
+                seen.push(i);
+                Ok(())
+            }).unwrap();
+            seen.sort();
+            seen
+        });
+        assert_eq!((0..20).collect::<Vec<_>>(), core.run(all).unwrap());
+        assert_eq!(5, max_active.get());
+        assert_eq!(0, active.get());
+
+        // An error stops it early, without starting the rest.
+        let second = futs(20);
+        let stopped = Coroutine::with_defaults(core.handle(), move || {
+            let mut calls = 0;
+            let result = Coroutine::for_each_concurrent(second, 5, |_| {
+                calls += 1;
+                Err(IoError::new(ErrorKind::Other, "Stop"))
+            });
+            assert!(result.is_err());
+            calls
+        });
+        assert_eq!(1, core.run(stopped).unwrap());
+    }
+
+    /// The awaited future may spawn more coroutines from within its poll.
+    #[test]
+    fn wait_future_spawning() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), move || {
+            let spawning = future::lazy(move || {
+                Coroutine::with_defaults(handle, || {
+                    Coroutine::sleep(Duration::from_millis(10)).unwrap();
+                    42
+                })
+            });
+            Coroutine::wait(spawning).unwrap()
+        });
+        assert_eq!(42, core.run(coroutine).unwrap().unwrap());
+    }
+
+    /// Counts up to the target, one step per poll.
+    struct Counter {
+        count: u32,
+        target: u32,
+    }
+
+    impl Future for Counter {
+        type Item = u32;
+        type Error = ();
+        fn poll(&mut self) -> Poll<u32, ()> {
+            self.count += 1;
+            if self.count == self.target {
+                Ok(Async::Ready(self.count))
+            } else {
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::rc::Rc;
-    use std::time::Duration;
+    impl Stash for Counter {
+        type Checkpoint = u32;
+        fn stash(&self) -> u32 {
+            self.count
+        }
+    }
 
-    use futures::future;
-    use tokio_core::reactor::{Core, Timeout};
+    /// An interrupted wait leaves a checkpoint behind, which allows continuing later.
+    #[test]
+    fn wait_resumable() {
+        let mut core = Core::new().unwrap();
+        let counting = Coroutine::with_defaults(core.handle(), || {
+            Coroutine::wait_resumable(Counter {
+                count: 0,
+                target: 1_000_000,
+            })
+        });
+        for _ in 0..5 {
+            core.turn(Some(Duration::from_millis(0)));
+        }
+        counting.cancel();
+        let checkpoint = core.run(counting).unwrap().unwrap_err();
+        assert!(checkpoint > 0);
+        assert!(checkpoint < 1_000_000);
 
-    use super::*;
+        let polls = Rc::new(Cell::new(0));
+        let polls_cp = polls.clone();
+        let resumed = Coroutine::with_defaults(core.handle(), move || {
+            let mut counter = Counter {
+                count: checkpoint,
+                target: checkpoint + 3,
+            };
+            let counted = future::poll_fn(move || {
+                polls_cp.set(polls_cp.get() + 1);
+                counter.poll()
+            });
+            Coroutine::wait(counted).unwrap()
+        });
+        assert_eq!(Ok(checkpoint + 3), core.run(resumed).unwrap());
+        // It didn't start from the scratch
+        assert_eq!(3, polls.get());
+    }
 
-    /// Test spawning and execution of tasks.
+    /// Each poll of the future is reported to the callback.
     #[test]
-    fn spawn_some() {
+    fn wait_instrumented() {
         let mut core = Core::new().unwrap();
-        let s1 = Rc::new(AtomicBool::new(false));
-        let s2 = Rc::new(AtomicBool::new(false));
-        let s1c = s1.clone();
-        let s2c = s2.clone();
-        let handle = core.handle();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let mut remaining = 2;
+            let slow = future::poll_fn(move || -> Poll<u32, ()> {
+                if remaining == 0 {
+                    Ok(Async::Ready(42))
+                } else {
+                    remaining -= 1;
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            });
+            let mut polls = Vec::new();
+            let result = Coroutine::wait_instrumented(slow, |poll| polls.push(*poll));
+            (result, polls)
+        });
+        let (result, polls) = core.run(coroutine).unwrap();
+        assert_eq!(Ok(Ok(42)), result);
+        assert_eq!(vec![Ok(Async::NotReady), Ok(Async::NotReady), Ok(Async::Ready(42))], polls);
+    }
 
-        let mut builder = Coroutine::new(handle);
-        builder.stack_size(40960);
-        let builder_inner = builder.clone();
+    /// The polls needed to resolve the future are counted.
+    #[test]
+    fn wait_counted() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let mut remaining = 4;
+            let slow = future::poll_fn(move || -> Poll<u32, ()> {
+                if remaining == 0 {
+                    Ok(Async::Ready(42))
+                } else {
+                    remaining -= 1;
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            });
+            let slow = Coroutine::wait_counted(slow);
+            let quick = Coroutine::wait_counted(future::ok::<_, ()>(1));
+            (slow, quick)
+        });
+        let (slow, quick) = core.run(coroutine).unwrap();
+        assert_eq!((Ok(Ok(42)), 5), slow);
+        assert_eq!((Ok(Ok(1)), 1), quick);
+    }
 
-        let result = builder.spawn(move || {
-                let result = builder_inner.spawn(move || {
-                        s2c.store(true, Ordering::Relaxed);
-                        42
+    /// Values attached on the builder are visible inside the coroutine.
+    #[test]
+    fn context_value() {
+        #[derive(Debug, PartialEq)]
+        struct Config {
+            name: &'static str,
+        }
+
+        let mut core = Core::new().unwrap();
+        let mut builder = Coroutine::new(core.handle());
+        builder
+            .context_value(Config { name: "first" })
+            .context_value(42u32)
+            .context_value(Config { name: "second" });
+        let coroutine = builder.spawn(|| {
+            let config = Coroutine::get_context_value::<Config>().unwrap();
+            let number = Coroutine::get_context_value::<u32>().unwrap();
+            Coroutine::yield_now().unwrap();
+            // Still there after a wait
+            let again = Coroutine::get_context_value::<Config>().unwrap();
+            assert!(Rc::ptr_eq(&config, &again));
+            assert!(Coroutine::get_context_value::<u64>().is_none());
+            (config.name, *number)
+        }).unwrap();
+        assert_eq!(("second", 42), core.run(coroutine).unwrap());
+        assert!(Coroutine::get_context_value::<u32>().is_none());
+    }
+
+    /// Waiting on an inline poll function, borrowing from the coroutine.
+    #[test]
+    fn wait_poll_fn() {
+        let mut core = Core::new().unwrap();
+        let coroutine = Coroutine::with_defaults(core.handle(), || {
+            let mut polls = 0;
+            let result = Coroutine::wait_poll_fn(|| -> Poll<u32, ()> {
+                polls += 1;
+                if polls > 2 {
+                    Ok(Async::Ready(42))
+                } else {
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }
+            });
+            (result, polls)
+        });
+        assert_eq!((Ok(Ok(42)), 3), core.run(coroutine).unwrap());
+    }
+
+    /// Coroutines that are ready all the time take turns.
+    #[test]
+    fn fairness() {
+        let mut core = Core::new().unwrap();
+        let mut builder = Coroutine::new(core.handle());
+        builder.fairness(true);
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let coroutines = (0..4)
+            .map(|i| {
+                let progress = progress.clone();
+                builder.spawn(move || {
+                    for _ in 0..50 {
+                        Coroutine::wait(future::ok::<(), ()>(())).unwrap().unwrap();
+                        progress.borrow_mut().push(i);
+                    }
+                }).unwrap()
+            })
+            .collect::<Vec<_>>();
+        core.run(future::join_all(coroutines)).unwrap();
+
+        let progress = progress.borrow();
+        assert_eq!(200, progress.len());
+        let mut counts = [0; 4];
+        for &i in progress.iter() {
+            counts[i] += 1;
+            let max = counts.iter().max().unwrap();
+            let min = counts.iter().min().unwrap();
+            assert!(max - min <= 1, "Unfair progress {:?}", counts);
+        }
+    }
+
+    /// Of the coroutines ready at the same time, the one with higher priority is resumed first.
+    #[test]
+    fn priority_order() {
+        let mut core = Core::new().unwrap();
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut senders = Vec::new();
+        let mut results = Vec::new();
+        for &priority in &[1, 10, 5] {
+            let (sender, receiver) = oneshot::channel();
+            senders.push(sender);
+            let order = order.clone();
+            let result = Coroutine::new(core.handle())
+                .priority(priority)
+                .spawn(move || {
+                    Coroutine::wait(receiver).unwrap().unwrap();
+                    order.borrow_mut().push(priority);
+                })
+                .unwrap();
+            results.push(result);
+        }
+        // Make them all ready at once.
+        for sender in senders {
+            sender.send(()).unwrap();
+        }
+        core.run(future::join_all(results)).unwrap();
+        assert_eq!(vec![10, 5, 1], *order.borrow());
+    }
+
+    /// Coroutines yielding in the same turn come back in the order of their priorities.
+    #[test]
+    fn priority_yield() {
+        let mut core = Core::new().unwrap();
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let results = [1, 10, 5]
+            .iter()
+            .map(|&priority| {
+                let order = order.clone();
+                Coroutine::new(core.handle())
+                    .priority(priority)
+                    .spawn(move || {
+                        Coroutine::yield_now().unwrap();
+                        order.borrow_mut().push(priority);
                     })
-                    .unwrap();
-                s1c.store(true, Ordering::Relaxed);
-                result
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        core.run(future::join_all(results)).unwrap();
+        assert_eq!(vec![10, 5, 1], *order.borrow());
+    }
+
+    /// A supervised coroutine gets restarted until it succeeds.
+    #[cfg(not(corona_panic_abort))]
+    #[test]
+    fn supervised_restart() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let attempts_cp = attempts.clone();
+        let coroutine = Coroutine::new(core.handle())
+            .spawn_supervised(3, move || {
+                attempts_cp.set(attempts_cp.get() + 1);
+                Coroutine::wait(future::ok::<_, ()>(())).unwrap().unwrap();
+                if attempts_cp.get() <= 2 {
+                    panic!("Test");
+                }
+                42
             })
             .unwrap();
+        assert_eq!(42, core.run(coroutine).unwrap());
+        assert_eq!(3, attempts.get());
+    }
 
-        // Both coroutines run to finish
-        assert!(s1.load(Ordering::Relaxed), "The outer closure didn't run");
-        assert!(s2.load(Ordering::Relaxed), "The inner closure didn't run");
-        // The result gets propagated through.
-        let extract = result.and_then(|r| r);
-        assert_eq!(42, core.run(extract).unwrap());
+    /// When the restarts run out, the last panic is reported.
+    #[cfg(not(corona_panic_abort))]
+    #[test]
+    fn supervised_give_up() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let attempts_cp = attempts.clone();
+        let coroutine = Coroutine::new(core.handle())
+            .spawn_supervised(1, move || {
+                attempts_cp.set(attempts_cp.get() + 1);
+                panic!("Test");
+            })
+            .unwrap();
+        match core.run(coroutine) {
+            Err(TaskFailed::Panicked(_)) => (),
+            _ => panic!("Panic not reported properly"),
+        }
+        assert_eq!(2, attempts.get());
+        // All the incarnations are gone, including their closures
+        assert_eq!(1, Rc::strong_count(&attempts));
     }
 
-    /// Wait for a future to complete.
+    /// Spawns a lot of coroutines, parks them and cancels them by dropping the core, many times
+    /// over.
+    ///
+    /// The stacks must get reused, not leaked.
     #[test]
-    fn future_wait() {
+    fn spawn_cancel_churn() {
+        let round = || {
+            let core = Core::new().unwrap();
+            let finished = Rc::new(Cell::new(0));
+            for i in 0..50 {
+                let finished = finished.clone();
+                let mut builder = Coroutine::new(core.handle());
+                // Mix different stack sizes
+                builder.stack_size(4096 * (16 + i % 3));
+                builder.spawn(move || {
+                        // Some finish right away, most get parked forever
+                        if i % 5 != 0 {
+                            let _ = Coroutine::wait(future::empty::<(), ()>());
+                        }
+                        finished.set(finished.get() + 1);
+                    })
+                    .unwrap();
+            }
+            drop(core);
+            assert_eq!(50, finished.get());
+            assert_eq!(1, Rc::strong_count(&finished));
+        };
+        round();
+        let allocated = stack_cache::allocated();
+        for _ in 0..100 {
+            round();
+        }
+        assert_eq!(allocated, stack_cache::allocated());
+        assert_eq!(0, context_depth());
+    }
+
+    /// Two coroutines passing the control to each other directly.
+    #[test]
+    fn yield_to_ping_pong() {
         let mut core = Core::new().unwrap();
-        let handle = core.handle();
-        let (sender, receiver) = oneshot::channel();
-        let all_done = Coroutine::with_defaults(core.handle(), move || {
-            let msg = Coroutine::wait(receiver).unwrap().unwrap();
-            msg
+        let parked = Rc::new(RefCell::new(None));
+        let parked_cp = parked.clone();
+        let rounds = Rc::new(Cell::new(0));
+        let rounds_cp = rounds.clone();
+        let pong = Coroutine::with_defaults(core.handle(), move || {
+            let mut ping = Coroutine::suspend(|me| *parked_cp.borrow_mut() = Some(me)).unwrap();
+            while let Some(other) = ping.take() {
+                rounds_cp.set(rounds_cp.get() + 1);
+                ping = Coroutine::yield_to(other).unwrap();
+            }
         });
-        Coroutine::with_defaults(core.handle(), move || {
-            let timeout = Timeout::new(Duration::from_millis(50), &handle).unwrap();
+        let ping = Coroutine::with_defaults(core.handle(), move || {
+            let mut pong = parked.borrow_mut().take().unwrap();
+            for _ in 0..100 {
+                pong = Coroutine::yield_to(pong).unwrap().unwrap();
+            }
+            // Let it terminate
+            pong.resume();
+        });
+        core.run(ping).unwrap();
+        core.run(pong).unwrap();
+        assert_eq!(100, rounds.get());
+        assert_eq!(0, context_depth());
+    }
+
+    /// Registering a timeout from the reactor context and waiting for it afterwards.
+    #[test]
+    fn reactor_context_timeout() {
+        let result = Coroutine::run(|| {
+            let timeout = Coroutine::with_reactor_context(|handle| {
+                // We are outside of the coroutine now
+                assert_eq!(0, context_depth());
+                Timeout::new(Duration::from_millis(10), handle).unwrap()
+            });
+            assert_eq!(1, context_depth());
             Coroutine::wait(timeout).unwrap().unwrap();
-            sender.send(42).unwrap();
+            42
         });
-        assert_eq!(42, core.run(all_done).unwrap());
+        assert_eq!(42, result.unwrap());
+    }
+
+    /// A panic in the reactor context closure ends up in the coroutine.
+    #[cfg(not(corona_panic_abort))]
+    #[test]
+    fn reactor_context_panic() {
+        let mut core = Core::new().unwrap();
+        let result = Coroutine::new(core.handle())
+            .spawn_catch_panic(|| {
+                Coroutine::with_reactor_context(|_| panic!("Test"));
+            })
+            .unwrap();
+        match core.run(result) {
+            Err(TaskFailed::Panicked(_)) => (),
+            _ => panic!("The panic didn't get through"),
+        }
+        assert_eq!(0, context_depth());
+    }
+
+    /// Only the stacks that are idle for long enough get trimmed.
+    #[test]
+    fn trim_stack_pool() {
+        Coroutine::stack_pool_idle(Some(Duration::from_millis(50)));
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        // Fill the pool with stacks of coroutines running at the same time
+        let coroutines = (0..3)
+            .map(|_| {
+                Coroutine::with_defaults(handle.clone(), || {
+                    Coroutine::sleep(Duration::from_millis(10)).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        core.run(future::join_all(coroutines)).unwrap();
+        assert_eq!(0, Coroutine::trim_stack_pool());
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(3, Coroutine::trim_stack_pool());
+        assert_eq!(0, Coroutine::trim_stack_pool());
+        Coroutine::stack_pool_idle(None);
+    }
+
+    /// Spawning onto a reactor that is already gone is an error, not a panic.
+    #[test]
+    fn spawn_dead_reactor() {
+        let handle = Core::new().unwrap().handle();
+        let builder = Coroutine::new(handle);
+        match builder.spawn(|| ()) {
+            Err(SpawnError::ReactorGone) => (),
+            _ => panic!("Spawned onto a dead reactor"),
+        }
+        match builder.lazy(|| ()) {
+            Err(SpawnError::ReactorGone) => (),
+            _ => panic!("Prepared a coroutine for a dead reactor"),
+        }
+        assert_eq!(0, context_depth());
+    }
+
+    /// Dropping the handle of a suspended coroutine cleans it up.
+    #[test]
+    fn suspend_dropped() {
+        let mut core = Core::new().unwrap();
+        let parked = Rc::new(RefCell::new(None));
+        let parked_cp = parked.clone();
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            Coroutine::suspend(|me| *parked_cp.borrow_mut() = Some(me)).map(|_| ())
+        });
+        drop(parked.borrow_mut().take());
+        assert_eq!(Err(Dropped), core.run(result).unwrap());
+    }
+
+    /// Resuming or dropping the handle from within the hook is postponed until the hook returns.
+    #[test]
+    fn suspend_inside_hook() {
+        let mut core = Core::new().unwrap();
+        let resumed = Coroutine::with_defaults(core.handle(), || {
+            let mut order = Vec::new();
+            let result = Coroutine::suspend(|me| {
+                me.resume();
+                order.push("hook");
+            });
+            order.push("resumed");
+            (result.map(|handle| handle.is_none()), order)
+        });
+        assert_eq!((Ok(true), vec!["hook", "resumed"]), core.run(resumed).unwrap());
+        let dropped = Coroutine::with_defaults(core.handle(), || {
+            Coroutine::suspend(drop).map(|_| ())
+        });
+        assert_eq!(Err(Dropped), core.run(dropped).unwrap());
+        assert_eq!(0, context_depth());
+    }
+
+    fn context_depth() -> usize {
+        CONTEXTS.with(|c| c.borrow().len())
+    }
+
+    /// Spawns a tree of coroutines, each waiting for its children.
+    ///
+    /// Every time we get resumed from the reactor, our context must be the only one there.
+    fn nested_tree(handle: Handle, depth: u32) -> u32 {
+        if depth == 0 {
+            Coroutine::wait(future::ok::<_, ()>(())).unwrap().unwrap();
+            assert_eq!(1, context_depth());
+            return 1;
+        }
+        let children = (0..3)
+            .map(|_| {
+                let handle_cp = handle.clone();
+                Coroutine::with_defaults(handle.clone(), move || nested_tree(handle_cp, depth - 1))
+            })
+            .collect::<Vec<_>>();
+        let mut sum = 1;
+        for child in children {
+            sum += Coroutine::wait(child).unwrap().unwrap();
+            assert_eq!(1, context_depth());
+        }
+        sum
+    }
+
+    /// Deeply nested coroutines waiting on each other keep the context stack balanced.
+    #[test]
+    fn nested_waits_balanced() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let tree = Coroutine::with_defaults(core.handle(), move || nested_tree(handle, 4));
+        assert_eq!(0, context_depth());
+        // 1 + 3 + 9 + 27 + 81 coroutines, each contributing one
+        assert_eq!(121, core.run(tree).unwrap());
+        assert_eq!(0, context_depth());
+    }
+
+    /// Panics falling out of nested waits don't leave stray contexts behind.
+    #[cfg(not(corona_panic_abort))]
+    #[test]
+    fn nested_panics_balanced() {
+        let mut core = Core::new().unwrap();
+        let builder = Coroutine::new(core.handle());
+        let inner_builder = builder.clone();
+        let outer = builder.spawn_catch_panic(move || {
+                let inner = inner_builder.spawn_catch_panic(|| {
+                        Coroutine::wait(future::ok::<_, ()>(())).unwrap().unwrap();
+                        panic!("Test");
+                    })
+                    .unwrap();
+                match Coroutine::wait(inner).unwrap() {
+                    Err(TaskFailed::Panicked(_)) => (),
+                    _ => panic!("Inner panic not reported"),
+                }
+                assert_eq!(1, context_depth());
+            })
+            .unwrap();
+        core.run(outer).unwrap();
+        assert_eq!(0, context_depth());
     }
 
     /// The panic doesn't kill the main thread, but is reported.
+    #[cfg(not(corona_panic_abort))]
     #[test]
     fn panics_catch() {
         let mut core = Core::new().unwrap();
@@ -525,6 +7091,7 @@ mod tests {
     }
 
     /// However, normal coroutines do panic.
+    #[cfg(not(corona_panic_abort))]
     #[test]
     #[should_panic]
     fn panics_spawn() {
@@ -533,6 +7100,7 @@ mod tests {
     }
 
     /// This one panics and the panic is propagated, but after suspension point it is out of run.
+    #[cfg(not(corona_panic_abort))]
     #[test]
     fn panics_run() {
         let mut core = Core::new().unwrap();
@@ -543,6 +7111,16 @@ mod tests {
         panic::catch_unwind(AssertUnwindSafe(|| core.run(coroutine))).unwrap_err();
     }
 
+    /// With `panic = "abort"`, the task is run directly, without catching panics.
+    #[cfg(corona_panic_abort)]
+    #[test]
+    fn no_catch() {
+        match run_task(|| 42, true) {
+            (TaskResult::Finished(42), None) => (),
+            _ => panic!("Task not run directly"),
+        }
+    }
+
     /// It's impossible to wait on a future outside of a coroutine
     #[test]
     #[should_panic]
@@ -4,6 +4,8 @@ use std::any::Any;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 
+use futures::future::SharedError;
+
 pub use context::stack::StackError;
 
 /// An error marker when a future is dropped before having change to get resolved.
@@ -44,7 +46,7 @@ pub enum TaskFailed {
     /// The coroutine was lost.
     ///
     /// This can happen in case the `tokio_core::reactor::Core` the coroutine was spawned onto was
-    /// dropped before the coroutine completed.
+    /// dropped before the coroutine completed, or if the coroutine was cancelled.
     ///
     /// Technically, the coroutine panicked, but this special panic is handled differently.
     Lost,
@@ -60,7 +62,268 @@ impl Error for TaskFailed {
 }
 
 impl Display for TaskFailed {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let TaskFailed::Panicked(ref panic) = *self {
+            if let Some(labeled) = panic.downcast_ref::<LabeledPanic>() {
+                return write!(f, "{} while waiting for {}", self.description(),
+                              labeled.trail.join(" > "));
+            }
+        }
+        write!(f, "{}", self.description())
+    }
+}
+
+/// A panic that happened during a labeled wait.
+///
+/// When a coroutine panics while waiting through
+/// [`Coroutine::wait_labeled`](../coroutine/struct.Coroutine.html#method.wait_labeled), the
+/// panic continues with this payload, wrapping the original one. This is also what ends up in
+/// [`TaskFailed::Panicked`](enum.TaskFailed.html#variant.Panicked).
+#[derive(Debug)]
+pub struct LabeledPanic {
+    /// The labels of the waits the panic went through, the outermost first.
+    pub trail: Vec<&'static str>,
+    /// The original payload of the panic.
+    pub payload: Box<Any + Send + 'static>,
+}
+
+/// The reason why waiting with a timeout didn't produce a result.
+///
+/// This is returned by
+/// [`Coroutine::wait_timeout`](../coroutine/struct.Coroutine.html#method.wait_timeout) and
+/// similar methods.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum WaitTimeout {
+    /// The time ran out before the future resolved.
+    ///
+    /// The future was dropped in such case.
+    TimedOut,
+    /// The reactor was dropped before the future resolved.
+    ///
+    /// This corresponds to the [`Dropped`](struct.Dropped.html) error of other waiting methods.
+    Dropped,
+}
+
+impl Error for WaitTimeout {
+    fn description(&self) -> &str {
+        match *self {
+            WaitTimeout::TimedOut => "The time ran out before the future resolved",
+            WaitTimeout::Dropped => Dropped.description(),
+        }
+    }
+}
+
+impl Display for WaitTimeout {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl From<Dropped> for WaitTimeout {
+    fn from(_: Dropped) -> Self {
+        WaitTimeout::Dropped
+    }
+}
+
+/// The reason why waiting with a poll budget didn't produce a result.
+///
+/// This is returned by
+/// [`Coroutine::wait_bounded`](../coroutine/struct.Coroutine.html#method.wait_bounded).
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum WaitAborted {
+    /// The future was polled the allowed number of times without resolving.
+    ///
+    /// The future was dropped in such case.
+    PollBudgetExceeded,
+    /// The reactor was dropped before the future resolved.
+    ///
+    /// This corresponds to the [`Dropped`](struct.Dropped.html) error of other waiting methods.
+    Dropped,
+}
+
+impl Error for WaitAborted {
+    fn description(&self) -> &str {
+        match *self {
+            WaitAborted::PollBudgetExceeded => "The future didn't resolve within its poll budget",
+            WaitAborted::Dropped => Dropped.description(),
+        }
+    }
+}
+
+impl Display for WaitAborted {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}", self.description())
     }
 }
+
+impl From<Dropped> for WaitAborted {
+    fn from(_: Dropped) -> Self {
+        WaitAborted::Dropped
+    }
+}
+
+/// The reason why a coroutine couldn't be spawned.
+#[derive(Debug)]
+pub enum SpawnError {
+    /// The stack couldn't be allocated (eg. the configured stack size is invalid).
+    Stack {
+        /// The error from allocating the stack.
+        err: StackError,
+        /// The requested size of the stack.
+        size: usize,
+    },
+    /// The reactor the coroutine would run on is no longer running.
+    ///
+    /// This happens when spawning through a handle to an already dropped
+    /// `tokio_core::reactor::Core`.
+    ReactorGone,
+    /// The coroutines on this thread are being shut down.
+    ///
+    /// See
+    /// [`Coroutine::shutdown_graceful`](../coroutine/struct.Coroutine.html#method.shutdown_graceful).
+    ShuttingDown,
+}
+
+impl Error for SpawnError {
+    fn description(&self) -> &str {
+        match *self {
+            SpawnError::Stack { .. } => "Failed to allocate the stack of the coroutine",
+            SpawnError::ReactorGone => "The reactor is no longer running",
+            SpawnError::ShuttingDown => "The coroutines are being shut down",
+        }
+    }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SpawnError::Stack { ref err, .. } => Some(err),
+            SpawnError::ReactorGone | SpawnError::ShuttingDown => None,
+        }
+    }
+}
+
+impl Display for SpawnError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            SpawnError::Stack { ref err, size } => {
+                write!(f, "{} of {} bytes (too large for the system limits): {}",
+                       self.description(), size, err)
+            },
+            SpawnError::ReactorGone | SpawnError::ShuttingDown => {
+                write!(f, "{}", self.description())
+            },
+        }
+    }
+}
+
+/// The stack size is rejected by the builder.
+///
+/// This is returned by
+/// [`Coroutine::try_stack_size`](../coroutine/struct.Coroutine.html#method.try_stack_size) and
+/// [`Coroutine::set_profile_size`](../coroutine/struct.Coroutine.html#method.set_profile_size).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InvalidStackSize {
+    /// The rejected size.
+    pub size: usize,
+    /// The page size of the system, the stack size must be a multiple of it.
+    pub page_size: usize,
+}
+
+impl Error for InvalidStackSize {
+    fn description(&self) -> &str {
+        "The stack size must be a non-zero multiple of the page size"
+    }
+}
+
+impl Display for InvalidStackSize {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Invalid stack size {} (it must be a non-zero multiple of the page size {})",
+               self.size, self.page_size)
+    }
+}
+
+/// The reason why waiting for a shared future didn't produce a value.
+///
+/// This is returned by
+/// [`Coroutine::wait_shared`](../coroutine/struct.Coroutine.html#method.wait_shared).
+#[derive(Debug)]
+pub enum SharedFailed<E> {
+    /// The shared future resolved with an error.
+    Failed(SharedError<E>),
+    /// The reactor was dropped before the future resolved.
+    Dropped,
+}
+
+impl<E: Error> Error for SharedFailed<E> {
+    fn description(&self) -> &str {
+        match *self {
+            SharedFailed::Failed(ref err) => err.description(),
+            SharedFailed::Dropped => Dropped.description(),
+        }
+    }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SharedFailed::Failed(ref err) => Some(err),
+            SharedFailed::Dropped => None,
+        }
+    }
+}
+
+impl<E: Display> Display for SharedFailed<E> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            SharedFailed::Failed(ref err) => write!(f, "{}", err),
+            SharedFailed::Dropped => write!(f, "{}", Dropped),
+        }
+    }
+}
+
+impl<E> From<Dropped> for SharedFailed<E> {
+    fn from(_: Dropped) -> Self {
+        SharedFailed::Dropped
+    }
+}
+
+/// The reason why pumping a stream into a sink failed.
+///
+/// This is returned by [`Coroutine::pump`](../coroutine/struct.Coroutine.html#method.pump).
+#[derive(Debug, Eq, PartialEq)]
+pub enum PumpError<S, K> {
+    /// The stream produced an error.
+    Stream(S),
+    /// The sink refused an item or failed to flush.
+    Sink(K),
+    /// The reactor was dropped before the pumping was done.
+    Dropped,
+}
+
+impl<S: Error, K: Error> Error for PumpError<S, K> {
+    fn description(&self) -> &str {
+        match *self {
+            PumpError::Stream(ref err) => err.description(),
+            PumpError::Sink(ref err) => err.description(),
+            PumpError::Dropped => Dropped.description(),
+        }
+    }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PumpError::Stream(ref err) => Some(err),
+            PumpError::Sink(ref err) => Some(err),
+            PumpError::Dropped => None,
+        }
+    }
+}
+
+impl<S: Display, K: Display> Display for PumpError<S, K> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            PumpError::Stream(ref err) => write!(f, "Stream failed: {}", err),
+            PumpError::Sink(ref err) => write!(f, "Sink failed: {}", err),
+            PumpError::Dropped => write!(f, "{}", Dropped),
+        }
+    }
+}
+
+impl<S, K> From<Dropped> for PumpError<S, K> {
+    fn from(_: Dropped) -> Self {
+        PumpError::Dropped
+    }
+}
@@ -0,0 +1,201 @@
+//! A single-threaded executor owning the reactor core.
+
+use std::cell::{Cell, RefCell};
+use std::io::Error as IoError;
+use std::rc::Rc;
+
+use futures::{task, Async, Future, Poll};
+use futures::task::Task;
+use tokio_core::reactor::{Core, Handle};
+
+use coroutine::{Coroutine, CoroutineResult};
+use errors::SpawnError;
+
+/// Keeps track of the coroutines spawned through the executor.
+#[derive(Default)]
+struct Tracker {
+    alive: Cell<usize>,
+    waiting: RefCell<Option<Task>>,
+}
+
+/// Marks one coroutine alive for as long as it exists.
+///
+/// It lives inside the closure of the coroutine, so it goes away both when the coroutine finishes
+/// and when it gets dropped without ever running.
+struct Alive(Rc<Tracker>);
+
+impl Alive {
+    fn new(tracker: &Rc<Tracker>) -> Self {
+        tracker.alive.set(tracker.alive.get() + 1);
+        Alive(tracker.clone())
+    }
+}
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        let alive = self.0.alive.get() - 1;
+        self.0.alive.set(alive);
+        if alive == 0 {
+            if let Some(task) = self.0.waiting.borrow_mut().take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// Resolves once all the tracked coroutines are gone.
+struct AllDone(Rc<Tracker>);
+
+impl Future for AllDone {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.0.alive.get() == 0 {
+            Ok(Async::Ready(()))
+        } else {
+            *self.0.waiting.borrow_mut() = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A single-threaded executor, owning a reactor core and spawning coroutines onto it.
+///
+/// Most applications end up creating a `Core`, getting its `Handle`, configuring a
+/// [`Coroutine`](struct.Coroutine.html) builder and spawning coroutines with it. The executor puts
+/// these together into one object. It can also run the reactor until all the coroutines spawned
+/// through it finish.
+///
+/// Using it is optional, the `Core` and the builder work the same without it.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate corona;
+/// use std::time::Duration;
+///
+/// use corona::{Coroutine, Executor};
+///
+/// # fn main() {
+/// let mut executor = Executor::new().unwrap();
+/// for i in 0..3 {
+///     executor.spawn(move || {
+///         Coroutine::sleep(Duration::from_millis(10 * i)).unwrap();
+///     }).unwrap();
+/// }
+/// executor.run();
+/// # }
+/// ```
+pub struct Executor {
+    core: Core,
+    builder: Coroutine,
+    tracker: Rc<Tracker>,
+}
+
+impl Executor {
+    /// Creates an executor with a new reactor core.
+    pub fn new() -> Result<Self, IoError> {
+        let core = Core::new()?;
+        let builder = Coroutine::new(core.handle());
+        Ok(Executor {
+            core,
+            builder,
+            tracker: Rc::new(Tracker::default()),
+        })
+    }
+
+    /// Returns a handle to the reactor core.
+    pub fn handle(&self) -> Handle {
+        self.core.handle()
+    }
+
+    /// Gives access to the builder used to spawn the coroutines.
+    ///
+    /// This allows configuring them, for example setting the stack size.
+    pub fn builder(&mut self) -> &mut Coroutine {
+        &mut self.builder
+    }
+
+    /// Spawns a coroutine.
+    ///
+    /// The coroutine is spawned with the [`builder`](#method.builder) and it starts right away,
+    /// the same as with [`Coroutine::spawn`](struct.Coroutine.html#method.spawn). It is tracked
+    /// by [`run`](#method.run).
+    pub fn spawn<R, Task>(&self, task: Task) -> Result<CoroutineResult<R>, SpawnError>
+    where
+        R: 'static,
+        Task: FnOnce() -> R + 'static,
+    {
+        let alive = Alive::new(&self.tracker);
+        self.builder.spawn(move || {
+            let _alive = alive;
+            task()
+        })
+    }
+
+    /// Runs the reactor until the future resolves and returns its result.
+    ///
+    /// The future is run outside of any coroutine, so it must not use the waiting methods of
+    /// [`Coroutine`](struct.Coroutine.html). Everything else on the reactor is driven meanwhile.
+    pub fn block_on<Fut: Future>(&mut self, fut: Fut) -> Result<Fut::Item, Fut::Error> {
+        self.core.run(fut)
+    }
+
+    /// Runs the reactor until all the coroutines spawned through the executor finish.
+    ///
+    /// Coroutines spawned directly onto the [`handle`](#method.handle) are not waited for.
+    pub fn run(&mut self) {
+        self.core
+            .run(AllDone(self.tracker.clone()))
+            .expect("Waiting for coroutines never fails");
+    }
+
+    /// Consumes the executor and returns the reactor core inside.
+    pub fn into_core(self) -> Core {
+        self.core
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::unsync::oneshot;
+    use tokio_core::reactor::Timeout;
+
+    use super::*;
+
+    /// Spawning coroutines, one from within another, through the executor.
+    #[test]
+    fn spawn_some() {
+        let mut executor = Executor::new().unwrap();
+        executor.builder().stack_size(40960);
+        let handle = executor.handle();
+        let done = Rc::new(Cell::new(0));
+        let done_cp = done.clone();
+        let result = executor.spawn(move || {
+            let inner = Coroutine::new(handle).spawn(move || 42).unwrap();
+            done_cp.set(done_cp.get() + 1);
+            inner
+        }).unwrap();
+        let inner = executor.block_on(result).unwrap();
+        assert_eq!(42, executor.block_on(inner).unwrap());
+        assert_eq!(1, done.get());
+    }
+
+    /// Coroutines waiting for each other, then running until all are done.
+    #[test]
+    fn future_wait() {
+        let mut executor = Executor::new().unwrap();
+        let handle = executor.handle();
+        let (sender, receiver) = oneshot::channel();
+        let all_done = executor.spawn(move || Coroutine::wait(receiver).unwrap().unwrap()).unwrap();
+        executor.spawn(move || {
+            let timeout = Timeout::new(Duration::from_millis(50), &handle).unwrap();
+            Coroutine::wait(timeout).unwrap().unwrap();
+            sender.send(42).unwrap();
+        }).unwrap();
+        executor.run();
+        assert_eq!(42, executor.block_on(all_done).unwrap());
+    }
+}
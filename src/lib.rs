@@ -138,6 +138,24 @@
 //! There are waiting methods that return an error instead of panicking, but they are less
 //! convenient to use.
 //!
+//! ## Aborting panics
+//!
+//! If the application is compiled with `panic = "abort"`, there are never any panics to catch.
+//! The catching of panics around the coroutines is then only an overhead and is left out
+//! (detected by the build script, on compilers that tell it the panic strategy ‒ older ones keep
+//! the catching). Coroutines never report `TaskFailed::Panicked` in such case.
+//!
+//! Note that the cleanup of coroutines described above relies on panics, therefore it can't
+//! happen in such builds.
+//!
+//! ## Tracing
+//!
+//! With the `tracing` feature, each coroutine gets a span of the `tracing` crate. The span is
+//! entered whenever the coroutine is resumed and exited whenever it switches out (to wait for
+//! something, for example), so the active span reflects the coroutine that is running. Its
+//! spawning, completion and panics are reported as events. Note that the feature needs a much
+//! newer compiler than the rest of the crate.
+//!
 //! # Pitfalls
 //!
 //! If the coroutine is created with default configuration, it gets really small stack. If you
@@ -155,8 +173,8 @@
 //!
 //! These are the problems I'm aware of and which I want to find a solution some day.
 //!
-//! * Many handy abstractions are still missing, like waiting for a future with a timeout, or
-//!   conveniently waiting for a first of a set of futures or streams.
+//! * Many handy abstractions are still missing, like conveniently waiting for a first of a set of
+//!   futures or streams.
 //! * A global configuration for the whole coroutine to leak on panic might be a convenient
 //!   solution.
 //! * The coroutines can't move between threads. This is likely impossible, since Rust's type
@@ -265,19 +283,49 @@
 
 extern crate context;
 extern crate futures;
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate tokio_core;
 #[cfg(feature = "blocking-wrappers")]
 extern crate tokio_io;
+#[cfg(feature = "signal")]
+extern crate tokio_signal;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[macro_use]
+mod macros;
 
 #[cfg(feature = "blocking-wrappers")]
 pub mod io;
 pub mod errors;
 pub mod prelude;
+pub mod rendezvous;
+pub mod rwlock;
+pub mod testing;
 pub mod wrappers;
 
 mod coroutine;
+mod executor;
+mod pool;
+mod reactor;
+mod scheduler;
+mod set;
 mod stack_cache;
+mod supervisor;
 mod switch;
+mod trace;
 
-pub use errors::{Dropped, TaskFailed};
-pub use coroutine::{Coroutine, CoroutineResult};
+pub use errors::{Dropped, InvalidStackSize, LabeledPanic, PumpError, SharedFailed, SpawnError,
+                 TaskFailed, WaitAborted, WaitTimeout};
+pub use coroutine::{CancelToken, CoNotify, CompletionInfo, CompletionStatus, Coroutine,
+                    CoroutineHandle, CoroutineResult, Deadline, GracefulTimeout, LazyCoroutine,
+                    ReactorStats, ShutdownReport, SpawnHandle, StackProfile, Started, Stash,
+                    WaitInProgress, WaitOutcome, WithTimeout};
+pub use executor::Executor;
+pub use pool::CoroutinePool;
+pub use reactor::ReactorLike;
+pub use set::CoroutineSet;
+pub use supervisor::Supervisor;
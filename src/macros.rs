@@ -0,0 +1,54 @@
+//! Syntactic sugar for the common operations.
+
+/// Waits for a future inside a coroutine, propagating its error with `?`.
+///
+/// Waiting for a future results in two layers of `Result`s ‒ one for the reactor being dropped and
+/// one for the future's own error (see [`Coroutine::wait`](struct.Coroutine.html#method.wait)).
+/// This macro peels both of them, which makes longer coroutine bodies much more readable.
+///
+/// * `co_await!(fut)` panics if the reactor got dropped (which is what usually happens with the
+///   other waiting methods too) and propagates the error of the future with `?`.
+/// * `co_await!(fut, dropped => err)` returns `Err(err)` (converted with `From`) instead of
+///   panicking when the reactor got dropped.
+///
+/// As it uses `?`, it must be used inside something returning a `Result` ‒ usually the closure
+/// passed to [`spawn`](struct.Coroutine.html#method.spawn).
+///
+/// # Panics
+///
+/// If used outside of a coroutine or, in the first form, if the reactor got dropped.
+///
+/// # Examples
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate corona;
+/// extern crate futures;
+/// extern crate tokio_core;
+///
+/// use corona::Coroutine;
+/// use futures::future;
+/// use tokio_core::reactor::Core;
+///
+/// # fn main() {
+/// let mut core = Core::new().unwrap();
+/// let coroutine = Coroutine::with_defaults(core.handle(), || -> Result<u32, String> {
+///     let a = co_await!(future::ok::<_, String>(20));
+///     let b = co_await!(future::ok::<_, String>(22), dropped => "Reactor gone");
+///     Ok(a + b)
+/// });
+/// assert_eq!(Ok(42), core.run(coroutine).unwrap());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! co_await {
+    ($fut: expr) => {
+        $crate::Coroutine::wait($fut).expect("The reactor got dropped")?
+    };
+    ($fut: expr, dropped => $dropped: expr) => {
+        match $crate::Coroutine::wait($fut) {
+            Ok(result) => result?,
+            Err(_) => return Err(::std::convert::From::from($dropped)),
+        }
+    };
+}
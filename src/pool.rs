@@ -0,0 +1,243 @@
+//! A pool of coroutines reused for many short jobs.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use futures::{task, Async, Future, Poll};
+use futures::future::{self, JoinAll};
+use futures::task::Task;
+
+use coroutine::{Coroutine, CoroutineResult};
+use errors::SpawnError;
+
+type Job = Box<FnMut()>;
+
+#[derive(Default)]
+struct State {
+    jobs: VecDeque<Job>,
+    /// The task of each worker, while it waits for a job.
+    waiting: Vec<Option<Task>>,
+    /// The indices of the waiting workers, in the order they started waiting.
+    idle: VecDeque<usize>,
+    closed: bool,
+}
+
+impl State {
+    /// Takes the task of the worker that waits the longest.
+    fn pop_idle(&mut self) -> Option<Task> {
+        let worker = self.idle.pop_front()?;
+        self.waiting[worker].take()
+    }
+}
+
+type StateRef = Rc<RefCell<State>>;
+
+/// Resolves to the next job for a worker, or to `None` once the pool is closed and drained.
+struct NextJob {
+    state: StateRef,
+    worker: usize,
+}
+
+impl Future for NextJob {
+    type Item = Option<Job>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Job>, ()> {
+        let mut state = self.state.borrow_mut();
+        let next = if let Some(job) = state.jobs.pop_front() {
+            Some(job)
+        } else if state.closed {
+            None
+        } else {
+            // Register only once, repeated polls just refresh the task.
+            let worker = self.worker;
+            let registered = state.waiting[worker].is_some();
+            let current = state.waiting[worker]
+                .as_ref()
+                .map_or(false, Task::will_notify_current);
+            if !current {
+                state.waiting[worker] = Some(task::current());
+            }
+            if !registered {
+                state.idle.push_back(worker);
+            }
+            return Ok(Async::NotReady);
+        };
+        // Woken up by something else than the dispatch, no longer idle.
+        if state.waiting[self.worker].take().is_some() {
+            let worker = self.worker;
+            state.idle.retain(|&idle| idle != worker);
+        }
+        Ok(Async::Ready(next))
+    }
+}
+
+fn worker(state: StateRef, index: usize) {
+    loop {
+        let next = NextJob {
+            state: state.clone(),
+            worker: index,
+        };
+        let job = Coroutine::wait(next);
+        match job {
+            Ok(Ok(Some(mut job))) => job(),
+            // Closed or the reactor is gone
+            _ => return,
+        }
+    }
+}
+
+/// A fixed set of coroutines running jobs handed to them.
+///
+/// Spawning a coroutine is cheap, but not free. For bursts of many small jobs, it may be
+/// better to spawn a few worker coroutines up front and let them take the jobs one by one. The
+/// jobs [dispatched](#method.dispatch) to the pool are queued and each idle worker picks the next
+/// one.
+///
+/// The workers live until the pool is dropped (or [finished](#method.finish)), after which they
+/// run the jobs still in the queue and terminate. A panicking job takes its worker down with it.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate corona;
+/// # extern crate tokio_core;
+/// use std::cell::Cell;
+/// use std::rc::Rc;
+///
+/// use corona::{Coroutine, CoroutinePool};
+/// use tokio_core::reactor::Core;
+///
+/// # fn main() {
+/// let mut core = Core::new().unwrap();
+/// let pool = CoroutinePool::new(&Coroutine::new(core.handle()), 2).unwrap();
+/// let sum = Rc::new(Cell::new(0));
+/// for i in 0..10 {
+///     let sum = sum.clone();
+///     pool.dispatch(move || sum.set(sum.get() + i));
+/// }
+/// core.run(pool.finish()).unwrap();
+/// assert_eq!(45, sum.get());
+/// # }
+/// ```
+pub struct CoroutinePool {
+    state: StateRef,
+    workers: Vec<CoroutineResult<()>>,
+}
+
+impl CoroutinePool {
+    /// Spawns `size` worker coroutines with the builder.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is 0.
+    pub fn new(builder: &Coroutine, size: usize) -> Result<Self, SpawnError> {
+        assert!(size > 0, "A pool with no workers can't run anything");
+        let state = StateRef::default();
+        state.borrow_mut().waiting = vec![None; size];
+        let workers = (0..size)
+            .map(|index| {
+                let state = state.clone();
+                builder.spawn(move || worker(state, index))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(CoroutinePool { state, workers })
+    }
+
+    /// How many worker coroutines the pool has.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Hands a job to the pool.
+    ///
+    /// The job is queued and an idle worker (if there's any) is woken up to take it. It runs
+    /// inside the worker coroutine, so it may wait for things.
+    pub fn dispatch<F: FnOnce() + 'static>(&self, job: F) {
+        let mut job = Some(job);
+        let idle = {
+            let mut state = self.state.borrow_mut();
+            state.jobs.push_back(Box::new(move || {
+                job.take().expect("Job run twice")()
+            }));
+            state.pop_idle()
+        };
+        if let Some(idle) = idle {
+            idle.notify();
+        }
+    }
+
+    /// Closes the pool.
+    ///
+    /// The returned future resolves once the workers have run all the queued jobs and terminated.
+    /// It fails if any of them panicked.
+    pub fn finish(mut self) -> JoinAll<Vec<CoroutineResult<()>>> {
+        let workers = self.workers.drain(..).collect::<Vec<_>>();
+        drop(self);
+        future::join_all(workers)
+    }
+}
+
+impl Drop for CoroutinePool {
+    fn drop(&mut self) {
+        let idle = {
+            let mut state = self.state.borrow_mut();
+            state.closed = true;
+            state.idle.clear();
+            state.waiting.iter_mut().filter_map(Option::take).collect::<Vec<_>>()
+        };
+        for idle in idle {
+            idle.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// Many jobs go through a few workers.
+    #[test]
+    fn dispatch_many() {
+        let mut core = Core::new().unwrap();
+        let pool = CoroutinePool::new(&Coroutine::new(core.handle()), 4).unwrap();
+        assert_eq!(4, pool.size());
+        let done = Rc::new(Cell::new(0));
+        for _ in 0..100 {
+            let done = done.clone();
+            pool.dispatch(move || {
+                Coroutine::sleep(Duration::from_millis(1)).unwrap();
+                done.set(done.get() + 1);
+            });
+        }
+        core.run(pool.finish()).unwrap();
+        assert_eq!(100, done.get());
+    }
+    /// A worker polled repeatedly while waiting is registered as idle only once.
+    #[test]
+    fn idle_registered_once() {
+        let mut core = Core::new().unwrap();
+        let state = StateRef::default();
+        state.borrow_mut().waiting = vec![None; 2];
+        let mut next = NextJob {
+            state: state.clone(),
+            worker: 1,
+        };
+        core.run(future::lazy(|| {
+            for _ in 0..3 {
+                assert!(next.poll().unwrap().is_not_ready());
+            }
+            Ok::<_, ()>(())
+        })).unwrap();
+        assert_eq!(vec![1], state.borrow().idle.iter().cloned().collect::<Vec<_>>());
+        state.borrow_mut().jobs.push_back(Box::new(|| ()));
+        core.run(future::lazy(|| next.poll())).unwrap();
+        assert!(state.borrow().idle.is_empty());
+        assert!(state.borrow().waiting[1].is_none());
+    }
+}
@@ -12,11 +12,13 @@
 
 use std::iter;
 use std::panic;
+use std::time::Duration;
 
 use futures::{Future, Sink, Stream};
 
-use errors::Dropped;
-use wrappers::{CleanupIterator, OkIterator, ResultIterator, SinkSender, StreamExtractor};
+use errors::{Dropped, WaitTimeout};
+use wrappers::{CleanupIterator, OkIterator, ResultIterator, SinkCloser, SinkFlusher, SinkSender,
+               StreamExtractor};
 
 pub use coroutine::Coroutine;
 
@@ -82,6 +84,42 @@ pub trait CoroutineFuture: Sized {
     /// When called outside of the coroutine. Also, panics from within the future are propagated to
     /// the calling (current) coroutine.
     fn coro_wait_cleanup(self) -> Result<Result<Self::Item, Self::Error>, Dropped>;
+
+    /// A coroutine aware wait on the result, with a timeout.
+    ///
+    /// This is like [`coro_wait_cleanup`](#method.coro_wait_cleanup), but gives up after the
+    /// given `duration` if the future doesn't resolve by then, dropping the future. Both the
+    /// timeout and the reactor being dropped are reported through the
+    /// [`WaitTimeout`](../errors/enum.WaitTimeout.html) error.
+    ///
+    /// # Panics
+    ///
+    /// When called outside of the coroutine. Also, panics from within the future are propagated to
+    /// the calling (current) coroutine.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// # use std::time::Duration;
+    /// # use corona::prelude::*;
+    /// # use corona::WaitTimeout;
+    /// # use futures::unsync::oneshot;
+    /// # use tokio_core::reactor::Core;
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// // We keep the sender alive, but never send anything.
+    /// let (_sender, receiver) = oneshot::channel::<u32>();
+    /// let coro = Coroutine::with_defaults(core.handle(), move || {
+    ///     receiver.coro_wait_timeout(Duration::from_millis(50))
+    /// });
+    /// assert_eq!(Err(WaitTimeout::TimedOut), core.run(coro).unwrap());
+    /// # }
+    /// ```
+    fn coro_wait_timeout(self, duration: Duration)
+        -> Result<Result<Self::Item, Self::Error>, WaitTimeout>;
 }
 
 impl<I, E, F: Future<Item = I, Error = E>> CoroutineFuture for F {
@@ -90,6 +128,9 @@ impl<I, E, F: Future<Item = I, Error = E>> CoroutineFuture for F {
     fn coro_wait_cleanup(self) -> Result<Result<I, E>, Dropped> {
         Coroutine::wait(self)
     }
+    fn coro_wait_timeout(self, duration: Duration) -> Result<Result<I, E>, WaitTimeout> {
+        Coroutine::wait_timeout(self, duration)
+    }
 }
 
 /// An extension trait for `Stream`s.
@@ -114,6 +155,10 @@ pub trait CoroutineStream: Sized {
     /// When it waites for another item to come out of the stream, the coroutine suspends and
     /// switches to others if there are some ready.
     ///
+    /// The iterator takes ownership of the stream. If you want to consume just part of the stream
+    /// and keep using it afterwards, iterate through a mutable reference to it (eg. the one
+    /// produced by `Stream::by_ref`), as that is a stream too.
+    ///
     /// # Panics
     ///
     /// If the reactor is dropped during the iteration, this method panics to clean up the
@@ -149,6 +194,29 @@ pub trait CoroutineStream: Sized {
     /// assert_eq!(42, core.run(coro).unwrap());
     /// # }
     /// ```
+    ///
+    /// Consuming only part of the stream:
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// # use corona::prelude::*;
+    /// # use futures::Stream;
+    /// # use futures::stream;
+    /// # use tokio_core::reactor::Core;
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let mut numbers = stream::iter_ok::<_, ()>(vec![1, 2, 3, 4]);
+    ///
+    /// let coro = Coroutine::with_defaults(core.handle(), move || {
+    ///     let first: Vec<_> = numbers.by_ref().iter_ok().take(2).collect();
+    ///     let rest: Vec<_> = numbers.iter_ok().collect();
+    ///     (first, rest)
+    /// });
+    /// assert_eq!((vec![1, 2], vec![3, 4]), core.run(coro).unwrap());
+    /// # }
+    /// ```
     fn iter_ok(self) -> OkIterator<CleanupIterator<Self>> {
         OkIterator::new(self.iter_cleanup())
     }
@@ -385,6 +453,29 @@ pub trait CoroutineSink: Sized {
     /// If it is called outside of a coroutine or if the sink itself panics.
     fn coro_send_cleanup(&mut self, item: Self::Item) -> Result<Result<(), Self::Error>, Dropped>;
 
+    /// Sends an item into the sink, creating it only once the sink is ready for it.
+    ///
+    /// The sink is flushed first (see [`coro_flush`](#method.coro_flush)), which suspends the
+    /// coroutine until the items sent previously are pushed through. Only then `make_item` is
+    /// called and its result sent. This avoids constructing an expensive item in advance, only to
+    /// have it sit in a full buffer.
+    ///
+    /// Note that this relies on the sink reporting its backpressure through `poll_complete`. Sinks
+    /// that are always complete (like the unsynchronized channels) accept the item right away.
+    ///
+    /// # Parameters
+    ///
+    /// * `make_item`: Creates the item to be sent.
+    ///
+    /// # Panics
+    ///
+    /// If the reactor is dropped before the sending is done.
+    ///
+    /// If it is called outside of a coroutine or if the sink panics internally.
+    fn coro_send_when_ready<F>(&mut self, make_item: F) -> Result<(), Self::Error>
+    where
+        F: FnOnce() -> Self::Item;
+
     /// Sends multiple items into the sink.
     ///
     /// This is like [`coro_send_cleanup`](#method.coro_send_cleanup). However, it sends multiple
@@ -418,6 +509,64 @@ pub trait CoroutineSink: Sized {
     where
         Iter: Iterator<Item = Self::Item>,
         I: IntoIterator<Item = Self::Item, IntoIter = Iter>;
+
+    /// Waits until everything sent into the sink so far is flushed.
+    ///
+    /// Some sinks buffer the items and push them further only later on. This suspends the
+    /// coroutine until the sink is done with all of them, which allows creating a checkpoint in
+    /// the production.
+    ///
+    /// Note that the other sending methods here already flush the sink after each batch, this is
+    /// mostly useful when the sink is fed by other means.
+    ///
+    /// # Panics
+    ///
+    /// If the reactor is dropped before the flushing is done.
+    ///
+    /// If it is called outside of a coroutine or if the sink panics internally.
+    fn coro_flush(&mut self) -> Result<(), Self::Error>;
+
+    /// Creates a future that flushes the sink.
+    ///
+    /// This is the internal future of [`coro_flush`](#method.coro_flush), without waiting for it.
+    fn coro_flusher(&mut self) -> SinkFlusher<Self>;
+
+    /// Closes the sink.
+    ///
+    /// This flushes and closes the sink and then drops it. For channels, this signals the end of
+    /// the stream to the receiving side.
+    ///
+    /// # Panics
+    ///
+    /// If the reactor is dropped before the closing is done.
+    ///
+    /// If it is called outside of a coroutine or if the sink panics internally.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate corona;
+    /// # extern crate futures;
+    /// # extern crate tokio_core;
+    /// # use corona::prelude::*;
+    /// # use futures::unsync::mpsc;
+    /// # use tokio_core::reactor::Core;
+    /// # fn main() {
+    /// let mut core = Core::new().unwrap();
+    /// let (mut sender, receiver) = mpsc::channel(1);
+    /// Coroutine::with_defaults(core.handle(), move || {
+    ///     sender.coro_send(21).unwrap();
+    ///     sender.coro_send(21).unwrap();
+    ///     sender.coro_close().unwrap();
+    /// });
+    /// let sum = Coroutine::with_defaults(core.handle(), move || {
+    ///     // Terminates, because the sender got closed
+    ///     receiver.iter_ok().sum::<u32>()
+    /// });
+    /// assert_eq!(42, core.run(sum).unwrap());
+    /// # }
+    /// ```
+    fn coro_close(self) -> Result<(), Self::Error>;
 }
 
 impl<I, E, S: Sink<SinkItem = I, SinkError = E>> CoroutineSink for S {
@@ -436,6 +585,13 @@ impl<I, E, S: Sink<SinkItem = I, SinkError = E>> CoroutineSink for S {
     fn coro_send_cleanup(&mut self, item: Self::Item) -> Result<Result<(), Self::Error>, Dropped> {
         self.coro_sender(iter::once(item)).coro_wait_cleanup()
     }
+    fn coro_send_when_ready<F>(&mut self, make_item: F) -> Result<(), Self::Error>
+    where
+        F: FnOnce() -> Self::Item,
+    {
+        self.coro_flush()?;
+        self.coro_send(make_item())
+    }
     fn coro_send_many<Iter, Src>(&mut self, iter: Src) -> Result<Result<(), Self::Error>, Dropped>
     where
         Iter: Iterator<Item = Self::Item>,
@@ -443,4 +599,13 @@ impl<I, E, S: Sink<SinkItem = I, SinkError = E>> CoroutineSink for S {
     {
         self.coro_sender(iter).coro_wait_cleanup()
     }
+    fn coro_flush(&mut self) -> Result<(), Self::Error> {
+        self.coro_flusher().coro_wait()
+    }
+    fn coro_flusher(&mut self) -> SinkFlusher<Self> {
+        SinkFlusher::new(self)
+    }
+    fn coro_close(mut self) -> Result<(), Self::Error> {
+        SinkCloser::new(&mut self).coro_wait()
+    }
 }
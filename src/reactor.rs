@@ -0,0 +1,140 @@
+//! Abstraction over the reactor the coroutines run on.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use futures::{future, Async, Future, Poll};
+use tokio_core::reactor::{CoreId, Handle};
+
+thread_local! {
+    /// Sentinels planted into the reactors on this thread, to tell if they are still alive.
+    static SENTINELS: RefCell<HashMap<CoreId, Weak<()>>> = RefCell::new(HashMap::new());
+}
+
+/// A task that never resolves, living on the reactor for as long as the reactor does.
+///
+/// The reactor drops it together with itself, which is visible through the weak reference.
+struct Sentinel(#[allow(dead_code)] Rc<()>);
+
+impl Future for Sentinel {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        Ok(Async::NotReady)
+    }
+}
+
+/// Returns the sentinel of the reactor, planting it on the first call.
+///
+/// The weak reference is dead once the reactor is gone.
+pub(crate) fn sentinel(handle: &Handle) -> Weak<()> {
+    let id = handle.id();
+    SENTINELS.with(|s| {
+        let mut sentinels = s.borrow_mut();
+        if let Some(sentinel) = sentinels.get(&id) {
+            return sentinel.clone();
+        }
+        // Forget the sentinels of reactors that are gone.
+        sentinels.retain(|_, sentinel| sentinel.upgrade().is_some());
+        let sentinel = Rc::new(());
+        let weak = Rc::downgrade(&sentinel);
+        // A dead reactor drops the sentinel right away.
+        handle.spawn(Sentinel(sentinel));
+        if weak.upgrade().is_some() {
+            sentinels.insert(id, weak.clone());
+        }
+        weak
+    })
+}
+
+/// Something the coroutines can run on.
+///
+/// The coroutines need a place to install the futures they wait for, which is usually the reactor
+/// core from `tokio_core` (represented by its `Handle`). However, a different executor can be
+/// plugged in by implementing this trait ‒ for example the
+/// [`MockReactor`](testing/struct.MockReactor.html) with virtual time, for deterministic tests.
+///
+/// Note that some functionality needs the real `tokio_core` reactor (like
+/// [`Coroutine::reactor`](struct.Coroutine.html#method.reactor), timeouts created by the library
+/// or priorities). These are not available on other implementations.
+pub trait ReactorLike {
+    /// Spawns a future to be run to completion.
+    ///
+    /// If the reactor is no longer running, the future should be dropped right away.
+    fn spawn_task(&self, task: Box<Future<Item = (), Error = ()>>);
+
+    /// Checks if the reactor is still running.
+    ///
+    /// The default implementation spawns a probe future and checks if it got dropped right away
+    /// without being run. That is a task per call, so implementations are encouraged to provide
+    /// something cheaper.
+    fn is_running(&self) -> bool {
+        let probe = Rc::new(Cell::new(false));
+        let ran = probe.clone();
+        self.spawn_task(Box::new(future::lazy(move || {
+            ran.set(true);
+            Ok::<(), ()>(())
+        })));
+        // Either still waiting to run, or run right away by an executor that polls inside
+        // `spawn_task`. Only a dead one drops it without running it.
+        probe.get() || Rc::strong_count(&probe) > 1
+    }
+
+    /// Returns the `tokio_core` handle, if this is backed by one.
+    ///
+    /// The default implementation clones the one from
+    /// [`tokio_handle_ref`](#method.tokio_handle_ref).
+    fn tokio_handle(&self) -> Option<Handle> {
+        self.tokio_handle_ref().cloned()
+    }
+
+    /// Borrows the `tokio_core` handle, if this is backed by one.
+    fn tokio_handle_ref(&self) -> Option<&Handle> {
+        None
+    }
+}
+
+impl ReactorLike for Handle {
+    fn spawn_task(&self, task: Box<Future<Item = (), Error = ()>>) {
+        self.spawn(task);
+    }
+    /// Checks the sentinel of the reactor, planting it on the first call.
+    fn is_running(&self) -> bool {
+        sentinel(self).upgrade().is_some()
+    }
+    fn tokio_handle_ref(&self) -> Option<&Handle> {
+        Some(self)
+    }
+}
+
+/// The shared reactor, as stored inside the coroutines.
+pub(crate) type Reactor = Rc<ReactorLike>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs everything right away, inside `spawn_task`.
+    struct Eager;
+
+    impl ReactorLike for Eager {
+        fn spawn_task(&self, task: Box<Future<Item = (), Error = ()>>) {
+            task.wait().unwrap();
+        }
+    }
+
+    /// Drops everything, like a reactor that is gone.
+    struct Dead;
+
+    impl ReactorLike for Dead {
+        fn spawn_task(&self, _task: Box<Future<Item = (), Error = ()>>) {}
+    }
+
+    /// The default probe tells a dead executor from one running the tasks right away.
+    #[test]
+    fn default_is_running() {
+        assert!(Eager.is_running());
+        assert!(!Dead.is_running());
+    }
+}
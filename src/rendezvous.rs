@@ -0,0 +1,237 @@
+//! A rendezvous channel between coroutines.
+//!
+//! Passing items between two coroutines on the same thread through a futures channel means
+//! buffering each item and a round trip through the reactor for every wake up. The channel here
+//! has no buffer at all. Instead, the producer hands the item directly to the waiting consumer (or
+//! waits for the consumer to come and pick it up), switching between the coroutines without the
+//! reactor being involved.
+//!
+//! The hand-off is synchronous ‒ `produce` returns only once the consumer took the item (or
+//! disappeared). When one side finds the other one waiting, it resumes it right away. The resumed
+//! coroutine runs until it waits for something again, then the control returns back.
+//!
+//! Both sides must live in coroutines on the same thread.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # extern crate corona;
+//! # extern crate tokio_core;
+//! use corona::Coroutine;
+//! use corona::rendezvous::coro_rendezvous;
+//! use tokio_core::reactor::Core;
+//!
+//! # fn main() {
+//! let mut core = Core::new().unwrap();
+//! let (producer, consumer) = coro_rendezvous();
+//! let consumer = Coroutine::with_defaults(core.handle(), move || {
+//!     consumer.sum::<u32>()
+//! });
+//! Coroutine::with_defaults(core.handle(), move || {
+//!     for i in 0..10 {
+//!         producer.produce(i).unwrap();
+//!     }
+//! });
+//! assert_eq!(45, core.run(consumer).unwrap());
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use coroutine::{Coroutine, CoroutineHandle};
+
+struct State<I> {
+    /// The item being handed over.
+    item: Option<I>,
+    /// The other side, if it waits.
+    waiting: Option<CoroutineHandle>,
+    /// One of the sides is gone.
+    closed: bool,
+}
+
+type StateRef<I> = Rc<RefCell<State<I>>>;
+
+/// Suspends the current coroutine, leaving its handle in the state.
+fn park<I>(state: &StateRef<I>) {
+    // We don't care why we were woken up, the state tells what happened.
+    let _ = Coroutine::suspend(|me| state.borrow_mut().waiting = Some(me));
+}
+
+/// Marks the channel as closed and lets the other side know.
+fn close<I>(state: &StateRef<I>) {
+    let waiting = {
+        let mut state = state.borrow_mut();
+        state.closed = true;
+        state.waiting.take()
+    };
+    if let Some(waiting) = waiting {
+        waiting.resume();
+    }
+}
+
+/// Creates a rendezvous channel.
+///
+/// See the [module documentation](index.html) for details.
+pub fn coro_rendezvous<I>() -> (RProducer<I>, RConsumer<I>) {
+    let state = Rc::new(RefCell::new(State {
+        item: None,
+        waiting: None,
+        closed: false,
+    }));
+    (RProducer(state.clone()), RConsumer(state))
+}
+
+/// The sending side of the rendezvous channel.
+pub struct RProducer<I>(StateRef<I>);
+
+impl<I> RProducer<I> {
+    /// Hands an item to the consumer.
+    ///
+    /// If the consumer waits already, it gets the item and is resumed right away. Otherwise, the
+    /// current coroutine is suspended until the consumer comes for the item.
+    ///
+    /// # Returns
+    ///
+    /// `Err(item)` if the consumer is gone.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn produce(&self, item: I) -> Result<(), I> {
+        let waiting = {
+            let mut state = self.0.borrow_mut();
+            if state.closed {
+                return Err(item);
+            }
+            state.item = Some(item);
+            state.waiting.take()
+        };
+        match waiting {
+            Some(consumer) => consumer.resume(),
+            None => park(&self.0),
+        }
+        // If the item is still there, nobody took it
+        match self.0.borrow_mut().item.take() {
+            Some(item) => Err(item),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<I> Drop for RProducer<I> {
+    fn drop(&mut self) {
+        close(&self.0);
+    }
+}
+
+/// The receiving side of the rendezvous channel.
+///
+/// Besides calling [`consume`](#method.consume), it can be used as an iterator of the produced
+/// items.
+pub struct RConsumer<I>(StateRef<I>);
+
+impl<I> RConsumer<I> {
+    /// Takes an item from the producer.
+    ///
+    /// If the producer waits with an item already, the item is taken and the producer is resumed
+    /// right away. Otherwise, the current coroutine is suspended until the producer brings an
+    /// item.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the producer is gone.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine.
+    pub fn consume(&self) -> Option<I> {
+        let (item, waiting, closed) = {
+            let mut state = self.0.borrow_mut();
+            (state.item.take(), state.waiting.take(), state.closed)
+        };
+        if item.is_some() {
+            if let Some(producer) = waiting {
+                producer.resume();
+            }
+            return item;
+        }
+        if closed {
+            return None;
+        }
+        park(&self.0);
+        self.0.borrow_mut().item.take()
+    }
+}
+
+impl<I> Iterator for RConsumer<I> {
+    type Item = I;
+    fn next(&mut self) -> Option<I> {
+        self.consume()
+    }
+}
+
+impl<I> Drop for RConsumer<I> {
+    fn drop(&mut self) {
+        close(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// The consumer comes first, then the producer.
+    #[test]
+    fn consumer_first() {
+        let mut core = Core::new().unwrap();
+        let (producer, consumer) = coro_rendezvous();
+        let consumer = Coroutine::with_defaults(core.handle(), move || {
+            consumer.collect::<Vec<_>>()
+        });
+        let producer = Coroutine::with_defaults(core.handle(), move || {
+            for i in 0..5 {
+                producer.produce(i).unwrap();
+            }
+        });
+        core.run(producer).unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], core.run(consumer).unwrap());
+    }
+
+    /// The producer comes first and waits for the consumer.
+    #[test]
+    fn producer_first() {
+        let mut core = Core::new().unwrap();
+        let (producer, consumer) = coro_rendezvous();
+        let produced = Rc::new(Cell::new(0));
+        let produced_cp = produced.clone();
+        let producer = Coroutine::with_defaults(core.handle(), move || {
+            for i in 0..5 {
+                producer.produce(i).unwrap();
+                produced_cp.set(produced_cp.get() + 1);
+            }
+        });
+        // Nobody took the first item yet
+        assert_eq!(0, produced.get());
+        let consumer = Coroutine::with_defaults(core.handle(), move || {
+            consumer.collect::<Vec<_>>()
+        });
+        core.run(producer).unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 4], core.run(consumer).unwrap());
+        assert_eq!(5, produced.get());
+    }
+
+    /// The waiting producer gets its item back when the consumer goes away.
+    #[test]
+    fn consumer_gone() {
+        let mut core = Core::new().unwrap();
+        let (producer, consumer) = coro_rendezvous();
+        let producer = Coroutine::with_defaults(core.handle(), move || producer.produce(42));
+        drop(consumer);
+        assert_eq!(Err(42), core.run(producer).unwrap());
+    }
+}
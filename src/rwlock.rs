@@ -0,0 +1,370 @@
+//! A reader-writer lock for coroutines.
+//!
+//! Holding a `RefCell` borrowed across a suspension point is a bad idea, another coroutine may
+//! want it in the meantime and panic. The [`CoRwLock`](struct.CoRwLock.html) is the coroutine
+//! aware alternative ‒ if the lock can't be granted right away, the coroutine is suspended until
+//! it can.
+//!
+//! Any number of readers may hold the lock at once, or a single writer. To keep either side
+//! from starving, a waiting writer blocks new readers from coming in, and the readers that queued
+//! during a write go before the next writer.
+//!
+//! The lock is meant to be shared between coroutines of the same thread, usually inside an `Rc`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # extern crate corona;
+//! # extern crate tokio_core;
+//! use std::rc::Rc;
+//!
+//! use corona::Coroutine;
+//! use corona::rwlock::CoRwLock;
+//! use tokio_core::reactor::Core;
+//!
+//! # fn main() {
+//! let mut core = Core::new().unwrap();
+//! let lock = Rc::new(CoRwLock::new(0));
+//! let lock_cp = lock.clone();
+//! let writer = Coroutine::with_defaults(core.handle(), move || {
+//!     *lock_cp.write().unwrap() += 42;
+//! });
+//! let reader = Coroutine::with_defaults(core.handle(), move || {
+//!     *lock.read().unwrap()
+//! });
+//! core.run(writer).unwrap();
+//! assert_eq!(42, core.run(reader).unwrap());
+//! # }
+//! ```
+
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+use futures::{task, Async, Future, Poll};
+use futures::task::Task;
+
+use coroutine::Coroutine;
+use errors::Dropped;
+
+/// A coroutine waiting for the lock.
+#[derive(Default)]
+struct Waiter {
+    granted: Cell<bool>,
+    task: RefCell<Option<Task>>,
+}
+
+impl Waiter {
+    /// Hands the lock over to the waiter and wakes it up.
+    fn grant(&self) {
+        self.granted.set(true);
+        let task = self.task.borrow_mut().take();
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+}
+
+/// Resolves once the lock is granted to the waiter.
+struct Grant(Rc<Waiter>);
+
+impl Future for Grant {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.0.granted.get() {
+            Ok(Async::Ready(()))
+        } else {
+            *self.0.task.borrow_mut() = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    readers: usize,
+    writer: bool,
+    read_queue: VecDeque<Rc<Waiter>>,
+    write_queue: VecDeque<Rc<Waiter>>,
+}
+
+impl State {
+    fn grant_readers(&mut self) {
+        let waiting = mem::replace(&mut self.read_queue, VecDeque::new());
+        self.readers += waiting.len();
+        for waiter in waiting {
+            waiter.grant();
+        }
+    }
+
+    fn grant_writer(&mut self) -> bool {
+        match self.write_queue.pop_front() {
+            Some(waiter) => {
+                self.writer = true;
+                waiter.grant();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Hands the released lock over to the waiting coroutines.
+    ///
+    /// After a writer, the waiting readers go first. After the last reader, the next writer does.
+    fn dispatch(&mut self, after_writer: bool) {
+        if after_writer {
+            self.grant_readers();
+        }
+        if self.readers == 0 && !self.grant_writer() {
+            self.grant_readers();
+        }
+    }
+}
+
+/// A reader-writer lock suspending the coroutines that can't get it.
+///
+/// See the [module documentation](index.html) for details.
+pub struct CoRwLock<T> {
+    state: RefCell<State>,
+    value: UnsafeCell<T>,
+}
+
+impl<T> CoRwLock<T> {
+    /// Creates a new unlocked lock holding the value.
+    pub fn new(value: T) -> Self {
+        CoRwLock {
+            state: RefCell::new(State::default()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the lock and returns the value inside.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Locks for reading.
+    ///
+    /// If a writer holds the lock or waits for it, the current coroutine is suspended until the
+    /// lock can be granted.
+    ///
+    /// # Returns
+    ///
+    /// The guard giving access to the value, or `Err(Dropped)` if the reactor was dropped (or the
+    /// coroutine cancelled) while waiting.
+    ///
+    /// # Panics
+    ///
+    /// If it has to wait and is called outside of a coroutine.
+    pub fn read(&self) -> Result<CoReadGuard<T>, Dropped> {
+        let waiter = {
+            let mut state = self.state.borrow_mut();
+            if !state.writer && state.write_queue.is_empty() {
+                state.readers += 1;
+                None
+            } else {
+                let waiter = Rc::new(Waiter::default());
+                state.read_queue.push_back(waiter.clone());
+                Some(waiter)
+            }
+        };
+        if let Some(waiter) = waiter {
+            self.wait_for(waiter, false)?;
+        }
+        Ok(CoReadGuard { lock: self })
+    }
+
+    /// Locks for writing.
+    ///
+    /// If anyone else holds the lock, the current coroutine is suspended until the lock can be
+    /// granted.
+    ///
+    /// # Returns
+    ///
+    /// The guard giving access to the value, or `Err(Dropped)` if the reactor was dropped (or the
+    /// coroutine cancelled) while waiting.
+    ///
+    /// # Panics
+    ///
+    /// If it has to wait and is called outside of a coroutine.
+    pub fn write(&self) -> Result<CoWriteGuard<T>, Dropped> {
+        let waiter = {
+            let mut state = self.state.borrow_mut();
+            if !state.writer && state.readers == 0 {
+                state.writer = true;
+                None
+            } else {
+                let waiter = Rc::new(Waiter::default());
+                state.write_queue.push_back(waiter.clone());
+                Some(waiter)
+            }
+        };
+        if let Some(waiter) = waiter {
+            self.wait_for(waiter, true)?;
+        }
+        Ok(CoWriteGuard { lock: self })
+    }
+
+    fn wait_for(&self, waiter: Rc<Waiter>, write: bool) -> Result<(), Dropped> {
+        if Coroutine::wait(Grant(waiter.clone())).is_ok() {
+            return Ok(());
+        }
+        if waiter.granted.get() {
+            // We got it just as we gave up, so pass it on.
+            if write {
+                self.release_write();
+            } else {
+                self.release_read();
+            }
+        } else {
+            let mut state = self.state.borrow_mut();
+            state.read_queue.retain(|w| !Rc::ptr_eq(w, &waiter));
+            state.write_queue.retain(|w| !Rc::ptr_eq(w, &waiter));
+            // The readers might have been waiting only because of us.
+            if !state.writer && state.write_queue.is_empty() {
+                state.grant_readers();
+            }
+        }
+        Err(Dropped)
+    }
+
+    fn release_read(&self) {
+        let mut state = self.state.borrow_mut();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.dispatch(false);
+        }
+    }
+
+    fn release_write(&self) {
+        let mut state = self.state.borrow_mut();
+        state.writer = false;
+        state.dispatch(true);
+    }
+}
+
+/// Shared access to the value inside a [`CoRwLock`](struct.CoRwLock.html).
+///
+/// The lock is released when the guard is dropped.
+pub struct CoReadGuard<'a, T: 'a> {
+    lock: &'a CoRwLock<T>,
+}
+
+impl<'a, T: 'a> Deref for CoReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for CoReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release_read();
+    }
+}
+
+/// Exclusive access to the value inside a [`CoRwLock`](struct.CoRwLock.html).
+///
+/// The lock is released when the guard is dropped.
+pub struct CoWriteGuard<'a, T: 'a> {
+    lock: &'a CoRwLock<T>,
+}
+
+impl<'a, T: 'a> Deref for CoWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for CoWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T: 'a> Drop for CoWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.release_write();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::unsync::oneshot;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// Several readers hold the lock at the same time.
+    #[test]
+    fn readers_together() {
+        let mut core = Core::new().unwrap();
+        let lock = Rc::new(CoRwLock::new(42));
+        let active = Rc::new(Cell::new(0));
+        let max_active = Rc::new(Cell::new(0));
+        let readers = (0..3)
+            .map(|_| {
+                let lock = lock.clone();
+                let active = active.clone();
+                let max_active = max_active.clone();
+                Coroutine::with_defaults(core.handle(), move || {
+                    let guard = lock.read().unwrap();
+                    active.set(active.get() + 1);
+                    max_active.set(max_active.get().max(active.get()));
+                    Coroutine::sleep(Duration::from_millis(10)).unwrap();
+                    active.set(active.get() - 1);
+                    *guard
+                })
+            })
+            .collect::<Vec<_>>();
+        for reader in readers {
+            assert_eq!(42, core.run(reader).unwrap());
+        }
+        assert_eq!(3, max_active.get());
+    }
+
+    /// A writer waits for the reader to finish and the later reader waits for the writer.
+    #[test]
+    fn writer_waits_for_readers() {
+        let mut core = Core::new().unwrap();
+        let lock = Rc::new(CoRwLock::new(0));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (release, released) = oneshot::channel::<()>();
+
+        let lock_cp = lock.clone();
+        let log_cp = log.clone();
+        let reader = Coroutine::with_defaults(core.handle(), move || {
+            let _guard = lock_cp.read().unwrap();
+            log_cp.borrow_mut().push("read");
+            Coroutine::wait(released).unwrap().unwrap();
+            log_cp.borrow_mut().push("unlock");
+        });
+        let lock_cp = lock.clone();
+        let log_cp = log.clone();
+        let writer = Coroutine::with_defaults(core.handle(), move || {
+            let mut guard = lock_cp.write().unwrap();
+            log_cp.borrow_mut().push("write");
+            *guard += 1;
+        });
+        let log_cp = log.clone();
+        let late = Coroutine::with_defaults(core.handle(), move || {
+            let guard = lock.read().unwrap();
+            log_cp.borrow_mut().push("late read");
+            *guard
+        });
+        // The writer waits for the reader, the late reader waits behind the writer
+        assert_eq!(vec!["read"], *log.borrow());
+
+        release.send(()).unwrap();
+        assert_eq!(1, core.run(late).unwrap());
+        core.run(reader).unwrap();
+        core.run(writer).unwrap();
+        assert_eq!(vec!["read", "unlock", "write", "late read"], *log.borrow());
+    }
+}
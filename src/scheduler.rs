@@ -0,0 +1,215 @@
+//! Ordering of resumptions of coroutines.
+//!
+//! Usually, a coroutine is resumed directly from the reactor as soon as the future it waits for
+//! resolves. Coroutines with priority set are instead put into a queue and a drainer task resumes
+//! them in order of their priority. This way, coroutines that get ready in the same reactor turn
+//! get resumed in the right order.
+//!
+//! The coroutines that yield are put into a separate queue, resumed in the next turn of the
+//! reactor by another drainer task (or passed to the priority or fairness queue at that point, if
+//! they have these set). The coroutines with fairness turned on go through yet another such
+//! round-robin queue.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+use std::thread::LocalKey;
+
+use futures::{task, Async, Future, Poll};
+use tokio_core::reactor::{CoreId, Handle};
+
+use switch::ReadyCoroutine;
+
+struct Entry {
+    priority: u8,
+    /// Order of insertion, to keep FIFO order among the same priorities.
+    seq: u64,
+    coroutine: ReadyCoroutine,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        // Higher priority first, then the ones that came first.
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: BinaryHeap<Entry>,
+    seq: u64,
+}
+
+type QueueRef = Rc<RefCell<Queue>>;
+
+thread_local! {
+    /// The queues of the reactors running on this thread.
+    ///
+    /// The queues are owned by their drainer tasks, so they go away together with the reactor.
+    static QUEUES: RefCell<HashMap<CoreId, Weak<RefCell<Queue>>>> = RefCell::new(HashMap::new());
+    /// The queues of the yielded coroutines, owned by their drainers the same way.
+    static YIELDS: RoundRobinQueues = RefCell::new(HashMap::new());
+    /// The round-robin queues of the coroutines with fairness, owned by their drainers too.
+    static FAIR: RoundRobinQueues = RefCell::new(HashMap::new());
+}
+
+/// What happens to a coroutine from a round-robin queue once its turn comes.
+#[derive(Clone, Copy)]
+enum Turn {
+    Resume,
+    Priority(u8),
+    Fair,
+}
+
+type YieldQueue = VecDeque<(ReadyCoroutine, Turn)>;
+
+type RoundRobinQueues = RefCell<HashMap<CoreId, Weak<RefCell<YieldQueue>>>>;
+
+/// The task resuming the queued coroutines.
+struct Drainer(QueueRef);
+
+impl Future for Drainer {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        // Don't hold the borrow while running the coroutine, it may want to get scheduled again.
+        loop {
+            let entry = self.0.borrow_mut().entries.pop();
+            match entry {
+                Some(entry) => entry.coroutine.resume(),
+                None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+/// Schedules a coroutine to be resumed on the given reactor.
+pub(crate) fn schedule(handle: &Handle, priority: u8, coroutine: ReadyCoroutine) {
+    let id = handle.id();
+    let (queue, new) = QUEUES.with(|q| {
+        let mut queues = q.borrow_mut();
+        let existing = queues.get(&id).and_then(Weak::upgrade);
+        match existing {
+            Some(queue) => (queue, false),
+            None => {
+                // Forget the queues of reactors that are gone.
+                queues.retain(|_, queue| queue.upgrade().is_some());
+                let queue = QueueRef::default();
+                queues.insert(id, Rc::downgrade(&queue));
+                (queue, true)
+            },
+        }
+    });
+    {
+        let mut queue = queue.borrow_mut();
+        let seq = queue.seq;
+        queue.seq += 1;
+        queue.entries.push(Entry {
+            priority,
+            seq,
+            coroutine,
+        });
+    }
+    if new {
+        handle.spawn(Drainer(queue));
+    }
+}
+
+/// The task resuming the yielded coroutines (or the ones with fairness).
+///
+/// It stays on the reactor for as long as there are coroutines yielding, so yielding doesn't need
+/// to spawn anything.
+struct YieldDrainer {
+    queue: Rc<RefCell<YieldQueue>>,
+    handle: Handle,
+}
+
+impl Future for YieldDrainer {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        // Only the ones that were there before, the ones that yield again now wait for the next
+        // turn, so the rest of the reactor gets its chance.
+        let waiting = self.queue.borrow().len();
+        for _ in 0..waiting {
+            let coroutine = self.queue.borrow_mut().pop_front();
+            match coroutine {
+                Some((coroutine, Turn::Resume)) => coroutine.resume(),
+                Some((coroutine, Turn::Priority(priority))) => {
+                    schedule(&self.handle, priority, coroutine)
+                },
+                Some((coroutine, Turn::Fair)) => schedule_fair(&self.handle, coroutine),
+                None => break,
+            }
+        }
+        if self.queue.borrow().is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            task::current().notify();
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Queues a yielded coroutine to be resumed in the next turn of the reactor.
+///
+/// A coroutine with priority or fairness is passed to the corresponding queue at that point
+/// instead of being resumed right away.
+pub(crate) fn yield_coroutine(handle: &Handle, coroutine: ReadyCoroutine, priority: Option<u8>,
+                              fair: bool)
+{
+    let turn = match priority {
+        Some(priority) => Turn::Priority(priority),
+        None if fair => Turn::Fair,
+        None => Turn::Resume,
+    };
+    round_robin(&YIELDS, handle, coroutine, turn);
+}
+
+/// Queues a coroutine with fairness turned on to be resumed after the ones that got ready before.
+pub(crate) fn schedule_fair(handle: &Handle, coroutine: ReadyCoroutine) {
+    round_robin(&FAIR, handle, coroutine, Turn::Resume);
+}
+
+fn round_robin(queues: &'static LocalKey<RoundRobinQueues>, handle: &Handle,
+               coroutine: ReadyCoroutine, turn: Turn)
+{
+    let id = handle.id();
+    let (queue, new) = queues.with(|q| {
+        let mut queues = q.borrow_mut();
+        let existing = queues.get(&id).and_then(Weak::upgrade);
+        match existing {
+            Some(queue) => (queue, false),
+            None => {
+                queues.retain(|_, queue| queue.upgrade().is_some());
+                let queue = Rc::new(RefCell::new(YieldQueue::new()));
+                queues.insert(id, Rc::downgrade(&queue));
+                (queue, true)
+            },
+        }
+    });
+    queue.borrow_mut().push_back((coroutine, turn));
+    if new {
+        handle.spawn(YieldDrainer {
+            queue,
+            handle: handle.clone(),
+        });
+    }
+}
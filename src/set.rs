@@ -0,0 +1,150 @@
+//! A collection of coroutines reaped in the order they finish.
+
+use std::mem;
+use std::panic;
+
+use futures::future;
+
+use coroutine::{Coroutine, CoroutineResult};
+use errors::{Dropped, SpawnError, TaskFailed};
+
+/// A set of coroutines, for managing a changing group of tasks.
+///
+/// Coroutines are spawned into the set over time and their results are then taken out with
+/// [`join_next`](#method.join_next) in the order they finish (not the order they were spawned
+/// in). This suits worker pools and similar places where tasks come and go.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate corona;
+/// # extern crate tokio_core;
+/// use corona::{Coroutine, CoroutineSet};
+/// use tokio_core::reactor::Core;
+///
+/// # fn main() {
+/// let mut core = Core::new().unwrap();
+/// let builder = Coroutine::new(core.handle());
+/// let sum = Coroutine::with_defaults(core.handle(), move || {
+///     let mut set = CoroutineSet::new(builder);
+///     for i in 0..10 {
+///         set.spawn(move || i).unwrap();
+///     }
+///     let mut sum = 0;
+///     while let Some(result) = set.join_next() {
+///         sum += result.unwrap();
+///     }
+///     sum
+/// });
+/// assert_eq!(45, core.run(sum).unwrap());
+/// # }
+/// ```
+pub struct CoroutineSet<R> {
+    builder: Coroutine,
+    running: Vec<CoroutineResult<R>>,
+}
+
+impl<R: 'static> CoroutineSet<R> {
+    /// Creates an empty set, spawning its coroutines with the given builder.
+    pub fn new(builder: Coroutine) -> Self {
+        CoroutineSet {
+            builder,
+            running: Vec::new(),
+        }
+    }
+
+    /// Spawns a coroutine into the set.
+    ///
+    /// This works like [`Coroutine::spawn`](struct.Coroutine.html#method.spawn), except the
+    /// result is kept inside the set.
+    pub fn spawn<Task>(&mut self, task: Task) -> Result<(), SpawnError>
+    where
+        Task: FnOnce() -> R + 'static,
+    {
+        let result = self.builder.spawn(task)?;
+        self.insert(result);
+        Ok(())
+    }
+
+    /// Adds an already running coroutine into the set.
+    pub fn insert(&mut self, result: CoroutineResult<R>) {
+        self.running.push(result);
+    }
+
+    /// How many coroutines in the set didn't have their results taken out yet.
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Checks if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Waits for the next coroutine in the set to finish.
+    ///
+    /// # Returns
+    ///
+    /// The result of the coroutine that finished first, or `None` if the set is empty.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the reactor is dropped while waiting.
+    pub fn join_next(&mut self) -> Option<Result<R, TaskFailed>> {
+        if self.running.is_empty() {
+            return None;
+        }
+        let running = mem::replace(&mut self.running, Vec::new());
+        let (result, rest) = match Coroutine::wait(future::select_all(running)) {
+            Ok(Ok((result, _, rest))) => (Ok(result), rest),
+            Ok(Err((err, _, rest))) => (Err(err), rest),
+            Err(Dropped) => panic::resume_unwind(Box::new(Dropped)),
+        };
+        self.running = rest;
+        Some(result)
+    }
+
+    /// Cancels all the coroutines in the set.
+    ///
+    /// The coroutines stay in the set. Unless they manage to finish anyway, their results
+    /// resolve to [`TaskFailed::Lost`](enum.TaskFailed.html#variant.Lost) (see
+    /// [`CoroutineResult::cancel`](struct.CoroutineResult.html#method.cancel)).
+    pub fn abort_all(&self) {
+        for coroutine in &self.running {
+            coroutine.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// The results come in the order the coroutines finish.
+    #[test]
+    fn finish_order() {
+        let mut core = Core::new().unwrap();
+        let builder = Coroutine::new(core.handle());
+        let order = Coroutine::with_defaults(core.handle(), move || {
+            let mut set = CoroutineSet::new(builder);
+            for &ms in &[50, 10, 40, 20, 30] {
+                set.spawn(move || {
+                    Coroutine::sleep(Duration::from_millis(ms)).unwrap();
+                    ms
+                }).unwrap();
+            }
+            assert_eq!(5, set.len());
+            let mut order = Vec::new();
+            while let Some(result) = set.join_next() {
+                order.push(result.unwrap());
+            }
+            assert!(set.is_empty());
+            order
+        });
+        assert_eq!(vec![10, 20, 30, 40, 50], core.run(order).unwrap());
+    }
+}
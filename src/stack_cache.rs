@@ -1,29 +1,121 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::ptr;
+use std::time::{Duration, Instant};
 
 use context::stack::ProtectedFixedSizeStack;
 
-use errors::StackError;
+use errors::SpawnError;
+
+/// The cached stacks, each with the time it was returned to the cache.
+struct Cache {
+    stacks: HashMap<usize, Vec<(ProtectedFixedSizeStack, Instant)>>,
+    /// If set, stacks unused for this long get freed automatically.
+    idle: Option<Duration>,
+    last_trim: Instant,
+}
+
+impl Cache {
+    /// Frees the stacks that weren't used for the given time.
+    ///
+    /// Returns how many stacks were freed.
+    fn trim(&mut self, idle: Duration) -> usize {
+        let now = Instant::now();
+        let mut freed = 0;
+        for stacks in self.stacks.values_mut() {
+            let before = stacks.len();
+            stacks.retain(|&(_, used)| now.duration_since(used) < idle);
+            freed += before - stacks.len();
+        }
+        self.stacks.retain(|_, stacks| !stacks.is_empty());
+        self.last_trim = now;
+        freed
+    }
+
+    /// Performs the automatic trimming, if it is time for it.
+    fn auto_trim(&mut self) {
+        if let Some(idle) = self.idle {
+            if self.last_trim.elapsed() >= idle {
+                self.trim(idle);
+            }
+        }
+    }
+}
 
 thread_local! {
-    static CACHE: RefCell<HashMap<usize, Vec<ProtectedFixedSizeStack>>> =
-        RefCell::new(HashMap::new());
+    static CACHE: RefCell<Cache> = RefCell::new(Cache {
+        stacks: HashMap::new(),
+        idle: None,
+        last_trim: Instant::now(),
+    });
+    /// How many stacks were newly allocated (not taken from the cache) on this thread.
+    static ALLOCATED: Cell<usize> = Cell::new(0);
+}
+
+/// The stride used to touch the stack pages.
+///
+/// The real page size may be larger, but touching it more often than needed does no harm.
+const PREFAULT_STRIDE: usize = 4096;
+
+/// Touches every page of the stack, so the kernel backs it with memory upfront.
+///
+/// Only the usable part of the stack (between its bottom and top) is touched, the guard page lies
+/// below the bottom. The pages are touched from the top down, the way the stack grows.
+fn prefault(stack: &ProtectedFixedSizeStack) {
+    let bottom = stack.bottom() as usize;
+    let mut page = stack.top() as usize;
+    while page > bottom {
+        // Don't step below the bottom, even if the size is not a multiple of the stride.
+        page = if page - bottom > PREFAULT_STRIDE { page - PREFAULT_STRIDE } else { bottom };
+        // A read could be satisfied by the shared zero page, so write.
+        unsafe { ptr::write_volatile(page as *mut u8, 0) };
+    }
+}
+
+/// The page size of the system.
+///
+/// Stack sizes need to be multiples of this.
+#[cfg(unix)]
+pub(crate) fn page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 { size as usize } else { PREFAULT_STRIDE }
+}
+
+/// The page size of the system.
+///
+/// Stack sizes need to be multiples of this. There's no portable way to ask outside of unix, so
+/// the usual 4096 is assumed.
+#[cfg(not(unix))]
+pub(crate) fn page_size() -> usize {
+    4096
 }
 
 /// Get a stack of the given size.
 ///
-/// Retrieve it from the cache or create a new one, if none is available.
+/// Retrieve it from the cache or create a new one, if none is available. If `prefault` is set, a
+/// newly created stack gets all its pages touched before being returned (the cached ones have been
+/// in use already).
 ///
 /// The cache is thread local.
-pub(crate) fn get(size: usize) -> Result<ProtectedFixedSizeStack, StackError> {
-    CACHE.with(|c| {
-        let mut cell = c.borrow_mut();
-        cell.get_mut(&size)
-            .and_then(|v| v.pop().map(Ok))
+pub(crate) fn get(size: usize, prefault: bool) -> Result<ProtectedFixedSizeStack, SpawnError> {
+    let stack = CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        cache.auto_trim();
+        cache.stacks
+            .get_mut(&size)
+            .and_then(|v| v.pop().map(|(stack, _)| Ok(stack)))
             .unwrap_or_else(|| {
-                ProtectedFixedSizeStack::new(size)
+                ALLOCATED.with(|a| a.set(a.get() + 1));
+                let stack = ProtectedFixedSizeStack::new(size)
+                    .map_err(|err| SpawnError::Stack { err, size })?;
+                if prefault {
+                    self::prefault(&stack);
+                }
+                Ok(stack)
             })
-    })
+    })?;
+    assert_eq!(stack.len(), size);
+    Ok(stack)
 }
 
 /// Put a stack into the cache, for future reuse.
@@ -32,5 +124,37 @@ pub(crate) fn get(size: usize) -> Result<ProtectedFixedSizeStack, StackError> {
 /// [`get`](function.get.html) call.
 pub(crate) fn put(stack: ProtectedFixedSizeStack) {
     let len = stack.len();
-    CACHE.with(|c| c.borrow_mut().entry(len).or_insert_with(Vec::new).push(stack));
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        cache.auto_trim();
+        cache.stacks
+            .entry(len)
+            .or_insert_with(Vec::new)
+            .push((stack, Instant::now()));
+    });
+}
+
+/// Frees the cached stacks that weren't used within the idle window.
+///
+/// If no idle window is set, all the cached stacks are freed. Returns how many were freed.
+pub(crate) fn trim() -> usize {
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let idle = cache.idle.unwrap_or_else(|| Duration::from_secs(0));
+        cache.trim(idle)
+    })
+}
+
+/// Sets the idle window for the automatic trimming (or turns it off with `None`).
+pub(crate) fn set_idle(idle: Option<Duration>) {
+    CACHE.with(|c| c.borrow_mut().idle = idle);
+}
+
+/// How many stacks were allocated on this thread so far.
+///
+/// If the stacks get properly returned to the cache, this stops growing once there are enough
+/// stacks for the peak number of coroutines alive at once.
+#[cfg(test)]
+pub(crate) fn allocated() -> usize {
+    ALLOCATED.with(Cell::get)
 }
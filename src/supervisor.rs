@@ -0,0 +1,130 @@
+//! Aggregated failure reporting for a group of coroutines.
+
+use std::panic;
+
+use futures::Future;
+
+use coroutine::{Coroutine, CoroutineResult};
+use errors::{Dropped, TaskFailed};
+
+/// Collects the failures of a group of child coroutines.
+///
+/// The children are registered with the supervisor and [`run`](#method.run) then waits for all of
+/// them. A failing child doesn't stop the others ‒ every failure is collected, together with the
+/// [id](struct.CoroutineResult.html#method.id) of the child, so the whole tree can be reported at
+/// once.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate corona;
+/// # extern crate tokio_core;
+/// use corona::{Coroutine, Supervisor};
+/// use tokio_core::reactor::Core;
+///
+/// # fn main() {
+/// let mut core = Core::new().unwrap();
+/// let builder = Coroutine::new(core.handle());
+/// let supervisor = Coroutine::with_defaults(core.handle(), move || {
+///     let mut supervisor = Supervisor::new();
+///     for i in 0..3 {
+///         supervisor.register(builder.spawn(move || i * 2).unwrap());
+///     }
+///     supervisor.run()
+/// });
+/// assert!(core.run(supervisor).unwrap().is_ok());
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Supervisor {
+    children: Vec<(usize, Box<Future<Item = (), Error = TaskFailed>>)>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor with no children.
+    pub fn new() -> Self {
+        Supervisor::default()
+    }
+
+    /// Registers a child coroutine.
+    ///
+    /// The result of the child is thrown away, only its failure is of interest.
+    pub fn register<R: 'static>(&mut self, child: CoroutineResult<R>) {
+        let id = child.id();
+        self.children.push((id, Box::new(child.map(|_| ()))));
+    }
+
+    /// How many children are registered.
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Checks if there are no children.
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Waits for all the children to terminate.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if all the children succeeded.
+    /// * `Err(failures)` with the ids and failures of the children that didn't, in the order they
+    ///   were registered.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a coroutine or if the reactor is dropped while waiting.
+    pub fn run(self) -> Result<(), Vec<(usize, TaskFailed)>> {
+        let (ids, children): (Vec<_>, Vec<_>) = self.children.into_iter().unzip();
+        let results = match Coroutine::wait_all_settled(children) {
+            Ok(results) => results,
+            Err(Dropped) => panic::resume_unwind(Box::new(Dropped)),
+        };
+        let failures = ids.into_iter()
+            .zip(results)
+            .filter_map(|(id, result)| result.err().map(|failure| (id, failure)))
+            .collect::<Vec<_>>();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+}
+
+// All the tests need to catch panics.
+#[cfg(all(test, not(corona_panic_abort)))]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    /// All the failures are collected, with the ids of the failed children.
+    #[test]
+    fn collect_failures() {
+        let mut core = Core::new().unwrap();
+        let builder = Coroutine::new(core.handle());
+        let result = Coroutine::with_defaults(core.handle(), move || {
+            let first = builder.spawn_catch_panic(|| panic!("First")).unwrap();
+            let ok = builder.spawn_catch_panic(|| 42).unwrap();
+            let second = builder.spawn_catch_panic(|| panic!("Second")).unwrap();
+            let expected = vec![first.id(), second.id()];
+            let mut supervisor = Supervisor::new();
+            supervisor.register(first);
+            supervisor.register(ok);
+            supervisor.register(second);
+            assert_eq!(3, supervisor.len());
+            let failures = supervisor.run().unwrap_err();
+            assert_eq!(expected, failures.iter().map(|&(id, _)| id).collect::<Vec<_>>());
+            failures
+                .into_iter()
+                .map(|(_, failure)| match failure {
+                    TaskFailed::Panicked(panic) => *panic.downcast::<&str>().unwrap(),
+                    other => panic!("Unexpected failure {:?}", other),
+                })
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(vec!["First", "Second"], core.run(result).unwrap());
+    }
+}
@@ -1,19 +1,85 @@
 //! Module for the low-level switching of coroutines
 
 use std::any::Any;
+use std::cell::RefCell;
 use std::panic::{self, AssertUnwindSafe};
 use std::process;
+use std::rc::Rc;
 use std::thread;
 
 use context::{Context, Transfer};
 use context::stack::ProtectedFixedSizeStack;
 use futures::{Async, Future, Poll};
 use tokio_core::reactor::Handle;
-
-use coroutine::CleanupStrategy;
-use errors::StackError;
+use coroutine::{self, CancelState, CleanupStrategy};
+use reactor::Reactor;
+use scheduler;
 use stack_cache;
 
+#[cfg(test)]
+thread_local! {
+    /// How many times the stacks were switched on this thread, for the tests.
+    static SWITCHES: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+}
+
+/// How many times the stacks were switched on this thread so far.
+#[cfg(test)]
+pub(crate) fn switch_count() -> usize {
+    SWITCHES.with(|s| s.get())
+}
+
+thread_local! {
+    /// Coroutines resumed or dropped from within a suspend hook.
+    ///
+    /// While a hook runs, the suspended coroutine's stack still holds the hook itself, so it can't
+    /// be switched into nor destroyed. The resumes and drops are collected here (the bool tells
+    /// which one) and performed once the hook returns. `None` when no hook runs.
+    static HOOK_DEFERRED: RefCell<Option<Vec<(ReadyCoroutine, bool)>>> = RefCell::new(None);
+}
+
+/// Postpones the resume or drop of the coroutine if we are inside a suspend hook.
+///
+/// Gives the coroutine back if it can be handled right away.
+fn defer(coroutine: ReadyCoroutine, resume: bool) -> Option<ReadyCoroutine> {
+    HOOK_DEFERRED.with(|deferred| match *deferred.borrow_mut() {
+        Some(ref mut deferred) => {
+            deferred.push((coroutine, resume));
+            None
+        },
+        None => Some(coroutine),
+    })
+}
+
+/// The deferred coroutines of a running suspend hook.
+///
+/// Restores the collection of the outer hook (if any) when left, even if the hook panics.
+struct HookScope {
+    outer: Option<Option<Vec<(ReadyCoroutine, bool)>>>,
+}
+
+impl HookScope {
+    fn enter() -> Self {
+        let outer = HOOK_DEFERRED.with(|d| d.replace(Some(Vec::new())));
+        HookScope { outer: Some(outer) }
+    }
+    fn leave(mut self) -> Vec<(ReadyCoroutine, bool)> {
+        self.restore()
+    }
+    fn restore(&mut self) -> Vec<(ReadyCoroutine, bool)> {
+        match self.outer.take() {
+            Some(outer) => HOOK_DEFERRED.with(|d| d.replace(outer)).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for HookScope {
+    fn drop(&mut self) {
+        // Any coroutines resumed from a panicking hook are simply dropped (cleaned up).
+        drop(self.restore());
+    }
+}
+
 /// A workaround befause Box<FnOnce> is currently very unusable in rust :-(.
 pub(crate) trait BoxableTask {
     fn perform(&mut self, Context, ProtectedFixedSizeStack) ->
@@ -33,14 +99,20 @@ where
 }
 
 /// A fake Box<FnOnce(Context) -> Context>.
-type BoxedTask = Box<BoxableTask>;
+pub(crate) type BoxedTask = Box<BoxableTask>;
 
 pub(crate) struct WaitTask {
     pub(crate) poll: *mut FnMut() -> Poll<(), ()>,
     pub(crate) context: Option<Context>,
     pub(crate) stack: Option<ProtectedFixedSizeStack>,
-    pub(crate) handle: Handle,
+    pub(crate) handle: Reactor,
     pub(crate) cleanup_strategy: CleanupStrategy,
+    /// If set, the coroutine is resumed through the scheduler instead of right away.
+    pub(crate) priority: Option<u8>,
+    /// Resume through the round-robin queue of the scheduler (unless there's a priority).
+    pub(crate) fair: bool,
+    /// Cancellation of the waiting coroutine.
+    pub(crate) cancel: Rc<CancelState>,
 }
 
 impl Future for WaitTask {
@@ -48,20 +120,44 @@ impl Future for WaitTask {
     type Error = ();
     fn poll(&mut self) -> Poll<(), ()> {
         assert!(self.context.is_some());
-        // The catch unwind is fine ‒ we don't swallow the panic, only move it to the correct place
-        // ‒ so likely everything relevant will be dropped like with any other normal panic.
-        match panic::catch_unwind(AssertUnwindSafe(unsafe {
+        if self.cancel.is_cancelled() {
+            // Wake the coroutine up the same way as if the reactor was dropped.
+            let context = self.context.take().unwrap();
+            if !cleanup(context, &mut self.stack, self.cleanup_strategy) {
+                coroutine::unregister(&self.cancel);
+            }
+            return Ok(Async::Ready(()));
+        }
+        self.cancel.park();
+        let poll = unsafe {
             // The future is still not dangling pointer ‒ we never resumed the stack
             self.poll
                 .as_mut()
                 .unwrap()
-        })) {
+        };
+        // The catch unwind is fine ‒ we don't swallow the panic, only move it to the correct place
+        // ‒ so likely everything relevant will be dropped like with any other normal panic.
+        let polled = coroutine::polling_for(&self.handle, &self.cancel, || {
+            panic::catch_unwind(AssertUnwindSafe(poll))
+        });
+        match polled {
             Ok(Ok(Async::NotReady)) => Ok(Async::NotReady),
             Ok(result) => {
-                Switch::Resume {
-                        stack: self.stack.take().unwrap(),
-                    }
-                    .run_child(self.context.take().unwrap());
+                // Waiting for its turn now, no longer for the future.
+                self.cancel.set_parked(false);
+                let ready = ReadyCoroutine {
+                    context: self.context.take(),
+                    stack: self.stack.take(),
+                    cleanup_strategy: self.cleanup_strategy,
+                };
+                // Priorities and fairness are handled only on the tokio reactor.
+                match (self.priority, self.handle.tokio_handle_ref()) {
+                    (Some(priority), Some(handle)) => scheduler::schedule(handle, priority, ready),
+                    (None, Some(handle)) if self.fair => {
+                        scheduler::schedule_fair(handle, ready)
+                    },
+                    _ => ready.resume(),
+                }
                 result
             },
             Err(panic) => {
@@ -80,23 +176,73 @@ impl Drop for WaitTask {
     fn drop(&mut self) {
         if let Some(context) = self.context.take() {
             // Not terminated yet?
-            let perform_cleanup = match (self.cleanup_strategy, thread::panicking()) {
-                (CleanupStrategy::CleanupAlways, _)
-                    | (CleanupStrategy::LeakOnPanic, false)
-                    | (CleanupStrategy::AbortOnPanic, false) => true,
-                (CleanupStrategy::LeakAlways, _)
-                    | (CleanupStrategy::LeakOnPanic, true) => false,
-                (CleanupStrategy::AbortAlways, _)
-                    | (CleanupStrategy::AbortOnPanic, true) => {
-                        process::abort();
-                    }
-            };
-            if perform_cleanup {
-                Switch::Cleanup {
-                        stack: self.stack.take().expect("Taken stack, but not context?")
-                    }
-                    .run_child(context);
+            if !cleanup(context, &mut self.stack, self.cleanup_strategy) {
+                coroutine::unregister(&self.cancel);
+            }
+        }
+    }
+}
 
+/// Cleans up a coroutine that won't get a chance to continue, according to the strategy.
+///
+/// Returns false if the coroutine got leaked instead.
+fn cleanup(
+    context: Context,
+    stack: &mut Option<ProtectedFixedSizeStack>,
+    cleanup_strategy: CleanupStrategy
+) -> bool {
+    let perform_cleanup = match (cleanup_strategy, thread::panicking()) {
+        (CleanupStrategy::CleanupAlways, _)
+            | (CleanupStrategy::LeakOnPanic, false)
+            | (CleanupStrategy::AbortOnPanic, false) => true,
+        (CleanupStrategy::LeakAlways, _)
+            | (CleanupStrategy::LeakOnPanic, true) => false,
+        (CleanupStrategy::AbortAlways, _)
+            | (CleanupStrategy::AbortOnPanic, true) => {
+                process::abort();
+            }
+    };
+    if perform_cleanup {
+        Switch::Cleanup {
+                stack: stack.take().expect("Taken stack, but not context?")
+            }
+            .run_child(context);
+    }
+    perform_cleanup
+}
+
+/// A coroutine whose future already resolved, but which wasn't resumed yet.
+///
+/// If it gets dropped before being resumed, it is cleaned up the same way as a waiting one.
+pub(crate) struct ReadyCoroutine {
+    context: Option<Context>,
+    stack: Option<ProtectedFixedSizeStack>,
+    cleanup_strategy: CleanupStrategy,
+}
+
+impl ReadyCoroutine {
+    /// Switches into the coroutine, letting it continue.
+    ///
+    /// From within a suspend hook, this only happens once the hook returns.
+    pub(crate) fn resume(self) {
+        if let Some(mut me) = defer(self, true) {
+            let stack = me.stack.take().unwrap();
+            Switch::Resume { stack }.run_child(me.context.take().unwrap());
+        }
+    }
+}
+
+impl Drop for ReadyCoroutine {
+    fn drop(&mut self) {
+        if let Some(context) = self.context.take() {
+            let me = ReadyCoroutine {
+                context: Some(context),
+                stack: self.stack.take(),
+                cleanup_strategy: self.cleanup_strategy,
+            };
+            if let Some(mut me) = defer(me, false) {
+                let context = me.context.take().unwrap();
+                cleanup(context, &mut me.stack, me.cleanup_strategy);
             }
         }
     }
@@ -166,6 +312,29 @@ pub(crate) enum Switch {
     Cleanup {
         stack: ProtectedFixedSizeStack,
     },
+    /// The coroutine suspends itself, to be resumed through a handle.
+    Suspend {
+        stack: ProtectedFixedSizeStack,
+        cleanup_strategy: CleanupStrategy,
+        /// Receives the suspended coroutine, on the side of the parent.
+        ///
+        /// It may return a coroutine to switch into right away, without returning to the parent
+        /// first (which may be the suspended one itself).
+        ///
+        /// It points into the stack of the suspended coroutine, so it must be called right away,
+        /// before anything else can resume it. Resumes and drops of coroutines from within the
+        /// hook are postponed until it returns.
+        hook: *mut FnMut(ReadyCoroutine) -> Option<ReadyCoroutine>,
+    },
+    /// The coroutine yields, to be resumed in the next turn of the reactor.
+    Yield {
+        stack: ProtectedFixedSizeStack,
+        cleanup_strategy: CleanupStrategy,
+        handle: Handle,
+        /// Where to go after the yield, see `WaitTask`.
+        priority: Option<u8>,
+        fair: bool,
+    },
     /// Get rid of the sending coroutine, it terminated.
     Destroy {
         stack: ProtectedFixedSizeStack,
@@ -222,40 +391,83 @@ impl Switch {
         // (it has it passed to it and it keeps it on its own stack until it decides to terminate
         // and passes it back through the instruction on switching out). So the stack can't get
         // destroyed prematurely.
+        #[cfg(test)]
+        SWITCHES.with(|s| s.set(s.get() + 1));
         let transfer = unsafe { context.resume(swp as usize) };
         (Self::extract(transfer.data), transfer.context)
     }
     /// Runs a child coroutine (one that does the work, is not a control coroutine) and once it
     /// returns, handles its return instruction.
+    ///
+    /// If the child hands the control directly to another coroutine, that one is run from here
+    /// too. This is done in a loop, not recursively, so coroutines passing the control between
+    /// themselves don't grow our stack.
     pub(crate) fn run_child(self, context: Context) {
-        let (reply, context) = self.exchange(context);
-        use self::Switch::*;
-        match reply {
-            Destroy { stack, panic } => {
-                drop(context);
-                stack_cache::put(stack);
-                if let Some(panic) = panic {
-                    panic::resume_unwind(panic);
-                }
-            },
-            WaitFuture { mut task } => {
-                task.context = Some(context);
-                let handle = task.handle.clone();
-                handle.spawn(task);
-            },
-            _ => unreachable!("Invalid switch instruction when switching out"),
+        let mut instruction = self;
+        let mut context = context;
+        loop {
+            let (reply, ctx) = instruction.exchange(context);
+            use self::Switch::*;
+            match reply {
+                Destroy { stack, panic } => {
+                    drop(ctx);
+                    stack_cache::put(stack);
+                    if let Some(panic) = panic {
+                        panic::resume_unwind(panic);
+                    }
+                    return;
+                },
+                WaitFuture { mut task } => {
+                    task.context = Some(ctx);
+                    let handle = task.handle.clone();
+                    handle.spawn_task(Box::new(task));
+                    return;
+                },
+                Yield { stack, cleanup_strategy, handle, priority, fair } => {
+                    let ready = ReadyCoroutine {
+                        context: Some(ctx),
+                        stack: Some(stack),
+                        cleanup_strategy,
+                    };
+                    scheduler::yield_coroutine(&handle, ready, priority, fair);
+                    return;
+                },
+                Suspend { stack, cleanup_strategy, hook } => {
+                    let suspended = ReadyCoroutine {
+                        context: Some(ctx),
+                        stack: Some(stack),
+                        cleanup_strategy,
+                    };
+                    // The hook lives on the stack of the suspended coroutine, which is still
+                    // there, as it is owned by the `suspended` we are passing in. Anything the
+                    // hook resumes or drops (including the suspended coroutine) is deferred until
+                    // it returns, so the stack stays intact while the hook runs.
+                    let scope = HookScope::enter();
+                    let next = unsafe { hook.as_mut().unwrap()(suspended) };
+                    for (coroutine, resume) in scope.leave() {
+                        if resume {
+                            coroutine.resume();
+                        }
+                    }
+                    match next {
+                        Some(mut next) => {
+                            instruction = Resume { stack: next.stack.take().unwrap() };
+                            context = next.context.take().unwrap();
+                        },
+                        None => return,
+                    }
+                },
+                _ => unreachable!("Invalid switch instruction when switching out"),
+            }
         }
     }
-    /// Creates a new coroutine and runs it.
-    pub(crate) fn run_new_coroutine(stack_size: usize, task: BoxedTask) -> Result<(), StackError> {
-        let stack = stack_cache::get(stack_size)?;
-        assert_eq!(stack.len(), stack_size);
+    /// Creates a new coroutine on the given stack and runs it.
+    pub(crate) fn run_new_coroutine(stack: ProtectedFixedSizeStack, task: BoxedTask) {
         // The `Context::new` is unsafe only because we have to promise not to delete the stack
         // prematurely, while the coroutine is still alive. We ensure that by giving the ownership
         // of the stack to the coroutine and it gives it up only once it is ready to terminate.
         let context = unsafe { Context::new(&stack, coroutine) };
         Switch::StartTask { stack, task }.run_child(context);
-        Ok(())
     }
 }
 
@@ -274,7 +486,8 @@ mod tests {
             called_cp.set(true);
             (context, stack, None)
         };
-        Switch::run_new_coroutine(40960, Box::new(Some(task))).unwrap();
+        let stack = stack_cache::get(40960, false).unwrap();
+        Switch::run_new_coroutine(stack, Box::new(Some(task)));
         assert!(called.get());
         assert_eq!(1, Rc::strong_count(&called));
     }
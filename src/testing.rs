@@ -0,0 +1,335 @@
+//! Helpers for testing code using coroutines.
+//!
+//! The main item here is the [`MockReactor`](struct.MockReactor.html). It can run coroutines
+//! instead of a real `tokio_core` reactor and has its own virtual time. This allows testing
+//! timeout logic deterministically and without any actual sleeping.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use futures::executor::{self, Notify, NotifyHandle, Spawn};
+use futures::task::{self, Task};
+
+use errors::Dropped;
+use reactor::ReactorLike;
+
+type BoxedFuture = Box<Future<Item = (), Error = ()>>;
+
+/// Remembers which tasks were woken up.
+#[derive(Default)]
+struct Woken(Mutex<Vec<usize>>);
+
+impl Notify for Woken {
+    fn notify(&self, id: usize) {
+        self.0.lock().unwrap().push(id);
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    now: Duration,
+    next_id: usize,
+    tasks: HashMap<usize, Spawn<BoxedFuture>>,
+    /// The registered timeouts with their deadlines, by their ids (in the order of registration).
+    timers: BTreeMap<usize, (Duration, Task)>,
+    next_timer: usize,
+}
+
+/// A single-threaded executor with virtual time.
+///
+/// It runs the spawned futures (including the ones the coroutines wait on) only when asked to, by
+/// [`run_until_stalled`](#method.run_until_stalled) or [`advance`](#method.advance). The time
+/// doesn't move on its own, only by calling `advance`.
+///
+/// Similar to the `tokio_core` `Core`, this is the owner of the executor and coroutines are
+/// spawned onto its [`handle`](#method.handle). Once it is dropped, the futures and coroutines
+/// waiting on it are dropped too.
+///
+/// # Examples
+///
+/// ```rust
+/// # extern crate corona;
+/// use std::time::Duration;
+///
+/// use corona::Coroutine;
+/// use corona::testing::MockReactor;
+///
+/// # fn main() {
+/// let mut reactor = MockReactor::new();
+/// let handle = reactor.handle();
+/// let mut result = Coroutine::new(reactor.handle())
+///     .spawn(move || {
+///         Coroutine::wait(handle.timeout(Duration::from_secs(60))).unwrap().unwrap();
+///         42
+///     })
+///     .unwrap();
+/// reactor.advance(Duration::from_secs(60));
+/// assert_eq!(42, reactor.block(&mut result).unwrap().unwrap());
+/// # }
+/// ```
+#[derive(Default)]
+pub struct MockReactor {
+    inner: Rc<RefCell<Inner>>,
+    woken: Arc<Woken>,
+}
+
+impl MockReactor {
+    /// Creates a new mock reactor, with the virtual time at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a handle to spawn things onto.
+    pub fn handle(&self) -> MockHandle {
+        MockHandle {
+            inner: Rc::downgrade(&self.inner),
+            woken: self.woken.clone(),
+        }
+    }
+
+    /// How much virtual time has passed since the reactor was created.
+    pub fn now(&self) -> Duration {
+        self.inner.borrow().now
+    }
+
+    /// Runs the futures that were woken up, until there's no more work to do.
+    pub fn run_until_stalled(&mut self) {
+        loop {
+            let woken = mem::replace(&mut *self.woken.0.lock().unwrap(), Vec::new());
+            if woken.is_empty() {
+                return;
+            }
+            let notify = NotifyHandle::from(self.woken.clone());
+            for id in woken {
+                // Take it out, so it can spawn more tasks while being polled.
+                let task = self.inner.borrow_mut().tasks.remove(&id);
+                if let Some(mut task) = task {
+                    match task.poll_future_notify(&notify, id) {
+                        Ok(Async::NotReady) => {
+                            self.inner.borrow_mut().tasks.insert(id, task);
+                        },
+                        Ok(Async::Ready(())) | Err(()) => (),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the virtual time forward.
+    ///
+    /// The timeouts expiring in the meantime fire and everything is run until stalled.
+    pub fn advance(&mut self, duration: Duration) {
+        self.run_until_stalled();
+        let expired = {
+            let mut inner = self.inner.borrow_mut();
+            inner.now += duration;
+            let now = inner.now;
+            let expired = inner.timers
+                .iter()
+                .filter(|&(_, &(deadline, _))| deadline <= now)
+                .map(|(&id, _)| id)
+                .collect::<Vec<_>>();
+            expired
+                .into_iter()
+                .filter_map(|id| inner.timers.remove(&id))
+                .collect::<Vec<_>>()
+        };
+        for (_, task) in expired {
+            task.notify();
+        }
+        self.run_until_stalled();
+    }
+
+    /// Runs the reactor until the future resolves.
+    ///
+    /// The virtual time doesn't move, so if the future waits for some time to pass, this returns
+    /// `None`. It also returns `None` if the future simply can't make any progress.
+    pub fn block<F: Future>(&mut self, future: &mut F) -> Option<Result<F::Item, F::Error>> {
+        let mut future = executor::spawn(future);
+        let notify = NotifyHandle::from(self.woken.clone());
+        // An id no spawned task uses
+        let id = usize::max_value();
+        loop {
+            self.run_until_stalled();
+            match future.poll_future_notify(&notify, id) {
+                Ok(Async::NotReady) => (),
+                Ok(Async::Ready(result)) => return Some(Ok(result)),
+                Err(err) => return Some(Err(err)),
+            }
+            // Did anything other than us get woken up?
+            let progress = {
+                let mut woken = self.woken.0.lock().unwrap();
+                woken.retain(|&w| w != id);
+                !woken.is_empty()
+            };
+            if !progress {
+                return None;
+            }
+        }
+    }
+}
+
+/// A handle to the [`MockReactor`](struct.MockReactor.html).
+///
+/// This is what coroutines are spawned onto, by passing it to the
+/// [`Coroutine::new`](../struct.Coroutine.html#method.new).
+#[derive(Clone)]
+pub struct MockHandle {
+    inner: Weak<RefCell<Inner>>,
+    woken: Arc<Woken>,
+}
+
+impl MockHandle {
+    /// Creates a future that resolves once the given amount of virtual time passes.
+    pub fn timeout(&self, duration: Duration) -> MockTimeout {
+        let deadline = self.inner
+            .upgrade()
+            .map(|inner| {
+                let now = inner.borrow().now;
+                now + duration
+            })
+            .unwrap_or(duration);
+        MockTimeout {
+            inner: self.inner.clone(),
+            deadline,
+            timer: None,
+        }
+    }
+}
+
+impl ReactorLike for MockHandle {
+    fn spawn_task(&self, task: BoxedFuture) {
+        if let Some(inner) = self.inner.upgrade() {
+            let mut inner = inner.borrow_mut();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.tasks.insert(id, executor::spawn(task));
+            self.woken.notify(id);
+        }
+    }
+    fn is_running(&self) -> bool {
+        self.inner.upgrade().is_some()
+    }
+}
+
+/// A timeout in the virtual time of the [`MockReactor`](struct.MockReactor.html).
+///
+/// It resolves with `Err(Dropped)` if the reactor is gone.
+pub struct MockTimeout {
+    inner: Weak<RefCell<Inner>>,
+    deadline: Duration,
+    /// The id of the registered timer, once polled.
+    timer: Option<usize>,
+}
+
+impl Future for MockTimeout {
+    type Item = ();
+    type Error = Dropped;
+    fn poll(&mut self) -> Poll<(), Dropped> {
+        let inner = self.inner.upgrade().ok_or(Dropped)?;
+        let mut inner = inner.borrow_mut();
+        if inner.now >= self.deadline {
+            return Ok(Async::Ready(()));
+        }
+        // Register only once, later polls just update the task if it changed.
+        if let Some(id) = self.timer {
+            if let Some(&mut (_, ref mut task)) = inner.timers.get_mut(&id) {
+                if !task.will_notify_current() {
+                    *task = task::current();
+                }
+                return Ok(Async::NotReady);
+            }
+        }
+        let id = inner.next_timer;
+        inner.next_timer += 1;
+        inner.timers.insert(id, (self.deadline, task::current()));
+        self.timer = Some(id);
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for MockTimeout {
+    fn drop(&mut self) {
+        if let (Some(id), Some(inner)) = (self.timer, self.inner.upgrade()) {
+            inner.borrow_mut().timers.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use coroutine::Coroutine;
+
+    /// The timeout fires only once enough virtual time passes.
+    #[test]
+    fn virtual_timeout() {
+        let mut reactor = MockReactor::new();
+        let handle = reactor.handle();
+        let fired = Rc::new(Cell::new(false));
+        let fired_cp = fired.clone();
+        Coroutine::new(reactor.handle())
+            .spawn(move || {
+                let timeout = handle.timeout(Duration::from_secs(3600));
+                Coroutine::wait(timeout).unwrap().unwrap();
+                fired_cp.set(true);
+            })
+            .unwrap();
+        reactor.run_until_stalled();
+        assert!(!fired.get());
+        reactor.advance(Duration::from_secs(3599));
+        assert!(!fired.get());
+        reactor.advance(Duration::from_secs(1));
+        assert!(fired.get());
+        assert_eq!(Duration::from_secs(3600), reactor.now());
+    }
+
+    /// Polling a timeout repeatedly registers it only once.
+    #[test]
+    fn timeout_registered_once() {
+        let mut reactor = MockReactor::new();
+        let mut timeout = reactor.handle().timeout(Duration::from_secs(1));
+        assert!(reactor.block(&mut timeout).is_none());
+        assert!(reactor.block(&mut timeout).is_none());
+        assert_eq!(1, reactor.inner.borrow().timers.len());
+        reactor.advance(Duration::from_secs(1));
+        assert!(reactor.inner.borrow().timers.is_empty());
+        assert_eq!(Some(Ok(())), reactor.block(&mut timeout));
+        {
+            let mut timeout = reactor.handle().timeout(Duration::from_secs(1));
+            assert!(reactor.block(&mut timeout).is_none());
+        }
+        // Dropping a timeout that didn't fire unregisters it
+        assert!(reactor.inner.borrow().timers.is_empty());
+    }
+
+    /// The coroutines on the mock reactor have no `tokio_core` handle, but can ask safely.
+    #[test]
+    fn no_tokio_reactor() {
+        let mut reactor = MockReactor::new();
+        let mut result = Coroutine::new(reactor.handle())
+            .spawn(|| Coroutine::try_reactor().is_none())
+            .unwrap();
+        assert!(reactor.block(&mut result).unwrap().unwrap());
+    }
+
+    /// Dropping the reactor cleans up the waiting coroutines.
+    #[test]
+    fn drop_reactor() {
+        let mut reactor = MockReactor::new();
+        let handle = reactor.handle();
+        let mut result = Coroutine::new(reactor.handle())
+            .spawn(move || Coroutine::wait(handle.timeout(Duration::from_secs(1))).is_err())
+            .unwrap();
+        assert!(reactor.block(&mut result).is_none());
+        drop(reactor);
+        assert!(result.wait().unwrap());
+    }
+}
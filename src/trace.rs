@@ -0,0 +1,153 @@
+//! Integration with the `tracing` crate.
+//!
+//! With the `tracing` feature, each coroutine gets a span. The span is entered whenever the
+//! coroutine gets resumed and exited whenever it switches out, so the active span always reflects
+//! the running coroutine. Spawning and termination of the coroutine are reported as events inside
+//! the span. Without the feature, all this compiles to nothing.
+
+use coroutine::CompletionStatus;
+#[cfg(feature = "tracing")]
+use tracing::{self, Level, Span};
+
+/// The span of a single coroutine.
+#[cfg(feature = "tracing")]
+pub(crate) struct CoroutineSpan(Span);
+
+#[cfg(feature = "tracing")]
+impl CoroutineSpan {
+    /// Creates the span of a newly started coroutine.
+    ///
+    /// The span of the currently running coroutine (if any) becomes its parent.
+    pub(crate) fn start(id: usize) -> Self {
+        let span = tracing::span!(Level::TRACE, "coroutine", id = id as u64);
+        tracing::event!(parent: &span, Level::TRACE, "coroutine spawned");
+        CoroutineSpan(span)
+    }
+
+    /// The coroutine got switched into.
+    pub(crate) fn enter(&self) {
+        self.0.with_subscriber(|(id, dispatch)| dispatch.enter(id));
+    }
+
+    /// The coroutine switched out.
+    pub(crate) fn exit(&self) {
+        self.0.with_subscriber(|(id, dispatch)| dispatch.exit(id));
+    }
+
+    /// The coroutine terminated.
+    pub(crate) fn complete(self, status: CompletionStatus) {
+        match status {
+            CompletionStatus::Panicked => {
+                tracing::event!(parent: &self.0, Level::ERROR, "coroutine panicked");
+            },
+            _ => tracing::event!(parent: &self.0, Level::TRACE, status = ?status,
+                                 "coroutine completed"),
+        }
+    }
+}
+
+/// Placeholder for the span of a coroutine, when the `tracing` feature is off.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct CoroutineSpan;
+
+#[cfg(not(feature = "tracing"))]
+impl CoroutineSpan {
+    pub(crate) fn start(_id: usize) -> Self {
+        CoroutineSpan
+    }
+    pub(crate) fn enter(&self) {}
+    pub(crate) fn exit(&self) {}
+    pub(crate) fn complete(self, _status: CompletionStatus) {}
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::fmt::{self, Debug};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio_core::reactor::Core;
+    use tracing::{self, Event, Metadata, Subscriber};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+
+    use coroutine::Coroutine;
+
+    /// Records what happens to the spans and the messages of the events.
+    #[derive(Clone, Default)]
+    struct Capture {
+        log: Arc<Mutex<Vec<String>>>,
+        next_id: Arc<AtomicUsize>,
+    }
+
+    struct Message(Option<String>);
+
+    impl Visit for Message {
+        fn record_debug(&mut self, field: &Field, value: &Debug) {
+            if field.name() == "message" {
+                self.0 = Some(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes) -> Id {
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) as u64 + 1)
+        }
+        fn record(&self, _: &Id, _: &Record) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event) {
+            let mut message = Message(None);
+            event.record(&mut message);
+            self.log.lock().unwrap().push(message.0.unwrap_or_default());
+        }
+        fn enter(&self, span: &Id) {
+            self.log.lock().unwrap().push(format!("enter {}", span.into_u64()));
+        }
+        fn exit(&self, span: &Id) {
+            self.log.lock().unwrap().push(format!("exit {}", span.into_u64()));
+        }
+    }
+
+    impl Debug for Capture {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Capture")
+        }
+    }
+
+    /// The span of the coroutine is entered while it runs and exited while it waits.
+    #[test]
+    fn coroutine_span() {
+        let capture = Capture::default();
+        tracing::subscriber::with_default(capture.clone(), || {
+            let mut core = Core::new().unwrap();
+            let coroutine = Coroutine::with_defaults(core.handle(), || {
+                Coroutine::sleep(Duration::from_millis(1)).unwrap();
+            });
+            core.run(coroutine).unwrap();
+            let panicked = Coroutine::new(core.handle())
+                .spawn_catch_panic(|| panic!("Test"))
+                .unwrap();
+            core.run(panicked).unwrap_err();
+        });
+        let expected = vec![
+            "coroutine spawned",
+            "enter 1",
+            // The sleep
+            "exit 1",
+            "enter 1",
+            // Termination
+            "exit 1",
+            "coroutine completed",
+            "coroutine spawned",
+            "enter 2",
+            "exit 2",
+            "coroutine panicked",
+        ];
+        assert_eq!(expected, *capture.log.lock().unwrap());
+    }
+}
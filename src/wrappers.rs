@@ -8,8 +8,10 @@
 
 use std::panic;
 
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::stream::FuturesUnordered;
 
+use coroutine::Coroutine;
 use prelude::CoroutineFuture;
 use errors::Dropped;
 
@@ -201,6 +203,141 @@ where
     }
 }
 
+/// A future flushing a sink.
+///
+/// This borrows a sink and waits until everything previously sent into it is flushed. It is
+/// returned by
+/// [`CoroutineSink::coro_flusher`](../prelude/trait.CoroutineSink.html#method.coro_flusher).
+pub struct SinkFlusher<'a, S: 'a>(&'a mut S);
+
+impl<'a, S: 'a> SinkFlusher<'a, S> {
+    /// A constructor.
+    pub fn new(sink: &'a mut S) -> Self {
+        SinkFlusher(sink)
+    }
+}
+
+impl<'a, V, E, S: Sink<SinkItem = V, SinkError = E> + 'a> Future for SinkFlusher<'a, S> {
+    type Item = ();
+    type Error = E;
+    fn poll(&mut self) -> Poll<(), E> {
+        self.0.poll_complete()
+    }
+}
+
+/// A future closing a sink.
+///
+/// This borrows a sink and waits until it is flushed and closed. It is used by
+/// [`CoroutineSink::coro_close`](../prelude/trait.CoroutineSink.html#method.coro_close).
+pub struct SinkCloser<'a, S: 'a>(&'a mut S);
+
+impl<'a, S: 'a> SinkCloser<'a, S> {
+    /// A constructor.
+    pub fn new(sink: &'a mut S) -> Self {
+        SinkCloser(sink)
+    }
+}
+
+impl<'a, V, E, S: Sink<SinkItem = V, SinkError = E> + 'a> Future for SinkCloser<'a, S> {
+    type Item = ();
+    type Error = E;
+    fn poll(&mut self) -> Poll<(), E> {
+        self.0.close()
+    }
+}
+
+/// A future that can tell it already completed.
+///
+/// Polling a future again after it resolved is usually undefined (it may panic, resolve again or
+/// never resolve at all). This wraps a future and remembers it completed, so waiting for it again
+/// is caught by a clear panic instead of hanging. It is used by
+/// [`Coroutine::wait_fused`](../struct.Coroutine.html#method.wait_fused).
+pub struct Fused<F>(Option<F>);
+
+impl<F> Fused<F> {
+    /// A constructor.
+    pub fn new(fut: F) -> Self {
+        Fused(Some(fut))
+    }
+
+    /// Checks if the wrapped future already resolved.
+    pub fn is_done(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl<I, E, F: Future<Item = I, Error = E>> Future for Fused<F> {
+    type Item = I;
+    type Error = E;
+    fn poll(&mut self) -> Poll<I, E> {
+        let result = self.0
+            .as_mut()
+            .expect("Awaited an already completed future")
+            .poll();
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        self.0 = None;
+        result
+    }
+}
+
+/// An iterator returned from
+/// [`Coroutine::buffered`](../struct.Coroutine.html#method.buffered).
+///
+/// It keeps up to a limit of futures from the wrapped iterator in flight and yields their results
+/// in the order they resolve.
+pub struct BufferedIterator<It: Iterator> {
+    source: It,
+    limit: usize,
+    in_flight: FuturesUnordered<It::Item>,
+}
+
+impl<It> BufferedIterator<It>
+where
+    It: Iterator,
+    It::Item: Future,
+{
+    /// A constructor.
+    ///
+    /// # Panics
+    ///
+    /// If the `limit` is 0.
+    pub fn new(source: It, limit: usize) -> Self {
+        assert!(limit > 0, "Need to be able to wait for at least one future");
+        BufferedIterator {
+            source,
+            limit,
+            in_flight: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<I, E, Fut, It> Iterator for BufferedIterator<It>
+where
+    It: Iterator<Item = Fut>,
+    Fut: Future<Item = I, Error = E>,
+{
+    type Item = Result<I, E>;
+    fn next(&mut self) -> Option<Result<I, E>> {
+        while self.in_flight.len() < self.limit {
+            match self.source.next() {
+                Some(fut) => self.in_flight.push(fut),
+                None => break,
+            }
+        }
+        if self.in_flight.is_empty() {
+            return None;
+        }
+        let in_flight = &mut self.in_flight;
+        match drop_panic(Coroutine::wait(future::poll_fn(|| in_flight.poll()))) {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => unreachable!("Ran out of futures in flight"),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,56 @@
+//! Tests of the `co_await!` macro.
+
+use futures::{future, Future};
+use futures::unsync::oneshot;
+use tokio_core::reactor::Core;
+
+use corona::Coroutine;
+
+/// Waiting for a successful future gives its value.
+#[test]
+fn co_await_success() {
+    let mut core = Core::new().unwrap();
+    let coroutine = Coroutine::with_defaults(core.handle(), || -> Result<u32, ()> {
+        Ok(co_await!(future::ok::<u32, ()>(42)))
+    });
+    assert_eq!(Ok(42), core.run(coroutine).unwrap());
+}
+
+/// The error of the future is propagated out and the rest is skipped.
+#[test]
+fn co_await_error() {
+    let mut core = Core::new().unwrap();
+    let coroutine = Coroutine::with_defaults(core.handle(), || -> Result<u32, &'static str> {
+        co_await!(future::err::<u32, _>("Failed"));
+        panic!("Should have returned already");
+    });
+    assert_eq!(Err("Failed"), core.run(coroutine).unwrap());
+}
+
+/// Several waits in a row, each depending on the previous one.
+#[test]
+fn co_await_sequential() {
+    let mut core = Core::new().unwrap();
+    let (sender, receiver) = oneshot::channel();
+    let coroutine = Coroutine::with_defaults(core.handle(), move || -> Result<u32, String> {
+        let a = co_await!(future::ok::<u32, String>(20));
+        let b = co_await!(receiver.map_err(|e| e.to_string()));
+        let c = co_await!(future::ok::<u32, String>(a + b));
+        Ok(c)
+    });
+    sender.send(22).unwrap();
+    assert_eq!(Ok(42), core.run(coroutine).unwrap());
+}
+
+/// The reactor goes away while waiting, the configured error is returned.
+#[test]
+fn co_await_dropped() {
+    let coroutine = {
+        let core = Core::new().unwrap();
+        Coroutine::with_defaults(core.handle(), || -> Result<(), String> {
+            co_await!(future::empty::<(), String>(), dropped => "Dropped");
+            Ok(())
+        })
+    };
+    assert_eq!(Err("Dropped".to_owned()), coroutine.wait().unwrap());
+}
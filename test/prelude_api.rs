@@ -2,11 +2,15 @@ extern crate corona;
 extern crate futures;
 extern crate tokio_core;
 
+use std::cell::{Cell, RefCell};
 use std::fmt::Debug;
+use std::rc::Rc;
+use std::time::Duration;
 
-use futures::{future, stream, Future};
+use futures::{future, stream, task, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::task::Task;
 use futures::sync::mpsc;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Interval};
 
 use corona::Coroutine;
 use corona::prelude::*;
@@ -113,3 +117,108 @@ fn extract() {
     let mut s = stream::once::<_, ()>(Ok(42));
     cor.cor_ft(move || s.coro_next().unwrap().unwrap());
 }
+
+/// Flushing and closing a sink terminates the consumer on the other side.
+#[test]
+fn flush_close_sink() {
+    let mut cor = Cor::new();
+    let (mut sender, receiver) = mpsc::channel(1);
+    let producer = cor.coroutine.spawn(move || {
+            sender.coro_send_many(vec![2, 20]).unwrap().unwrap();
+            sender.coro_flush().unwrap();
+            sender.coro_send(20).unwrap();
+            sender.coro_close().unwrap();
+        })
+        .unwrap();
+    cor.cor_ft(move || {
+        receiver.iter_ok().sum()
+    });
+    producer.wait().unwrap();
+}
+
+/// A sink with a single slot, complete only once somebody takes the item out.
+#[derive(Clone, Default)]
+struct Slot {
+    item: Rc<RefCell<Option<u32>>>,
+    task: Rc<RefCell<Option<Task>>>,
+}
+
+impl Slot {
+    fn take(&self) -> Option<u32> {
+        let item = self.item.borrow_mut().take();
+        if let Some(task) = self.task.borrow_mut().take() {
+            task.notify();
+        }
+        item
+    }
+}
+
+impl Sink for Slot {
+    type SinkItem = u32;
+    type SinkError = ();
+    fn start_send(&mut self, item: u32) -> StartSend<u32, ()> {
+        let mut slot = self.item.borrow_mut();
+        if slot.is_some() {
+            Ok(AsyncSink::NotReady(item))
+        } else {
+            *slot = Some(item);
+            Ok(AsyncSink::Ready)
+        }
+    }
+    fn poll_complete(&mut self) -> Poll<(), ()> {
+        if self.item.borrow().is_some() {
+            *self.task.borrow_mut() = Some(task::current());
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(()))
+        }
+    }
+}
+
+/// The item is created only after the consumer made room for it.
+#[test]
+fn send_when_ready() {
+    let mut cor = Cor::new();
+    let slot = Slot::default();
+    *slot.item.borrow_mut() = Some(1);
+    let made = Rc::new(Cell::new(false));
+    let mut sink = slot.clone();
+    let made_cp = made.clone();
+    let producer = cor.coroutine.spawn(move || {
+            sink.coro_send_when_ready(|| {
+                made_cp.set(true);
+                41
+            }).unwrap();
+        })
+        .unwrap();
+    // Still full, so nothing got created yet
+    assert!(!made.get());
+    assert_eq!(Some(1), slot.take());
+    // Take the second item out while the producer waits for the flush of it.
+    let consumer = cor.coroutine.spawn(move || loop {
+            if let Some(item) = slot.take() {
+                return item;
+            }
+            Coroutine::yield_now().unwrap();
+        })
+        .unwrap();
+    assert_eq!(41, cor.core.run(consumer).unwrap());
+    cor.core.run(producer).unwrap();
+    assert!(made.get());
+}
+
+/// Iterating through a borrowed stream leaves it usable for later.
+#[test]
+fn iter_borrowed() {
+    let mut cor = Cor::new();
+    let handle = cor.core.handle();
+    let counts = cor.coroutine
+        .spawn(move || {
+            let mut interval = Interval::new(Duration::from_millis(10), &handle).unwrap();
+            let first = interval.by_ref().iter_ok().take(2).count();
+            let second = interval.by_ref().iter_ok().take(2).count();
+            (first, second)
+        })
+        .unwrap();
+    assert_eq!((2, 2), cor.core.run(counts).unwrap());
+}
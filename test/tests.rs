@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate corona;
 extern crate futures;
 extern crate tokio_core;
@@ -6,9 +7,11 @@ extern crate tokio_io;
 #[macro_use]
 extern crate version_sync;
 
+#[cfg(not(corona_panic_abort))]
 mod early_cleanup;
 #[cfg(feature = "blocking-wrappers")]
 mod io_blocking;
+mod macros;
 mod prelude_api;
 mod recursive_core;
 mod version;